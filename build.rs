@@ -0,0 +1,24 @@
+use std::path::Path;
+use std::process::Command;
+
+// Embeds the commit the service is built from in `GIT_SHA` for the health report, unless
+// it's already set, e.g. by a docker build without the .git directory
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    if std::env::var("GIT_SHA").is_ok() {
+        return;
+    }
+    if Path::new(".git").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_SHA={}", sha);
+}
@@ -0,0 +1,286 @@
+use cached::{Cached, SizedCache};
+use mongodb_base_service::ID;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CacheConfig;
+use crate::metrics;
+
+/// Tag of every cached result read from a collection, invalidated by any write to it
+pub fn collection_tag(collection: &str) -> String {
+    collection.to_owned()
+}
+
+/// Tag of a cached result of a single document, invalidated when that document changes
+pub fn document_tag(collection: &str, id: &ID) -> String {
+    format!("{}:{}", collection, id)
+}
+
+struct Entry {
+    key: String,
+    tags: Vec<String>,
+    expires: Instant,
+    value: Box<dyn Any + Send>,
+}
+
+struct State {
+    entries: SizedCache<String, Entry>,
+    /// Incremented by every invalidation
+    generation: u64,
+}
+
+/// Caches query results by key with a TTL per query
+///
+/// Results are stored with the tags of the data they were read from and mutations
+/// invalidate the tags they touch, so the TTL only bounds how stale results can get
+/// when the database is written to by something other than this service.
+pub struct QueryCache {
+    state: Mutex<State>,
+    default_ttl: Duration,
+    ttls: HashMap<String, Duration>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, default_ttl: Duration, ttls: HashMap<String, Duration>) -> Self {
+        QueryCache {
+            state: Mutex::new(State {
+                entries: SizedCache::with_size(capacity),
+                generation: 0,
+            }),
+            default_ttl,
+            ttls,
+        }
+    }
+
+    /// Builds the cache from the `cache` config, the TTL of single queries is keyed by the
+    /// query, e.g. `all_pets`, and a TTL of 0 disables caching
+    pub fn from_config(config: &CacheConfig) -> Self {
+        let ttls = config
+            .ttls
+            .iter()
+            .map(|(query, ttl)| (query.to_lowercase(), Duration::from_secs(*ttl)))
+            .collect();
+        QueryCache::new(config.capacity, Duration::from_secs(config.ttl), ttls)
+    }
+
+    fn ttl(&self, query: &str) -> Duration {
+        self.ttls.get(query).cloned().unwrap_or(self.default_ttl)
+    }
+
+    /// Returns the cached result of `query` for `key` or builds and caches it, errors
+    /// aren't cached
+    pub fn get_or_insert_with<T, E, F>(
+        &self,
+        query: &str,
+        key: &str,
+        tags: Vec<String>,
+        build: F,
+    ) -> Result<T, E>
+    where
+        T: Clone + Send + 'static,
+        F: FnOnce() -> Result<T, E>,
+    {
+        self.get_or_insert(query, key, tags, self.ttl(query), build)
+    }
+
+    /// Same as `get_or_insert_with` for results that can't be kept for longer than `max_age`
+    pub fn get_or_insert_with_max_age<T, E, F>(
+        &self,
+        query: &str,
+        key: &str,
+        tags: Vec<String>,
+        max_age: Duration,
+        build: F,
+    ) -> Result<T, E>
+    where
+        T: Clone + Send + 'static,
+        F: FnOnce() -> Result<T, E>,
+    {
+        self.get_or_insert(query, key, tags, self.ttl(query).min(max_age), build)
+    }
+
+    fn get_or_insert<T, E, F>(
+        &self,
+        query: &str,
+        key: &str,
+        tags: Vec<String>,
+        ttl: Duration,
+        build: F,
+    ) -> Result<T, E>
+    where
+        T: Clone + Send + 'static,
+        F: FnOnce() -> Result<T, E>,
+    {
+        if ttl == Duration::from_secs(0) {
+            return build();
+        }
+        let key = format!("{}:{}", query, key);
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            let cached = state
+                .entries
+                .cache_get(&key)
+                .filter(|entry| entry.expires > Instant::now())
+                .and_then(|entry| entry.value.downcast_ref::<T>());
+            if let Some(value) = cached {
+                metrics::cache_hit(query);
+                return Ok(value.clone());
+            }
+            metrics::cache_miss(query);
+            state.generation
+        };
+
+        let value = build()?;
+        let mut state = self.state.lock().unwrap();
+        // a mutation that ran while the result was built may not be in it
+        if state.generation == generation {
+            let entry = Entry {
+                key: key.clone(),
+                tags,
+                expires: Instant::now() + ttl,
+                value: Box::new(value.clone()),
+            };
+            state.entries.cache_set(key, entry);
+        }
+        Ok(value)
+    }
+
+    /// Invalidates the results read from the collection, after inserts
+    pub fn invalidate_collection(&self, collection: &str) {
+        self.invalidate(&[collection_tag(collection)]);
+    }
+
+    /// Invalidates the results read from the collection and from the document, after it
+    /// is updated or deleted
+    pub fn invalidate_document(&self, collection: &str, id: &ID) {
+        self.invalidate(&[collection_tag(collection), document_tag(collection, id)]);
+    }
+
+    /// Removes every result cached with one of the tags
+    pub fn invalidate(&self, tags: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        let keys: Vec<String> = state
+            .entries
+            .value_order()
+            .filter(|entry| entry.tags.iter().any(|tag| tags.contains(tag)))
+            .map(|entry| entry.key.clone())
+            .collect();
+        for key in keys {
+            state.entries.cache_remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::thread;
+
+    fn cache(ttls: &[(&str, Duration)]) -> QueryCache {
+        let ttls = ttls
+            .iter()
+            .map(|(query, ttl)| (query.to_string(), *ttl))
+            .collect();
+        QueryCache::new(100, Duration::from_secs(10), ttls)
+    }
+
+    /// Gets the result of `query` for `key`, counting the times it's built
+    fn get(cache: &QueryCache, query: &str, key: &str, tags: &[&str], builds: &Cell<u32>) -> u32 {
+        let tags = tags.iter().map(|tag| tag.to_string()).collect();
+        let result: Result<u32, ()> = cache.get_or_insert_with(query, key, tags, || {
+            builds.set(builds.get() + 1);
+            Ok(builds.get())
+        });
+        result.unwrap()
+    }
+
+    #[test]
+    fn results_are_cached_by_query_and_key() {
+        let cache = cache(&[]);
+        let builds = Cell::new(0);
+        assert_eq!(get(&cache, "all_pets", "1", &["pets"], &builds), 1);
+        assert_eq!(get(&cache, "all_pets", "1", &["pets"], &builds), 1);
+        assert_eq!(get(&cache, "all_pets", "2", &["pets"], &builds), 2);
+        assert_eq!(get(&cache, "all_owners", "1", &["owners"], &builds), 3);
+    }
+
+    #[test]
+    fn invalidating_a_collection_evicts_the_results_tagged_with_it() {
+        let cache = cache(&[]);
+        let builds = Cell::new(0);
+        get(&cache, "all_pets", "", &["pets"], &builds);
+        get(&cache, "all_owners", "", &["owners"], &builds);
+        cache.invalidate_collection("pets");
+        assert_eq!(get(&cache, "all_pets", "", &["pets"], &builds), 3);
+        assert_eq!(get(&cache, "all_owners", "", &["owners"], &builds), 2);
+    }
+
+    #[test]
+    fn invalidating_a_document_evicts_it_and_the_results_of_its_collection() {
+        let cache = cache(&[]);
+        let builds = Cell::new(0);
+        let id = ID::String("1".to_owned());
+        let other = ID::String("2".to_owned());
+        let tag = document_tag("pets", &id);
+        let other_tag = document_tag("pets", &other);
+        get(&cache, "pet_by_id", "1", &[&tag], &builds);
+        get(&cache, "pet_by_id", "2", &[&other_tag], &builds);
+        get(&cache, "all_pets", "", &["pets"], &builds);
+        cache.invalidate_document("pets", &id);
+        assert_eq!(get(&cache, "pet_by_id", "1", &[&tag], &builds), 4);
+        assert_eq!(get(&cache, "pet_by_id", "2", &[&other_tag], &builds), 2);
+        assert_eq!(get(&cache, "all_pets", "", &["pets"], &builds), 5);
+    }
+
+    #[test]
+    fn results_built_before_an_invalidation_are_not_cached() {
+        let cache = cache(&[]);
+        let builds = Cell::new(0);
+        let result: Result<u32, ()> =
+            cache.get_or_insert_with("all_pets", "", vec!["pets".to_owned()], || {
+                // a mutation that runs while the result is read
+                cache.invalidate_collection("owners");
+                Ok(0)
+            });
+        assert_eq!(result, Ok(0));
+        assert_eq!(get(&cache, "all_pets", "", &["pets"], &builds), 1);
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let cache = cache(&[]);
+        let builds = Cell::new(0);
+        let result: Result<u32, &str> =
+            cache.get_or_insert_with("all_pets", "", vec![], || Err("unavailable"));
+        assert_eq!(result, Err("unavailable"));
+        assert_eq!(get(&cache, "all_pets", "", &[], &builds), 1);
+    }
+
+    #[test]
+    fn results_expire_after_the_ttl_of_their_query() {
+        let cache = cache(&[("all_pets", Duration::from_millis(20))]);
+        let builds = Cell::new(0);
+        get(&cache, "all_pets", "", &[], &builds);
+        get(&cache, "all_owners", "", &[], &builds);
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(get(&cache, "all_pets", "", &[], &builds), 3);
+        assert_eq!(get(&cache, "all_owners", "", &[], &builds), 2);
+    }
+
+    #[test]
+    fn a_ttl_of_zero_disables_caching_of_the_query() {
+        let cache = cache(&[("all_pets", Duration::from_secs(0))]);
+        let builds = Cell::new(0);
+        assert_eq!(get(&cache, "all_pets", "", &[], &builds), 1);
+        assert_eq!(get(&cache, "all_pets", "", &[], &builds), 2);
+        let max_age = Duration::from_secs(0);
+        let result: Result<u32, ()> =
+            cache.get_or_insert_with_max_age("all_owners", "", vec![], max_age, || Ok(7));
+        assert_eq!(result, Ok(7));
+        assert_eq!(get(&cache, "all_owners", "", &[], &builds), 3);
+    }
+}
@@ -0,0 +1,662 @@
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::{env, fmt, fs};
+
+use crate::environment::Environment;
+
+/// Loaded when `CONFIG_FILE` isn't set and it exists
+const DEFAULT_FILE: &str = "config.toml";
+const CACHE_TTL_PREFIX: &str = "CACHE_TTL_";
+
+/// The settings of the service, from a toml file and environment variables
+///
+/// Every setting can be overridden by its environment variable, e.g. `PORT` for
+/// `server.port`, and secrets can be read from a file with `<VARIABLE>_FILE`, e.g.
+/// `MONGO_URL_FILE`. See `config.example.toml` for every setting and its variable.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `APP_ENV`
+    pub environment: Environment,
+    /// Log filters, `RUST_LOG`
+    pub log: String,
+    /// `LOG_FORMAT`
+    pub log_format: LogFormat,
+    pub server: ServerConfig,
+    pub mongo: MongoConfig,
+    pub slow_queries: SlowQueriesConfig,
+    pub cache: CacheConfig,
+    pub graphql: GraphQLConfig,
+    pub persisted_queries: PersistedQueriesConfig,
+    pub health: HealthConfig,
+    pub tracing: TracingConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            environment: Environment::Development,
+            log: "info,actix_web=warn".to_owned(),
+            log_format: LogFormat::Json,
+            server: ServerConfig::default(),
+            mongo: MongoConfig::default(),
+            slow_queries: SlowQueriesConfig::default(),
+            cache: CacheConfig::default(),
+            graphql: GraphQLConfig::default(),
+            persisted_queries: PersistedQueriesConfig::default(),
+            health: HealthConfig::default(),
+            tracing: TracingConfig::default(),
+        }
+    }
+}
+
+/// Json lines for the log pipeline, or text that's easier to read in a terminal
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+impl LogFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "json" => Some(LogFormat::Json),
+            "text" => Some(LogFormat::Text),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `PORT`
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { port: 8080 }
+    }
+}
+
+/// The url and database have no defaults and are required
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MongoConfig {
+    /// `MONGO_URL`
+    pub url: String,
+    /// `MONGO_DB_NAME`
+    pub db_name: String,
+    /// What the server does with the indexes the models declare at startup, `MONGO_INDEXES`
+    pub indexes: IndexSync,
+}
+
+impl Default for MongoConfig {
+    fn default() -> Self {
+        MongoConfig {
+            url: String::new(),
+            db_name: String::new(),
+            indexes: IndexSync::Create,
+        }
+    }
+}
+
+/// Creates the missing indexes, or only reports them and the indexes that differ
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexSync {
+    Create,
+    Check,
+    Off,
+}
+
+impl IndexSync {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "create" => Some(IndexSync::Create),
+            "check" => Some(IndexSync::Check),
+            "off" => Some(IndexSync::Off),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowQueriesConfig {
+    /// MongoDB commands that take longer are logged, 0 disables the log, `SLOW_QUERY_MS`
+    pub threshold_ms: u64,
+    /// Logs the query plan of slow queries, `SLOW_QUERY_EXPLAIN`
+    pub explain: bool,
+}
+
+impl Default for SlowQueriesConfig {
+    fn default() -> Self {
+        SlowQueriesConfig {
+            threshold_ms: 100,
+            explain: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Number of results kept, `CACHE_CAPACITY`
+    pub capacity: usize,
+    /// Seconds results are kept, `CACHE_TTL`
+    pub ttl: u64,
+    /// Seconds the results of single queries are kept, `CACHE_TTL_<QUERY>`
+    pub ttls: HashMap<String, u64>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: 10000,
+            ttl: 10,
+            ttls: HashMap::new(),
+        }
+    }
+}
+
+/// Introspection and graphiql default to enabled outside of production
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphQLConfig {
+    /// `GRAPHQL_INTROSPECTION`
+    pub introspection: Option<bool>,
+    /// `GRAPHIQL_ENABLED`
+    pub graphiql: Option<bool>,
+    /// The url graphiql sends queries to, `GRAPHQL_PUBLIC_URL`
+    pub public_url: Option<String>,
+    /// Variables whose name contains one of these are redacted in the logs, ignoring case,
+    /// `GRAPHQL_REDACTED_VARIABLES` separated by commas
+    pub redacted_variables: Vec<String>,
+    /// Requests with this token in `X-Debug-Token` get the resolver timings and MongoDB
+    /// commands in the extensions of the response, `GRAPHQL_DEBUG_TOKEN`
+    pub debug_token: Option<String>,
+}
+
+impl Default for GraphQLConfig {
+    fn default() -> Self {
+        GraphQLConfig {
+            introspection: None,
+            graphiql: None,
+            public_url: None,
+            redacted_variables: ["password", "secret", "token", "authorization"]
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            debug_token: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistedQueriesConfig {
+    /// `PERSISTED_QUERIES_MANIFEST`
+    pub manifest: Option<String>,
+    /// `PERSISTED_QUERIES_CACHE_SIZE`
+    pub cache_size: usize,
+    /// `PERSISTED_QUERIES_ALLOWLIST`
+    pub allowlist: bool,
+}
+
+impl Default for PersistedQueriesConfig {
+    fn default() -> Self {
+        PersistedQueriesConfig {
+            manifest: None,
+            cache_size: 1000,
+            allowlist: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Milliseconds the dependencies have to answer the health checks, `HEALTH_TIMEOUT_MS`
+    pub timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig { timeout_ms: 1000 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TracingExporter {
+    None,
+    Otlp,
+    File,
+}
+
+impl TracingExporter {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "none" => Some(TracingExporter::None),
+            "otlp" => Some(TracingExporter::Otlp),
+            "file" => Some(TracingExporter::File),
+            _ => None,
+        }
+    }
+}
+
+/// Tracing is disabled until an exporter is chosen
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// `TRACING_EXPORTER`
+    pub exporter: TracingExporter,
+    /// Base url of an OTLP/HTTP collector, `OTEL_EXPORTER_OTLP_ENDPOINT`
+    pub otlp_endpoint: String,
+    /// File the spans are appended to as OTLP json lines, `TRACING_FILE`
+    pub file: String,
+    /// `OTEL_SERVICE_NAME`
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            exporter: TracingExporter::None,
+            otlp_endpoint: "http://localhost:4318".to_owned(),
+            file: "traces.ndjson".to_owned(),
+            service_name: env!("CARGO_PKG_NAME").to_owned(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: String, message: String },
+    Parse { path: String, message: String },
+    Invalid { name: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, message } => {
+                write!(f, "Unable to read {}: {}", path, message)
+            }
+            ConfigError::Parse { path, message } => write!(f, "Invalid {}: {}", path, message),
+            ConfigError::Invalid { name, message } => write!(f, "Invalid {}: {}", name, message),
+        }
+    }
+}
+
+impl Config {
+    /// Loads `.env`, then the file in `CONFIG_FILE` or `config.toml` when it exists, and
+    /// overrides the file with the environment variables
+    pub fn load() -> Result<Self, ConfigError> {
+        dotenv::dotenv().ok();
+        let names = env::vars_os()
+            .filter_map(|(name, _)| name.into_string().ok())
+            .collect();
+        Config::load_from(&Vars {
+            lookup: &|name| env::var(name).ok(),
+            names,
+        })
+    }
+
+    fn load_from(vars: &Vars) -> Result<Self, ConfigError> {
+        let mut config = match (vars.lookup)("CONFIG_FILE") {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_FILE).exists() => Config::from_file(DEFAULT_FILE)?,
+            None => Config::default(),
+        };
+        config.apply_env(vars)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })
+    }
+
+    fn apply_env(&mut self, vars: &Vars) -> Result<(), ConfigError> {
+        vars.set(
+            &mut self.environment,
+            "APP_ENV",
+            Environment::parse,
+            "development, staging or production",
+        )?;
+        vars.set(&mut self.log, "RUST_LOG", string, "log filters")?;
+        vars.set(
+            &mut self.log_format,
+            "LOG_FORMAT",
+            LogFormat::parse,
+            "json or text",
+        )?;
+        vars.set(&mut self.server.port, "PORT", number, "a port number")?;
+        vars.set(&mut self.mongo.url, "MONGO_URL", string, "a url")?;
+        vars.set(&mut self.mongo.db_name, "MONGO_DB_NAME", string, "a name")?;
+        vars.set(
+            &mut self.mongo.indexes,
+            "MONGO_INDEXES",
+            IndexSync::parse,
+            "create, check or off",
+        )?;
+        vars.set(
+            &mut self.slow_queries.threshold_ms,
+            "SLOW_QUERY_MS",
+            number,
+            "milliseconds",
+        )?;
+        vars.set(
+            &mut self.slow_queries.explain,
+            "SLOW_QUERY_EXPLAIN",
+            flag,
+            "true or false",
+        )?;
+        vars.set(
+            &mut self.cache.capacity,
+            "CACHE_CAPACITY",
+            number,
+            "a number",
+        )?;
+        vars.set(&mut self.cache.ttl, "CACHE_TTL", number, "seconds")?;
+        for name in vars.cache_ttl_names() {
+            let query = name[CACHE_TTL_PREFIX.len()..].to_lowercase();
+            let ttl = self.cache.ttls.entry(query).or_insert(self.cache.ttl);
+            vars.set(ttl, &name, number, "seconds")?;
+        }
+        let graphql = &mut self.graphql;
+        vars.set(
+            &mut graphql.introspection,
+            "GRAPHQL_INTROSPECTION",
+            |v| flag(v).map(Some),
+            "true or false",
+        )?;
+        vars.set(
+            &mut graphql.graphiql,
+            "GRAPHIQL_ENABLED",
+            |v| flag(v).map(Some),
+            "true or false",
+        )?;
+        vars.set(
+            &mut graphql.public_url,
+            "GRAPHQL_PUBLIC_URL",
+            |v| Some(Some(v.to_owned())),
+            "a url",
+        )?;
+        vars.set(
+            &mut graphql.redacted_variables,
+            "GRAPHQL_REDACTED_VARIABLES",
+            list,
+            "names separated by commas",
+        )?;
+        vars.set(
+            &mut graphql.debug_token,
+            "GRAPHQL_DEBUG_TOKEN",
+            |v| Some(Some(v.to_owned())),
+            "a token",
+        )?;
+        let persisted = &mut self.persisted_queries;
+        vars.set(
+            &mut persisted.manifest,
+            "PERSISTED_QUERIES_MANIFEST",
+            |v| Some(Some(v.to_owned())),
+            "a path",
+        )?;
+        vars.set(
+            &mut persisted.cache_size,
+            "PERSISTED_QUERIES_CACHE_SIZE",
+            number,
+            "a number",
+        )?;
+        vars.set(
+            &mut persisted.allowlist,
+            "PERSISTED_QUERIES_ALLOWLIST",
+            flag,
+            "true or false",
+        )?;
+        vars.set(
+            &mut self.health.timeout_ms,
+            "HEALTH_TIMEOUT_MS",
+            number,
+            "milliseconds",
+        )?;
+        let tracing = &mut self.tracing;
+        vars.set(
+            &mut tracing.exporter,
+            "TRACING_EXPORTER",
+            TracingExporter::parse,
+            "none, otlp or file",
+        )?;
+        vars.set(
+            &mut tracing.otlp_endpoint,
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            string,
+            "a url",
+        )?;
+        vars.set(&mut tracing.file, "TRACING_FILE", string, "a path")?;
+        vars.set(
+            &mut tracing.service_name,
+            "OTEL_SERVICE_NAME",
+            string,
+            "a name",
+        )?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.mongo.url.is_empty() {
+            return Err(invalid("mongo.url", "MONGO_URL must be set"));
+        }
+        if !self.mongo.url.starts_with("mongodb://")
+            && !self.mongo.url.starts_with("mongodb+srv://")
+        {
+            return Err(invalid(
+                "mongo.url",
+                "expected a mongodb:// or mongodb+srv:// url",
+            ));
+        }
+        if self.mongo.db_name.is_empty() {
+            return Err(invalid("mongo.db_name", "MONGO_DB_NAME must be set"));
+        }
+        if self.server.port == 0 {
+            return Err(invalid("server.port", "can't be 0"));
+        }
+        if self.cache.capacity == 0 {
+            return Err(invalid("cache.capacity", "can't be 0"));
+        }
+        if self.graphql.debug_token.as_deref() == Some("") {
+            return Err(invalid("graphql.debug_token", "can't be empty"));
+        }
+        if self.persisted_queries.cache_size == 0 {
+            return Err(invalid("persisted_queries.cache_size", "can't be 0"));
+        }
+        if self.health.timeout_ms == 0 {
+            return Err(invalid("health.timeout_ms", "can't be 0"));
+        }
+        // the client is built without tls, collectors are expected to run next to the service
+        if self.tracing.exporter == TracingExporter::Otlp
+            && !self.tracing.otlp_endpoint.starts_with("http://")
+        {
+            return Err(invalid("tracing.otlp_endpoint", "expected an http:// url"));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(name: &str, message: &str) -> ConfigError {
+    ConfigError::Invalid {
+        name: name.to_owned(),
+        message: message.to_owned(),
+    }
+}
+
+/// The environment variables the config is read from, the process's outside of tests
+struct Vars<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    /// The names of the variables that are set
+    names: Vec<String>,
+}
+
+impl Vars<'_> {
+    /// The value of the variable `name`, or the contents of the file in `<name>_FILE` for
+    /// secrets that are mounted as files
+    fn var(&self, name: &str) -> Result<Option<String>, ConfigError> {
+        if let Some(value) = (self.lookup)(name) {
+            return Ok(Some(value));
+        }
+        match (self.lookup)(&format!("{}_FILE", name)) {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(value) => Ok(Some(value.trim_end().to_owned())),
+                Err(e) => Err(ConfigError::Read {
+                    path,
+                    message: e.to_string(),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces `value` with the variable `name` when it's set
+    fn set<T>(
+        &self,
+        value: &mut T,
+        name: &str,
+        parse: impl Fn(&str) -> Option<T>,
+        expected: &str,
+    ) -> Result<(), ConfigError> {
+        if let Some(raw) = self.var(name)? {
+            *value = parse(&raw).ok_or_else(|| ConfigError::Invalid {
+                name: name.to_owned(),
+                message: format!("expected {}", expected),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The `CACHE_TTL_<QUERY>` variables that are set, directly or as a file
+    fn cache_ttl_names(&self) -> BTreeSet<String> {
+        self.names
+            .iter()
+            .map(|name| name.strip_suffix("_FILE").unwrap_or(name).to_owned())
+            // `CACHE_TTL_FILE` is the global ttl and not a query named `file`
+            .filter(|name| name.starts_with(CACHE_TTL_PREFIX))
+            .collect()
+    }
+}
+
+fn string(value: &str) -> Option<String> {
+    Some(value.to_owned())
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Option<T> {
+    value.trim().parse().ok()
+}
+
+fn list(value: &str) -> Option<Vec<String>> {
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_owned)
+            .collect(),
+    )
+}
+
+fn flag(value: &str) -> Option<bool> {
+    match value.trim() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn load(vars: &HashMap<String, String>) -> Result<Config, ConfigError> {
+        Config::load_from(&Vars {
+            lookup: &|name| vars.get(name).cloned(),
+            names: vars.keys().cloned().collect(),
+        })
+    }
+
+    const MONGO: &[(&str, &str)] = &[
+        ("MONGO_URL", "mongodb://localhost:27017"),
+        ("MONGO_DB_NAME", "pets"),
+        // a config.toml of the checkout isn't read
+        ("CONFIG_FILE", "config.example.toml"),
+    ];
+
+    #[test]
+    fn reads_the_global_cache_ttl_from_a_file() {
+        let path = env::temp_dir().join(format!("cache_ttl_{}", std::process::id()));
+        std::fs::write(&path, "30\n").unwrap();
+        let mut config = Config::default();
+        let applied = config.apply_env(&Vars {
+            lookup: &|name| match name {
+                "CACHE_TTL_FILE" => Some(path.to_string_lossy().into_owned()),
+                "CACHE_TTL_PETS" => Some("5".to_owned()),
+                _ => None,
+            },
+            names: vec!["CACHE_TTL_FILE".to_owned(), "CACHE_TTL_PETS".to_owned()],
+        });
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(applied.is_ok());
+        assert_eq!(config.cache.ttl, 30);
+        assert_eq!(config.cache.ttls.get("pets"), Some(&5));
+        assert!(!config.cache.ttls.contains_key("file"));
+        assert!(!config.cache.ttls.contains_key(""));
+    }
+
+    #[test]
+    fn variables_override_the_file() {
+        let mut env = vars(MONGO);
+        env.extend(vars(&[
+            ("PORT", "9000"),
+            ("CACHE_TTL", "20"),
+            ("CACHE_TTL_ALL_PETS", "0"),
+        ]));
+        let config = load(&env).unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.mongo.db_name, "pets");
+        assert_eq!(config.cache.ttl, 20);
+        assert_eq!(config.cache.ttls.get("all_pets"), Some(&0));
+    }
+
+    #[test]
+    fn rejects_invalid_variables() {
+        let mut env = vars(MONGO);
+        env.insert("PORT".to_owned(), "http".to_owned());
+        match load(&env) {
+            Err(ConfigError::Invalid { name, .. }) => assert_eq!(name, "PORT"),
+            _ => panic!("expected PORT to be invalid"),
+        }
+        let mut env = vars(MONGO);
+        env.insert("MONGO_URL".to_owned(), "localhost".to_owned());
+        match load(&env) {
+            Err(ConfigError::Invalid { name, .. }) => assert_eq!(name, "mongo.url"),
+            _ => panic!("expected the url to be invalid"),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// The environment a database is used in, recorded by a production server when it starts so
+// the seed can tell a production database apart from the others
+
+use crate::environment::Environment;
+
+use bson::doc;
+use mongodb::options::UpdateOptions;
+use mongodb::Database;
+
+const COLLECTION: &str = "_meta";
+const MARK_ID: &str = "environment";
+
+/// The environment the database is marked with, if any
+pub fn marked(database: &Database) -> Result<Option<Environment>, mongodb::error::Error> {
+    let mark = database
+        .collection(COLLECTION)
+        .find_one(doc! { "_id": MARK_ID }, None)?;
+    Ok(mark
+        .as_ref()
+        .and_then(|mark| mark.get_str("name").ok())
+        .and_then(Environment::parse))
+}
+
+/// Marks the database as used in production, only a production server marks databases so a
+/// mark is never downgraded by a seed or a development server
+pub fn mark_production(database: &Database) -> Result<(), mongodb::error::Error> {
+    let options = UpdateOptions {
+        upsert: Some(true),
+        ..UpdateOptions::default()
+    };
+    database.collection(COLLECTION).update_one(
+        doc! { "_id": MARK_ID },
+        doc! { "$set": { "name": Environment::Production.to_string() } },
+        options,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,458 @@
+// Indexes declared by the models, reconciled with the indexes of the database at startup and
+// by `migrate indexes`
+
+use crate::config::IndexSync;
+
+use bson::{doc, Bson, Document};
+use log::{error, info, warn};
+use mongodb::error::ErrorKind;
+use mongodb::Database;
+use std::fmt;
+
+/// The code of the error of `listIndexes` on a collection that doesn't exist yet
+const NAMESPACE_NOT_FOUND: i32 = 26;
+
+/// An index of a model, named like MongoDB names indexes, e.g. `pet_type_1_node.date_created_-1`
+pub struct Index {
+    /// Fields and their direction, 1 ascending and -1 descending
+    pub keys: &'static [(&'static str, i32)],
+    pub unique: bool,
+}
+
+impl Index {
+    pub fn name(&self) -> String {
+        let parts: Vec<String> = self
+            .keys
+            .iter()
+            .map(|(field, direction)| format!("{}_{}", field, direction))
+            .collect();
+        parts.join("_")
+    }
+
+    fn key(&self) -> Document {
+        let mut key = Document::new();
+        for (field, direction) in self.keys {
+            key.insert(*field, *direction);
+        }
+        key
+    }
+
+    fn matches(&self, existing: &Document) -> bool {
+        let key = match existing.get_document("key") {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let unique = existing.get_bool("unique").unwrap_or(false);
+        unique == self.unique
+            && key.len() == self.keys.len()
+            && key.iter().zip(self.keys).all(
+                |((field, direction), (expected, expected_direction))| {
+                    field == expected && direction_of(direction) == Some(*expected_direction)
+                },
+            )
+    }
+}
+
+/// Indexes created by the shell have their direction as a double
+fn direction_of(value: &Bson) -> Option<i32> {
+    match value {
+        Bson::I32(direction) => Some(*direction),
+        Bson::I64(direction) => Some(*direction as i32),
+        Bson::FloatingPoint(direction) => Some(*direction as i32),
+        _ => None,
+    }
+}
+
+/// A model kept in a collection with indexes, `_id` is always indexed and isn't declared
+pub trait Indexed {
+    const COLLECTION: &'static str;
+    const INDEXES: &'static [Index];
+}
+
+/// The indexes declared for a collection
+pub struct CollectionIndexes {
+    pub collection: &'static str,
+    pub indexes: &'static [Index],
+}
+
+impl CollectionIndexes {
+    pub fn of<T: Indexed>() -> Self {
+        CollectionIndexes {
+            collection: T::COLLECTION,
+            indexes: T::INDEXES,
+        }
+    }
+}
+
+/// What reconciling changes, the rest of the drift is only reported
+#[derive(Clone, Copy, Default)]
+pub struct ReconcileOptions {
+    pub create_missing: bool,
+    /// Drops and creates again the indexes whose keys or uniqueness changed
+    pub recreate_changed: bool,
+    /// Drops the indexes no model declares
+    pub drop_undeclared: bool,
+}
+
+pub enum DriftKind {
+    Missing,
+    /// Has the name of a declared index but other keys or uniqueness
+    Changed {
+        existing: String,
+    },
+    Undeclared,
+}
+
+/// A difference between the declared indexes and the indexes of the database
+pub struct Drift {
+    pub collection: &'static str,
+    pub index: String,
+    pub kind: DriftKind,
+    /// Whether reconciling fixed it
+    pub fixed: bool,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{} ", self.collection, self.index)?;
+        match &self.kind {
+            DriftKind::Missing => write!(f, "is missing")?,
+            DriftKind::Changed { existing } => write!(f, "is {} in the database", existing)?,
+            DriftKind::Undeclared => write!(f, "isn't declared by a model")?,
+        }
+        match (&self.kind, self.fixed) {
+            (DriftKind::Missing, true) => write!(f, ", created"),
+            (DriftKind::Changed { .. }, true) => write!(f, ", created again"),
+            (DriftKind::Undeclared, true) => write!(f, ", dropped"),
+            (_, false) => Ok(()),
+        }
+    }
+}
+
+/// Reconciles the indexes when the server starts, the drift that's left is logged so it
+/// can be fixed with `migrate indexes`
+pub fn sync_on_startup(database: &Database, declared: &[CollectionIndexes], sync: IndexSync) {
+    let options = match sync {
+        IndexSync::Create => ReconcileOptions {
+            create_missing: true,
+            ..ReconcileOptions::default()
+        },
+        IndexSync::Check => ReconcileOptions::default(),
+        IndexSync::Off => return,
+    };
+    match reconcile(database, declared, options) {
+        Ok(drift) => {
+            for drift in drift {
+                if drift.fixed {
+                    info!("Index {}", drift);
+                } else {
+                    warn!("Index {}", drift);
+                }
+            }
+        }
+        Err(e) => error!("Unable to reconcile indexes: {}", e),
+    }
+}
+
+/// Compares the indexes of every collection with the declared ones and applies the
+/// `options`, returns the drift found, fixed or not
+pub fn reconcile(
+    database: &Database,
+    declared: &[CollectionIndexes],
+    options: ReconcileOptions,
+) -> Result<Vec<Drift>, mongodb::error::Error> {
+    let mut drift = Vec::new();
+    for collection in declared {
+        drift.extend(reconcile_collection(database, collection, options)?);
+    }
+    Ok(drift)
+}
+
+fn reconcile_collection(
+    database: &Database,
+    declared: &CollectionIndexes,
+    options: ReconcileOptions,
+) -> Result<Vec<Drift>, mongodb::error::Error> {
+    let collection = declared.collection;
+    let existing = list_indexes(database, collection)?;
+    let mut drift = find_drift(declared, &existing);
+    let mut create = Vec::new();
+    for (drift, index) in &mut drift {
+        drift.fixed = match drift.kind {
+            DriftKind::Missing => options.create_missing,
+            DriftKind::Changed { .. } => options.recreate_changed,
+            DriftKind::Undeclared => options.drop_undeclared,
+        };
+        if !drift.fixed {
+            continue;
+        }
+        if let DriftKind::Changed { .. } | DriftKind::Undeclared = drift.kind {
+            drop_index(database, collection, &drift.index)?;
+        }
+        if let Some(index) = index {
+            create.push(*index);
+        }
+    }
+    create_indexes(database, collection, &create)?;
+    Ok(drift.into_iter().map(|(drift, _)| drift).collect())
+}
+
+/// Compares the indexes of a collection with the declared ones, the drift isn't fixed yet and
+/// comes with the declared index for the missing and changed ones
+fn find_drift(
+    declared: &CollectionIndexes,
+    existing: &[Document],
+) -> Vec<(Drift, Option<&'static Index>)> {
+    let collection = declared.collection;
+    let mut drift = Vec::new();
+    // the names of the existing indexes that are declared
+    let mut declared_names = vec!["_id_".to_owned()];
+    for index in declared.indexes {
+        let name = index.name();
+        // an index created by hand under another name is the same index
+        let found = existing
+            .iter()
+            .find(|e| e.get_str("name").ok() == Some(name.as_str()))
+            .or_else(|| existing.iter().find(|e| index.matches(e)));
+        if let Some(found) = found {
+            declared_names.push(found.get_str("name").unwrap_or("").to_owned());
+        }
+        let kind = match found {
+            Some(found) if index.matches(found) => continue,
+            Some(found) => DriftKind::Changed {
+                existing: describe(found),
+            },
+            None => DriftKind::Missing,
+        };
+        drift.push((
+            Drift {
+                collection,
+                index: name,
+                kind,
+                fixed: false,
+            },
+            Some(index),
+        ));
+    }
+    for e in existing {
+        let name = e.get_str("name").unwrap_or("");
+        if declared_names.iter().any(|declared| declared == name) {
+            continue;
+        }
+        drift.push((
+            Drift {
+                collection,
+                index: name.to_owned(),
+                kind: DriftKind::Undeclared,
+                fixed: false,
+            },
+            None,
+        ));
+    }
+    drift
+}
+
+/// The keys and uniqueness of an index of the database, e.g. `unique { username: 1 }`
+fn describe(index: &Document) -> String {
+    let key = index
+        .get_document("key")
+        .map(Document::to_string)
+        .unwrap_or_default();
+    if index.get_bool("unique").unwrap_or(false) {
+        format!("unique {}", key)
+    } else {
+        key
+    }
+}
+
+fn list_indexes(
+    database: &Database,
+    collection: &str,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    let result = match database.run_command(doc! { "listIndexes": collection }, None) {
+        Ok(result) => result,
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::CommandError(error) if error.code == NAMESPACE_NOT_FOUND => {
+                return Ok(Vec::new())
+            }
+            _ => return Err(e),
+        },
+    };
+    // the first batch has every index, a collection has at most 64
+    let indexes = result
+        .get_document("cursor")
+        .and_then(|cursor| cursor.get_array("firstBatch"))
+        .map(|batch| {
+            batch
+                .iter()
+                .filter_map(Bson::as_document)
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(indexes)
+}
+
+fn create_indexes(
+    database: &Database,
+    collection: &str,
+    indexes: &[&Index],
+) -> Result<(), mongodb::error::Error> {
+    if indexes.is_empty() {
+        return Ok(());
+    }
+    let indexes: Vec<Bson> = indexes
+        .iter()
+        .map(|index| {
+            let mut spec = doc! { "key": index.key(), "name": index.name() };
+            if index.unique {
+                spec.insert("unique", true);
+            }
+            Bson::Document(spec)
+        })
+        .collect();
+    database.run_command(
+        doc! { "createIndexes": collection, "indexes": indexes },
+        None,
+    )?;
+    Ok(())
+}
+
+fn drop_index(
+    database: &Database,
+    collection: &str,
+    name: &str,
+) -> Result<(), mongodb::error::Error> {
+    database.run_command(doc! { "dropIndexes": collection, "index": name }, None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PET_INDEXES: &[Index] = &[
+        Index {
+            keys: &[("pet_type", 1), ("node.date_created", -1)],
+            unique: false,
+        },
+        Index {
+            keys: &[("owner", 1)],
+            unique: false,
+        },
+    ];
+
+    static OWNER_INDEXES: &[Index] = &[Index {
+        keys: &[("username", 1)],
+        unique: true,
+    }];
+
+    fn existing(name: &str, key: Document, unique: bool) -> Document {
+        let mut index = doc! { "v": 2, "key": key, "name": name };
+        if unique {
+            index.insert("unique", true);
+        }
+        index
+    }
+
+    fn drift_of(declared: &CollectionIndexes, existing: &[Document]) -> Vec<(String, String)> {
+        find_drift(declared, existing)
+            .into_iter()
+            .map(|(drift, _)| {
+                let kind = match drift.kind {
+                    DriftKind::Missing => "missing".to_owned(),
+                    DriftKind::Changed { existing } => format!("changed from {}", existing),
+                    DriftKind::Undeclared => "undeclared".to_owned(),
+                };
+                (drift.index, kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn indexes_are_named_like_mongodb_names_them() {
+        assert_eq!(PET_INDEXES[0].name(), "pet_type_1_node.date_created_-1");
+        assert_eq!(OWNER_INDEXES[0].name(), "username_1");
+    }
+
+    #[test]
+    fn directions_are_read_from_any_number() {
+        assert_eq!(direction_of(&Bson::I32(-1)), Some(-1));
+        assert_eq!(direction_of(&Bson::I64(1)), Some(1));
+        assert_eq!(direction_of(&Bson::FloatingPoint(-1.0)), Some(-1));
+        assert_eq!(direction_of(&Bson::String("text".to_owned())), None);
+    }
+
+    #[test]
+    fn indexes_match_on_keys_in_order_and_uniqueness() {
+        let index = &PET_INDEXES[0];
+        let key = doc! { "pet_type": 1, "node.date_created": -1 };
+        assert!(index.matches(&existing("any", key.clone(), false)));
+        assert!(!index.matches(&existing("any", key, true)));
+        let shell_key = doc! { "pet_type": 1.0, "node.date_created": -1.0 };
+        assert!(index.matches(&existing("any", shell_key, false)));
+        let reversed = doc! { "node.date_created": -1, "pet_type": 1 };
+        assert!(!index.matches(&existing("any", reversed, false)));
+        let ascending = doc! { "pet_type": 1, "node.date_created": 1 };
+        assert!(!index.matches(&existing("any", ascending, false)));
+        assert!(!index.matches(&existing("any", doc! { "pet_type": 1 }, false)));
+        assert!(!index.matches(&doc! { "name": "any" }));
+    }
+
+    #[test]
+    fn missing_changed_and_undeclared_indexes_are_drift() {
+        let pets = CollectionIndexes {
+            collection: "pets",
+            indexes: PET_INDEXES,
+        };
+        let existing_indexes = vec![
+            existing("_id_", doc! { "_id": 1 }, false),
+            existing("owner_1", doc! { "owner": -1 }, false),
+            existing("kind_1", doc! { "kind": 1 }, false),
+        ];
+        assert_eq!(
+            drift_of(&pets, &existing_indexes),
+            vec![
+                (
+                    "pet_type_1_node.date_created_-1".to_owned(),
+                    "missing".to_owned()
+                ),
+                (
+                    "owner_1".to_owned(),
+                    "changed from { owner: -1 }".to_owned()
+                ),
+                ("kind_1".to_owned(), "undeclared".to_owned()),
+            ]
+        );
+        let owners = CollectionIndexes {
+            collection: "owners",
+            indexes: OWNER_INDEXES,
+        };
+        let not_unique = vec![existing("username_1", doc! { "username": 1 }, false)];
+        assert_eq!(
+            drift_of(&owners, &not_unique),
+            vec![(
+                "username_1".to_owned(),
+                "changed from { username: 1 }".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn an_index_created_under_another_name_isnt_drift() {
+        let pets = CollectionIndexes {
+            collection: "pets",
+            indexes: PET_INDEXES,
+        };
+        let existing_indexes = vec![
+            existing("_id_", doc! { "_id": 1 }, false),
+            existing(
+                "by_type",
+                doc! { "pet_type": 1.0, "node.date_created": -1.0 },
+                false,
+            ),
+            existing("owner_1", doc! { "owner": 1 }, false),
+        ];
+        assert!(find_drift(&pets, &existing_indexes).is_empty());
+    }
+}
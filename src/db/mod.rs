@@ -1,9 +1,26 @@
+// only used by the main and seed binaries
+#[allow(dead_code)]
+pub mod environment;
+pub mod indexes;
 pub mod mongo;
+pub mod slow_queries;
 
+use crate::cache::QueryCache;
+use mongodb::Database;
 use mongodb_base_service::DataSources;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct Clients {
+    /// The database the services of `mongo` use, for commands that aren't about a collection
+    // only read by the debug responses of the main binary and the seed
+    #[allow(dead_code)]
+    pub database: Database,
     pub mongo: DataSources,
+    pub cache: Arc<QueryCache>,
+    /// The id of the request being resolved, unset outside of requests
+    // only read by the graphql routes of the main binary
+    #[allow(dead_code)]
+    pub request_id: Option<String>,
 }
 impl juniper::Context for Clients {}
@@ -1,17 +1,34 @@
 use bson::doc;
-use mongodb::Client;
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use mongodb::options::ClientOptions;
+use mongodb::{Client, Database};
 use mongodb_base_service::DataSources;
-use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub fn connect() -> DataSources {
+use crate::config::{MongoConfig, SlowQueriesConfig};
+use crate::db::slow_queries::SlowQueryLog;
+use crate::metrics::MongoCommandMetrics;
+use crate::telemetry::{MongoCommandSpans, MongoDebugTrace};
+
+pub fn connect(config: &MongoConfig, slow_queries: &SlowQueriesConfig) -> (Database, DataSources) {
     // set up database connection pool
-    let mongo_url = env::var("MONGO_URL").expect("MONGO_URL must be set");
-    let mongo_db_name = env::var("MONGO_DB_NAME").expect("MONGO_DB_NAME must be set");
     let mut data_sources = DataSources::new();
 
-    let client = Client::with_uri_str(&mongo_url)
+    let mut options = ClientOptions::parse(&config.url).expect("Failed to parse MONGO_URL.");
+    let slow_queries = Arc::new(SlowQueryLog::from_config(slow_queries));
+    options.command_event_handler = Some(Arc::new(CommandEvents {
+        metrics: MongoCommandMetrics::default(),
+        spans: MongoCommandSpans::default(),
+        slow_queries: slow_queries.clone(),
+        debug_trace: MongoDebugTrace,
+    }));
+    let client = Client::with_options(options)
         .expect("Failed to initialize client.")
-        .database(&mongo_db_name);
+        .database(&config.db_name);
+    slow_queries.connected(client.clone());
 
     data_sources.create_mongo_service("owners", &client.collection("owners"), None);
     data_sources.create_mongo_service(
@@ -21,5 +38,70 @@ pub fn connect() -> DataSources {
         Some(doc! { "node.date_created": -1 }),
     );
 
-    return data_sources;
+    return (client, data_sources);
+}
+
+/// A client of its own for the health checks, which gives up on selecting a server and
+/// connecting after `timeout` rather than the driver's 30 seconds, and whose pings aren't
+/// counted in the command metrics
+// only used by the health checks of the main binary
+#[allow(dead_code)]
+pub fn connect_for_health(config: &MongoConfig, timeout: Duration) -> Database {
+    let mut options = ClientOptions::parse(&config.url).expect("Failed to parse MONGO_URL.");
+    options.server_selection_timeout = Some(timeout);
+    options.connect_timeout = Some(timeout);
+    options.max_pool_size = Some(1);
+    Client::with_options(options)
+        .expect("Failed to initialize client.")
+        .database(&config.db_name)
+}
+
+/// Checks that the database can be reached
+// only used by the health checks of the main binary
+#[allow(dead_code)]
+pub fn ping(database: &Database) -> Result<(), mongodb::error::Error> {
+    database.run_command(doc! { "ping": 1 }, None).map(|_| ())
+}
+
+/// The collection a command runs on, the value of the command, e.g. `{ "find": "pets" }`,
+/// except for `getMore` which has it in `collection`
+pub fn command_collection(event: &CommandStartedEvent) -> &str {
+    match event.command.get_str("collection") {
+        Ok(collection) if event.command_name == "getMore" => collection,
+        _ => event.command.get_str(&event.command_name).unwrap_or(""),
+    }
+}
+
+/// The client takes a single handler, this one passes the events to the metrics, the
+/// traces, the slow query log and the debug traces
+struct CommandEvents {
+    metrics: MongoCommandMetrics,
+    spans: MongoCommandSpans,
+    slow_queries: Arc<SlowQueryLog>,
+    debug_trace: MongoDebugTrace,
+}
+
+impl CommandEventHandler for CommandEvents {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        self.metrics.handle_command_started_event(event.clone());
+        self.spans.handle_command_started_event(event.clone());
+        self.slow_queries
+            .handle_command_started_event(event.clone());
+        self.debug_trace.handle_command_started_event(event);
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.metrics.handle_command_succeeded_event(event.clone());
+        self.spans.handle_command_succeeded_event(event.clone());
+        self.slow_queries
+            .handle_command_succeeded_event(event.clone());
+        self.debug_trace.handle_command_succeeded_event(event);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.metrics.handle_command_failed_event(event.clone());
+        self.spans.handle_command_failed_event(event.clone());
+        self.slow_queries.handle_command_failed_event(event.clone());
+        self.debug_trace.handle_command_failed_event(event);
+    }
 }
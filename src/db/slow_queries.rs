@@ -0,0 +1,405 @@
+use crate::config::SlowQueriesConfig;
+use crate::db::mongo::command_collection;
+use crate::logging;
+use crate::telemetry;
+
+use bson::{doc, Bson, Document};
+use log::{warn, Level};
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use mongodb::Database;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Logged under its own target so it can be filtered, e.g. `RUST_LOG=info,slow_query=off`
+const LOG_TARGET: &str = "slow_query";
+
+/// Commands whose query plan can be found with `explain`, other commands are logged without
+pub const EXPLAINABLE: &[&str] = &["find", "aggregate", "count", "distinct"];
+
+/// Slow queries waiting to be explained, the next ones are logged without their plan
+const EXPLAIN_QUEUE: usize = 16;
+/// Explains run no more often than this, so a database that's already slow isn't sent an
+/// explain for every one of its slow queries
+const EXPLAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replaces the values of filters and pipelines in the log
+const REDACTED: &str = "?";
+
+/// What's kept of a command until it finishes
+struct StartedCommand {
+    command: Document,
+    collection: String,
+    resolver: Option<String>,
+    request_id: Option<String>,
+}
+
+struct SlowQuery {
+    started: StartedCommand,
+    command_name: String,
+    duration: Duration,
+    failure: Option<String>,
+}
+
+/// Logs the MongoDB commands that take longer than the threshold with their collection,
+/// the shape of their filter and their sort and the resolver that sent them, and the query
+/// plan of slow queries when `explain` is enabled
+///
+/// The started event has the command and is sent on the thread of the resolver, so the
+/// command is kept by request id until it succeeds or fails.
+pub struct SlowQueryLog {
+    threshold: Option<Duration>,
+    explain: bool,
+    started: Mutex<HashMap<i32, StartedCommand>>,
+    explainer: Mutex<Option<SyncSender<SlowQuery>>>,
+    /// When the last slow query was sent to the explainer
+    last_explained: Mutex<Option<Instant>>,
+}
+
+impl SlowQueryLog {
+    pub fn from_config(config: &SlowQueriesConfig) -> Self {
+        SlowQueryLog {
+            threshold: match config.threshold_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            explain: config.explain,
+            started: Mutex::new(HashMap::new()),
+            explainer: Mutex::new(None),
+            last_explained: Mutex::new(None),
+        }
+    }
+
+    /// Explains slow queries on a thread of its own with the client once it's connected,
+    /// so the resolvers don't wait for it
+    pub fn connected(&self, database: Database) {
+        if !self.explain || self.threshold.is_none() {
+            return;
+        }
+        let (sender, receiver) = sync_channel::<SlowQuery>(EXPLAIN_QUEUE);
+        let spawned = thread::Builder::new()
+            .name("slow-query-explain".to_owned())
+            .spawn(move || {
+                for query in receiver {
+                    let plan = explain(&database, &query.started.command);
+                    log_slow_query(&query, Some(plan));
+                }
+            });
+        match spawned {
+            Ok(_) => *self.explainer.lock().unwrap() = Some(sender),
+            Err(e) => warn!("Unable to start explaining slow queries: {}", e),
+        }
+    }
+
+    fn finished(
+        &self,
+        request_id: i32,
+        command_name: &str,
+        duration: Duration,
+        failure: Option<String>,
+    ) {
+        let started = match self.started.lock().unwrap().remove(&request_id) {
+            Some(started) => started,
+            None => return,
+        };
+        match self.threshold {
+            Some(threshold) if duration >= threshold => {}
+            _ => return,
+        }
+        let query = SlowQuery {
+            started,
+            command_name: command_name.to_owned(),
+            duration,
+            failure,
+        };
+        if EXPLAINABLE.contains(&command_name) && query.failure.is_none() && self.may_explain() {
+            if let Some(explainer) = self.explainer.lock().unwrap().as_ref() {
+                // the explainer logs the query with its plan
+                match explainer.try_send(query) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(query)) | Err(TrySendError::Disconnected(query)) => {
+                        log_slow_query(&query, None)
+                    }
+                }
+                return;
+            }
+        }
+        log_slow_query(&query, None);
+    }
+
+    /// Whether the interval since the last explain has passed, and if so starts the next one
+    fn may_explain(&self) -> bool {
+        let mut last_explained = self.last_explained.lock().unwrap();
+        let now = Instant::now();
+        match *last_explained {
+            Some(last) if now.duration_since(last) < EXPLAIN_INTERVAL => false,
+            _ => {
+                *last_explained = Some(now);
+                true
+            }
+        }
+    }
+}
+
+impl CommandEventHandler for SlowQueryLog {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        if self.threshold.is_none() {
+            return;
+        }
+        let collection = command_collection(&event).to_owned();
+        let started = StartedCommand {
+            command: event.command,
+            collection,
+            resolver: telemetry::current_resolver(),
+            request_id: logging::current_request_id(),
+        };
+        self.started
+            .lock()
+            .unwrap()
+            .insert(event.request_id, started);
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.finished(event.request_id, &event.command_name, event.duration, None);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        let failure = Some(event.failure.to_string());
+        self.finished(
+            event.request_id,
+            &event.command_name,
+            event.duration,
+            failure,
+        );
+    }
+}
+
+fn log_slow_query(query: &SlowQuery, plan: Option<Result<Document, String>>) {
+    let started = &query.started;
+    let command = &started.command;
+    let mut fields = Map::new();
+    fields.insert("collection".to_owned(), json!(started.collection));
+    fields.insert("command".to_owned(), json!(query.command_name));
+    fields.insert(
+        "duration_ms".to_owned(),
+        logging::duration_ms(query.duration),
+    );
+    // `count` and `distinct` have their filter in `query`
+    let filter = command.get("filter").or_else(|| command.get("query"));
+    fields.insert("filter".to_owned(), to_json(filter.map(redact).as_ref()));
+    // only has fields and directions
+    fields.insert("sort".to_owned(), to_json(command.get("sort")));
+    if let Some(pipeline) = command.get("pipeline") {
+        fields.insert("pipeline".to_owned(), to_json(Some(&redact(pipeline))));
+    }
+    fields.insert("resolver".to_owned(), json!(started.resolver));
+    if let Some(failure) = &query.failure {
+        fields.insert("error".to_owned(), json!(failure));
+    }
+    match plan.as_ref().map(|plan| plan.as_ref().map(summarize_plan)) {
+        Some(Ok(Some(plan))) => {
+            fields.insert("plan".to_owned(), json!(plan.stages));
+            fields.insert("collection_scan".to_owned(), json!(plan.collection_scan));
+            let winning_plan = Bson::Document(redact_plan(&plan.winning_plan));
+            fields.insert("winning_plan".to_owned(), winning_plan.into());
+        }
+        Some(Ok(None)) => {
+            fields.insert("explain_error".to_owned(), json!("no winning plan"));
+        }
+        Some(Err(e)) => {
+            fields.insert("explain_error".to_owned(), json!(e));
+        }
+        None => {}
+    }
+    let message = format!("slow {} on {}", query.command_name, started.collection);
+    // the explainer logs on its own thread
+    let _request = logging::enter_request(started.request_id.clone());
+    logging::event(Level::Warn, LOG_TARGET, &message, fields);
+}
+
+fn to_json(value: Option<&Bson>) -> Value {
+    value.map_or(Value::Null, |value| value.clone().into())
+}
+
+/// The shape of a filter or pipeline, with its values replaced so the log has the fields and
+/// operators a query used but not the data it looked for. Arrays keep one of each shape,
+/// e.g. `{ "$in": ["?"] }`.
+pub fn redact(value: &Bson) -> Bson {
+    match value {
+        Bson::Document(document) => Bson::Document(
+            document
+                .iter()
+                .map(|(key, value)| (key.clone(), redact(value)))
+                .collect(),
+        ),
+        Bson::Array(values) => {
+            let mut shapes: Vec<Bson> = Vec::new();
+            for shape in values.iter().map(redact) {
+                if !shapes.contains(&shape) {
+                    shapes.push(shape);
+                }
+            }
+            Bson::Array(shapes)
+        }
+        _ => Bson::String(REDACTED.to_owned()),
+    }
+}
+
+/// A winning plan with the values of the filters of its stages and the bounds of its index
+/// scans redacted
+fn redact_plan(plan: &Document) -> Document {
+    let redact_stage = |value: &Bson| match value {
+        Bson::Document(stage) => Bson::Document(redact_plan(stage)),
+        Bson::Array(stages) => Bson::Array(
+            stages
+                .iter()
+                .map(|stage| match stage {
+                    Bson::Document(stage) => Bson::Document(redact_plan(stage)),
+                    value => value.clone(),
+                })
+                .collect(),
+        ),
+        value => value.clone(),
+    };
+    plan.iter()
+        .map(|(key, value)| {
+            let value = match key.as_str() {
+                "filter" | "parsedQuery" | "indexBounds" => redact(value),
+                _ => redact_stage(value),
+            };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+/// Runs the command again with `explain`, which only plans it
+pub fn explain(database: &Database, command: &Document) -> Result<Document, String> {
+    let mut command = command.clone();
+    // the driver adds the database and session, which can't be in the explained command
+    let added: Vec<String> = command
+        .keys()
+        .filter(|key| key.starts_with('$') || *key == "lsid" || *key == "txnNumber")
+        .cloned()
+        .collect();
+    for key in added {
+        command.remove(&key);
+    }
+    database
+        .run_command(
+            doc! { "explain": command, "verbosity": "queryPlanner" },
+            None,
+        )
+        .map_err(|e| e.to_string())
+}
+
+/// The plan the server chose for a command
+pub struct PlanSummary {
+    /// The stages from the top, e.g. `FETCH > IXSCAN pet_type_1`
+    pub stages: String,
+    pub collection_scan: bool,
+    pub winning_plan: Document,
+}
+
+/// Summarizes the result of `explain`, `None` when it has no winning plan
+pub fn summarize_plan(explain: &Document) -> Option<PlanSummary> {
+    let plan = winning_plan(explain)?;
+    let mut collection_scan = false;
+    let stages = describe_stage(plan, &mut collection_scan);
+    Some(PlanSummary {
+        stages,
+        collection_scan,
+        winning_plan: plan.clone(),
+    })
+}
+
+/// The plan the server chose, which aggregations have in the `$cursor` of their first
+/// stage on older servers
+fn winning_plan(explain: &Document) -> Option<&Document> {
+    let planner = match explain.get_document("queryPlanner") {
+        Ok(planner) => planner,
+        Err(_) => explain
+            .get_array("stages")
+            .ok()?
+            .first()?
+            .as_document()?
+            .get_document("$cursor")
+            .ok()?
+            .get_document("queryPlanner")
+            .ok()?,
+    };
+    let plan = planner.get_document("winningPlan").ok()?;
+    // the slot based engine nests the plan
+    Some(plan.get_document("queryPlan").unwrap_or(plan))
+}
+
+/// The stages of a plan from the top, e.g. `FETCH > IXSCAN pet_type_1`
+fn describe_stage(stage: &Document, collection_scan: &mut bool) -> String {
+    let name = stage.get_str("stage").unwrap_or("UNKNOWN");
+    *collection_scan |= name == "COLLSCAN";
+    let mut description = match stage.get_str("indexName") {
+        Ok(index) => format!("{} {}", name, index),
+        Err(_) => name.to_owned(),
+    };
+    if let Ok(input) = stage.get_document("inputStage") {
+        description.push_str(" > ");
+        description.push_str(&describe_stage(input, collection_scan));
+    } else if let Ok(inputs) = stage.get_array("inputStages") {
+        let inputs: Vec<String> = inputs
+            .iter()
+            .filter_map(Bson::as_document)
+            .map(|input| describe_stage(input, collection_scan))
+            .collect();
+        description.push_str(&format!(" > ({})", inputs.join(" | ")));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_the_values_of_filters() {
+        let filter = Bson::Document(doc! {
+            "pet_type": "Dog",
+            "age": { "$gt": 3 },
+            "owner": { "$in": ["a", "b", "c"] },
+            "$or": [{ "name": "Rex" }, { "name": "Fido" }, { "age": null }],
+        });
+        let expected = Bson::Document(doc! {
+            "pet_type": "?",
+            "age": { "$gt": "?" },
+            "owner": { "$in": ["?"] },
+            "$or": [{ "name": "?" }, { "age": "?" }],
+        });
+        assert_eq!(redact(&filter), expected);
+    }
+
+    #[test]
+    fn redacts_the_filters_and_bounds_of_plans() {
+        let plan = doc! {
+            "stage": "FETCH",
+            "filter": { "name": { "$eq": "Rex" } },
+            "inputStage": {
+                "stage": "IXSCAN",
+                "indexName": "pet_type_1",
+                "indexBounds": { "pet_type": ["[\"Dog\", \"Dog\"]"] },
+            },
+        };
+        let expected = doc! {
+            "stage": "FETCH",
+            "filter": { "name": { "$eq": "?" } },
+            "inputStage": {
+                "stage": "IXSCAN",
+                "indexName": "pet_type_1",
+                "indexBounds": { "pet_type": ["?"] },
+            },
+        };
+        assert_eq!(redact_plan(&plan), expected);
+    }
+}
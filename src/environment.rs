@@ -0,0 +1,47 @@
+use serde::de::{self, Deserialize, Deserializer};
+use std::fmt;
+
+/// The environment the service is deployed to, `environment` in the config or `APP_ENV`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Environment {
+    Development,
+    Staging,
+    Production,
+}
+
+impl Environment {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "development" | "dev" | "local" => Some(Environment::Development),
+            "staging" | "stage" => Some(Environment::Staging),
+            "production" | "prod" => Some(Environment::Production),
+            _ => None,
+        }
+    }
+
+    pub fn is_production(self) -> bool {
+        self == Environment::Production
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Environment::Development => write!(f, "development"),
+            Environment::Staging => write!(f, "staging"),
+            Environment::Production => write!(f, "production"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Environment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Environment::parse(&value).ok_or_else(|| {
+            de::Error::custom(format!(
+                "unknown environment {}, expected development, staging or production",
+                value
+            ))
+        })
+    }
+}
@@ -0,0 +1,306 @@
+use graphql_parser::query::{self, OperationDefinition};
+use graphql_parser::schema::{Definition, Directive, TypeDefinition, Value};
+use graphql_parser::Pos;
+use juniper::{
+    graphql_union, graphql_value, FieldError, InputValue, ParseScalarResult, ParseScalarValue,
+};
+use lazy_static::lazy_static;
+use mongodb_base_service::{BaseService, ServiceError, ID};
+use serde_json::Map;
+
+use crate::db::Clients;
+use crate::models::{Owner, Pet};
+use crate::schema::{create_schema, Schema};
+use crate::sdl;
+
+/// Object types the gateway can resolve through `_entities`, keyed by id
+const ENTITIES: [&str; 2] = ["Pet", "Owner"];
+/// Types and root fields from the federation spec, the gateway adds these itself
+const FEDERATION_TYPES: [&str; 3] = ["_Any", "_Entity", "_Service"];
+const FEDERATION_FIELDS: [&str; 2] = ["_service", "_entities"];
+
+#[derive(juniper::GraphQLObject)]
+#[graphql(name = "_Service")]
+pub struct Service {
+    pub sdl: String,
+}
+
+lazy_static! {
+    /// The schema doesn't change while the service runs
+    static ref SERVICE_SDL: String = service_sdl(&create_schema());
+}
+
+/// A reference to an entity sent by the gateway, its `__typename` and `@key` fields
+pub struct Representation(Map<String, serde_json::Value>);
+
+// juniper only accepts scalar values for scalars, so the representations the gateway sends
+// as variables are json encoded by `encode_representations` before the request is executed
+juniper::graphql_scalar!(Representation as "_Any" {
+    description: "A reference to an entity sent by the gateway"
+
+    resolve(&self) -> Value {
+        juniper::Value::scalar(serde_json::Value::Object(self.0.clone()).to_string())
+    }
+
+    from_input_value(v: &InputValue) -> Option<Representation> {
+        match serde_json::from_str(v.as_scalar_value::<String>()?).ok()? {
+            serde_json::Value::Object(fields) => Some(Representation(fields)),
+            _ => None,
+        }
+    }
+
+    from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a> {
+        <String as ParseScalarValue>::from_str(value)
+    }
+});
+
+/// Encodes the values of the `_Any` variables of the operation as json strings, which is
+/// how the `_Any` scalar reads them
+// only used by the graphql routes of the main binary
+#[allow(dead_code)]
+pub fn encode_representations(operation: &query::OperationDefinition, variables: &mut InputValue) {
+    let definitions = match operation {
+        OperationDefinition::Query(q) => &q.variable_definitions,
+        _ => return,
+    };
+    let variables = match variables {
+        InputValue::Object(variables) => variables,
+        _ => return,
+    };
+    for definition in definitions {
+        if innermost_name(&definition.var_type) != "_Any" {
+            continue;
+        }
+        for (name, value) in variables.iter_mut() {
+            if name.item == definition.name {
+                encode_objects(&mut value.item);
+            }
+        }
+    }
+}
+
+fn innermost_name(value_type: &query::Type) -> &str {
+    match value_type {
+        query::Type::NamedType(name) => name,
+        query::Type::ListType(inner) | query::Type::NonNullType(inner) => innermost_name(inner),
+    }
+}
+
+fn encode_objects(value: &mut InputValue) {
+    match value {
+        InputValue::List(items) => {
+            for item in items {
+                encode_objects(&mut item.item);
+            }
+        }
+        InputValue::Object(_) => {
+            let encoded = serde_json::to_string(value).unwrap_or_default();
+            *value = InputValue::scalar(encoded);
+        }
+        _ => {}
+    }
+}
+
+pub enum Entity {
+    Pet(Pet),
+    Owner(Owner),
+}
+
+graphql_union!(Entity: Clients as "_Entity" |&self| {
+    instance_resolvers: |_| {
+        &Pet => match *self { Entity::Pet(ref p) => Some(p), _ => None },
+        &Owner => match *self { Entity::Owner(ref o) => Some(o), _ => None },
+    }
+});
+
+/// Looks up the entity a representation refers to, None if it doesn't exist
+pub fn resolve_entity(
+    ctx: &Clients,
+    representation: &Representation,
+) -> Result<Option<Entity>, FieldError> {
+    let fields = &representation.0;
+    let typename = match fields.get("__typename") {
+        Some(serde_json::Value::String(typename)) => typename.as_str(),
+        _ => {
+            return Err(bad_representation(
+                "The representation has no __typename".to_owned(),
+            ))
+        }
+    };
+    let id: ID = match fields.get("id").cloned().map(serde_json::from_value) {
+        Some(Ok(id)) => id,
+        _ => {
+            return Err(bad_representation(format!(
+                "The representation of {} has no id",
+                typename
+            )))
+        }
+    };
+    match typename {
+        "Pet" => {
+            let service = &ctx.mongo.get_mongo_service("pets").unwrap();
+            let result: Result<Option<Pet>, ServiceError> = service.find_one_by_id(id);
+            Ok(result?.map(Entity::Pet))
+        }
+        "Owner" => {
+            let service = &ctx.mongo.get_mongo_service("owners").unwrap();
+            let result: Result<Option<Owner>, ServiceError> = service.find_one_by_id(id);
+            Ok(result?.map(Entity::Owner))
+        }
+        other => Err(bad_representation(format!("{} is not an entity", other))),
+    }
+}
+
+fn bad_representation(message: String) -> FieldError {
+    FieldError::new(message, graphql_value!({ "code": "BAD_REQUEST" }))
+}
+
+/// The SDL reported to the gateway, built once
+pub fn cached_service_sdl() -> String {
+    SERVICE_SDL.clone()
+}
+
+/// The SDL reported to the gateway, the schema's without the federation types and fields
+pub fn service_sdl(schema: &Schema) -> String {
+    let mut document = sdl::schema_document(schema);
+    document.definitions.retain(|definition| match definition {
+        Definition::TypeDefinition(t) => !FEDERATION_TYPES.contains(&sdl::type_name(t)),
+        _ => true,
+    });
+    for definition in document.definitions.iter_mut() {
+        if let Definition::TypeDefinition(TypeDefinition::Object(object)) = definition {
+            if object.name == "Query" {
+                object
+                    .fields
+                    .retain(|f| !FEDERATION_FIELDS.contains(&f.name.as_str()));
+            }
+        }
+    }
+    document.to_string()
+}
+
+/// The `@key` of an entity type, none for other types
+pub fn key_directives(type_name: &str) -> Vec<Directive> {
+    if !ENTITIES.contains(&type_name) {
+        return vec![];
+    }
+    vec![Directive {
+        position: Pos::default(),
+        name: "key".to_owned(),
+        arguments: vec![("fields".to_owned(), Value::String("id".to_owned()))],
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::QueryCache;
+    use crate::config::{CacheConfig, MongoConfig, SlowQueriesConfig};
+    use crate::db::mongo;
+    use juniper::{FromInputValue, Variables};
+    use std::sync::Arc;
+
+    const ENTITIES_QUERY: &str = "
+        query($representations: [_Any!]!) {
+            _entities(representations: $representations) { __typename }
+        }
+    ";
+
+    /// Runs a query the way the graphql routes do, with the `_Any` variables encoded
+    fn execute(query: &str, variables: serde_json::Value) -> serde_json::Value {
+        let document = graphql_parser::parse_query(query).unwrap();
+        let operation = document
+            .definitions
+            .iter()
+            .find_map(|definition| match definition {
+                query::Definition::Operation(operation) => Some(operation),
+                query::Definition::Fragment(_) => None,
+            })
+            .unwrap();
+        let mut variables: InputValue = serde_json::from_value(variables).unwrap();
+        encode_representations(operation, &mut variables);
+        let variables: Variables = variables
+            .to_object_value()
+            .unwrap()
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.clone()))
+            .collect();
+        let config = MongoConfig {
+            url: "mongodb://localhost:27017".to_owned(),
+            ..MongoConfig::default()
+        };
+        let (database, data_sources) = mongo::connect(&config, &SlowQueriesConfig::default());
+        let clients = Clients {
+            database,
+            mongo: data_sources,
+            cache: Arc::new(QueryCache::from_config(&CacheConfig::default())),
+            request_id: None,
+        };
+        let schema = create_schema();
+        let result = juniper::execute(query, None, &schema, &variables, &clients);
+        let (data, errors) = result.unwrap_or_else(|e| panic!("{:?}", e));
+        serde_json::json!({ "data": data, "errors": errors })
+    }
+
+    #[test]
+    fn reads_representations_as_any_scalars() {
+        let mut variables: InputValue = serde_json::from_value(serde_json::json!({
+            "representations": [{ "__typename": "Pet", "id": "1" }],
+            "other": { "a": 1 },
+        }))
+        .unwrap();
+        let document = graphql_parser::parse_query(ENTITIES_QUERY).unwrap();
+        let operation = match &document.definitions[0] {
+            query::Definition::Operation(operation) => operation,
+            query::Definition::Fragment(_) => unreachable!(),
+        };
+        encode_representations(operation, &mut variables);
+        let variables = variables.to_object_value().unwrap();
+        let representation = match variables["representations"] {
+            InputValue::List(items) => Representation::from_input_value(&items[0].item),
+            _ => None,
+        };
+        let fields = representation.unwrap().0;
+        assert_eq!(fields["__typename"], "Pet");
+        assert_eq!(fields["id"], "1");
+        // only the `_Any` variables are encoded
+        assert!(variables["other"].to_object_value().is_some());
+    }
+
+    #[test]
+    fn bad_representations_are_null_with_an_error() {
+        let variables = serde_json::json!({
+            "representations": [
+                { "__typename": "Nope", "id": "1" },
+                { "__typename": "Pet" },
+                { "id": "1" },
+            ],
+        });
+        let response = execute(ENTITIES_QUERY, variables);
+        assert_eq!(
+            response["data"]["_entities"],
+            serde_json::json!([null, null, null])
+        );
+        let messages: Vec<&str> = response["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["message"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "representations[0]: Nope is not an entity",
+                "representations[1]: The representation of Pet has no id",
+                "representations[2]: The representation has no __typename",
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_the_service_sdl_once() {
+        assert_eq!(cached_service_sdl(), service_sdl(&create_schema()));
+        assert!(!cached_service_sdl().contains("_Any"));
+        assert!(cached_service_sdl().contains("type Pet @key(fields: \"id\")"));
+    }
+}
@@ -0,0 +1,405 @@
+// Random owners and pets for load and scale testing, the same seed generates the same data
+//
+// ChaCha8 is used rather than `StdRng`, whose algorithm can change between versions of rand.
+
+use super::{FixtureError, SeedReport};
+use crate::db::Clients;
+use crate::models::common::Gender;
+use crate::models::{NewOwner, NewPet, PetTypes};
+
+use bson::oid::ObjectId;
+use mongodb_base_service::{BaseService, ID};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+
+const MALE_NAMES: &[&str] = &[
+    "James",
+    "John",
+    "Robert",
+    "Michael",
+    "William",
+    "David",
+    "Richard",
+    "Joseph",
+    "Thomas",
+    "Charles",
+    "Daniel",
+    "Matthew",
+    "Anthony",
+    "Mark",
+    "Steven",
+    "Paul",
+    "Andrew",
+    "Joshua",
+    "Kevin",
+    "Brian",
+    "George",
+    "Edward",
+    "Ronald",
+    "Jason",
+    "Ryan",
+    "Jacob",
+    "Gary",
+    "Eric",
+    "Jonathan",
+    "Stephen",
+    "Larry",
+    "Justin",
+    "Scott",
+    "Brandon",
+    "Frank",
+    "Gregory",
+    "Samuel",
+    "Raymond",
+    "Patrick",
+    "Alexander",
+    "Carlos",
+    "Luis",
+    "Juan",
+    "Ahmed",
+    "Wei",
+    "Hiroshi",
+];
+
+const FEMALE_NAMES: &[&str] = &[
+    "Mary",
+    "Patricia",
+    "Jennifer",
+    "Linda",
+    "Elizabeth",
+    "Barbara",
+    "Susan",
+    "Jessica",
+    "Sarah",
+    "Karen",
+    "Lisa",
+    "Nancy",
+    "Betty",
+    "Margaret",
+    "Sandra",
+    "Ashley",
+    "Kimberly",
+    "Emily",
+    "Donna",
+    "Michelle",
+    "Carol",
+    "Amanda",
+    "Melissa",
+    "Deborah",
+    "Stephanie",
+    "Rebecca",
+    "Sharon",
+    "Laura",
+    "Cynthia",
+    "Amy",
+    "Angela",
+    "Helen",
+    "Anna",
+    "Brenda",
+    "Nicole",
+    "Emma",
+    "Olivia",
+    "Sofia",
+    "Maria",
+    "Fatima",
+    "Mei",
+    "Yuki",
+    "Eileen",
+    "Jane",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Smith",
+    "Johnson",
+    "Williams",
+    "Brown",
+    "Jones",
+    "Garcia",
+    "Miller",
+    "Davis",
+    "Rodriguez",
+    "Martinez",
+    "Hernandez",
+    "Lopez",
+    "Gonzalez",
+    "Wilson",
+    "Anderson",
+    "Thomas",
+    "Taylor",
+    "Moore",
+    "Jackson",
+    "Martin",
+    "Lee",
+    "Perez",
+    "Thompson",
+    "White",
+    "Harris",
+    "Sanchez",
+    "Clark",
+    "Ramirez",
+    "Lewis",
+    "Robinson",
+    "Walker",
+    "Young",
+    "Allen",
+    "King",
+    "Wright",
+    "Scott",
+    "Torres",
+    "Nguyen",
+    "Hill",
+    "Flores",
+    "Green",
+    "Adams",
+    "Nelson",
+    "Baker",
+    "Hall",
+    "Rivera",
+    "Campbell",
+    "Mitchell",
+    "Carter",
+    "Roberts",
+    "Goldman",
+    "Phillips",
+    "Chen",
+    "Kim",
+];
+
+const PET_NAMES: &[&str] = &[
+    "Bella", "Max", "Luna", "Charlie", "Lucy", "Cooper", "Daisy", "Milo", "Bailey", "Buddy",
+    "Lola", "Rocky", "Sadie", "Bear", "Molly", "Duke", "Stella", "Tucker", "Maggie", "Oliver",
+    "Chloe", "Leo", "Sophie", "Jack", "Penny", "Toby", "Zoe", "Oscar", "Ruby", "Teddy", "Coco",
+    "Simba", "Nala", "Loki", "Ginger", "Pepper", "Shadow", "Smokey", "Tiger", "Oreo", "Cleo",
+    "Fido", "Bubbles", "Nemo", "Goldie", "Squirt", "Peanut", "Nibbles", "Hazel", "Biscuit",
+    "Shelly", "Speedy", "Fonz", "Lurch", "Ziggy", "Mochi", "Pickles", "Waffles", "Pumpkin",
+];
+
+/// How common each type is and the oldest a pet of the type gets
+const PET_TYPES: &[(PetTypes, u32, i32)] = &[
+    (PetTypes::Dog, 40, 16),
+    (PetTypes::Cat, 35, 20),
+    (PetTypes::Fish, 12, 6),
+    (PetTypes::Hamster, 8, 3),
+    (PetTypes::Turtle, 5, 40),
+];
+
+/// The share of pets without an owner and of pets whose age isn't known
+const STRAY_RATIO: f64 = 0.05;
+const UNKNOWN_AGE_RATIO: f64 = 0.05;
+
+pub struct GenerateOptions {
+    pub owners: usize,
+    pub pets: usize,
+    /// Generates the same data every time it's given
+    pub seed: u64,
+    /// The documents sent by each insert
+    pub batch_size: usize,
+}
+
+/// An owner with its `_id`, generated rather than given by the insert so that the pets of a
+/// seed get the same owners on every run
+#[derive(Serialize)]
+struct GeneratedOwner {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    #[serde(flatten)]
+    owner: NewOwner,
+}
+
+/// How much of a collection is inserted
+pub struct Progress {
+    pub collection: &'static str,
+    pub inserted: usize,
+    pub total: usize,
+}
+
+/// Inserts random owners and then pets owned by them, calling `progress` after every batch.
+/// The collections are expected to be empty, usernames are only unique among the generated
+/// owners.
+pub fn generate(
+    clients: &Clients,
+    options: &GenerateOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<SeedReport, FixtureError> {
+    let mut generator = Generator::new(options.seed);
+    let batch_size = options.batch_size.max(1);
+    let mut report = SeedReport::default();
+
+    let owners = clients.mongo.get_mongo_service("owners").unwrap();
+    let mut owner_ids: Vec<ID> = Vec::with_capacity(options.owners);
+    while owner_ids.len() < options.owners {
+        let count = batch_size.min(options.owners - owner_ids.len());
+        let batch: Vec<GeneratedOwner> = (0..count).map(|_| generator.owner()).collect();
+        owner_ids.extend(batch.iter().map(|owner| ID::ObjectId(owner.id.clone())));
+        let _: Vec<ID> = owners.insert_many(batch, None)?;
+        progress(Progress {
+            collection: "owners",
+            inserted: owner_ids.len(),
+            total: options.owners,
+        });
+    }
+    report.owners_inserted = owner_ids.len();
+
+    let pets = clients.mongo.get_mongo_service("pets").unwrap();
+    while report.pets_inserted < options.pets {
+        let count = batch_size.min(options.pets - report.pets_inserted);
+        let batch: Vec<NewPet> = (0..count).map(|_| generator.pet(&owner_ids)).collect();
+        let _: Vec<ID> = pets.insert_many(batch, None)?;
+        report.pets_inserted += count;
+        progress(Progress {
+            collection: "pets",
+            inserted: report.pets_inserted,
+            total: options.pets,
+        });
+    }
+    Ok(report)
+}
+
+struct Generator {
+    rng: ChaCha8Rng,
+    pet_types: WeightedIndex<u32>,
+    /// How many owners have each username so far, to number the next ones
+    usernames: HashMap<String, usize>,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Self {
+        let weights = PET_TYPES.iter().map(|(_, weight, _)| *weight);
+        Generator {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            pet_types: WeightedIndex::new(weights).unwrap(),
+            usernames: HashMap::new(),
+        }
+    }
+
+    fn owner(&mut self) -> GeneratedOwner {
+        let gender = match self.rng.gen_range(0, 100) {
+            0..=47 => Gender::Male,
+            48..=95 => Gender::Female,
+            _ => Gender::Other,
+        };
+        let first_names = match gender {
+            Gender::Male => MALE_NAMES,
+            Gender::Female => FEMALE_NAMES,
+            Gender::Other => {
+                if self.rng.gen() {
+                    MALE_NAMES
+                } else {
+                    FEMALE_NAMES
+                }
+            }
+        };
+        let first_name = *first_names.choose(&mut self.rng).unwrap();
+        let last_name = *LAST_NAMES.choose(&mut self.rng).unwrap();
+        GeneratedOwner {
+            id: ObjectId::with_bytes(self.rng.gen()),
+            owner: NewOwner {
+                username: self.username(first_name, last_name),
+                first_name: first_name.to_owned(),
+                last_name: last_name.to_owned(),
+                gender,
+            },
+        }
+    }
+
+    /// `jsmith`, then `jsmith2`, `jsmith3`... names don't end with digits so they can't clash
+    fn username(&mut self, first_name: &str, last_name: &str) -> String {
+        let initial: String = first_name.chars().take(1).collect();
+        let username = format!("{}{}", initial, last_name).to_lowercase();
+        let count = self.usernames.entry(username.clone()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            username
+        } else {
+            format!("{}{}", username, count)
+        }
+    }
+
+    fn pet(&mut self, owners: &[ID]) -> NewPet {
+        let (pet_type, _, oldest) = PET_TYPES[self.pet_types.sample(&mut self.rng)];
+        let age = if self.rng.gen_bool(UNKNOWN_AGE_RATIO) {
+            None
+        } else {
+            Some(self.rng.gen_range(0, oldest + 1))
+        };
+        let gender = if self.rng.gen() {
+            Gender::Male
+        } else {
+            Gender::Female
+        };
+        let owner = if self.rng.gen_bool(STRAY_RATIO) {
+            None
+        } else {
+            owners.choose(&mut self.rng).cloned()
+        };
+        NewPet {
+            name: PET_NAMES.choose(&mut self.rng).unwrap().to_string(),
+            pet_type,
+            age,
+            gender,
+            owner,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The documents the generator inserts for `seed`, as they're sent to the database
+    fn generated(seed: u64) -> (Vec<bson::Document>, Vec<bson::Document>) {
+        let mut generator = Generator::new(seed);
+        let owners: Vec<GeneratedOwner> = (0..200).map(|_| generator.owner()).collect();
+        let ids: Vec<ID> = owners
+            .iter()
+            .map(|owner| ID::ObjectId(owner.id.clone()))
+            .collect();
+        let pets: Vec<NewPet> = (0..500).map(|_| generator.pet(&ids)).collect();
+        let to_documents = |values: Vec<bson::Bson>| {
+            values
+                .into_iter()
+                .map(|value| value.as_document().unwrap().clone())
+                .collect()
+        };
+        (
+            to_documents(owners.iter().map(|o| bson::to_bson(o).unwrap()).collect()),
+            to_documents(pets.iter().map(|p| bson::to_bson(p).unwrap()).collect()),
+        )
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_data() {
+        let (owners, pets) = generated(42);
+        assert_eq!((owners.clone(), pets.clone()), generated(42));
+        assert_ne!(owners, generated(43).0);
+
+        // pets refer to the generated owners by their _id
+        let owner_ids: Vec<&bson::Bson> = owners.iter().map(|o| o.get("_id").unwrap()).collect();
+        assert!(owner_ids
+            .iter()
+            .all(|id| matches!(id, bson::Bson::ObjectId(_))));
+        let owned = pets.iter().filter_map(|pet| match pet.get("owner") {
+            Some(bson::Bson::Null) | None => None,
+            Some(owner) => Some(owner),
+        });
+        for owner in owned {
+            assert!(owner_ids.contains(&owner));
+        }
+    }
+
+    #[test]
+    fn usernames_are_unique() {
+        let (owners, _) = generated(7);
+        let mut usernames: Vec<&str> = owners
+            .iter()
+            .map(|o| o.get_str("username").unwrap())
+            .collect();
+        usernames.sort();
+        usernames.dedup();
+        assert_eq!(usernames.len(), owners.len());
+    }
+}
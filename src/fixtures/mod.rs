@@ -0,0 +1,495 @@
+// Fixtures of owners and pets loaded by the seed binary from json or yaml files
+//
+// Pets refer to their owner by username, `owner: "@jsmith"`, so fixtures don't depend on
+// the ids the database gives the owners.
+
+pub mod generate;
+
+use crate::db::Clients;
+use crate::environment::Environment;
+use crate::models::common::Gender;
+use crate::models::{NewOwner, PetTypes};
+
+use bson::{doc, Bson, Document};
+use chrono::Utc;
+use mongodb::options::UpdateOptions;
+use mongodb_base_service::{BaseService, ServiceError, ID};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A file of fixtures, every list is optional
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureFile {
+    /// The environments the file is loaded in, every environment when empty
+    #[serde(default)]
+    environments: Vec<Environment>,
+    #[serde(default)]
+    owners: Vec<NewOwner>,
+    #[serde(default)]
+    pets: Vec<PetFixture>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PetFixture {
+    name: String,
+    pet_type: PetTypes,
+    age: Option<i32>,
+    gender: Gender,
+    /// `@<username>` of the owner
+    #[serde(skip_serializing)]
+    owner: Option<String>,
+}
+
+/// The fixtures of every file loaded for an environment
+#[derive(Default)]
+pub struct Fixtures {
+    owners: Vec<NewOwner>,
+    /// With the file they're from, to report unknown owners
+    pets: Vec<(PathBuf, PetFixture)>,
+}
+
+#[derive(Debug)]
+pub enum FixtureError {
+    Read {
+        path: PathBuf,
+        message: String,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    UnknownOwner {
+        path: PathBuf,
+        pet: String,
+        owner: String,
+    },
+    Service(ServiceError),
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixtureError::Read { path, message } => {
+                write!(f, "Unable to read {}: {}", path.display(), message)
+            }
+            FixtureError::Parse { path, message } => {
+                write!(f, "Invalid {}: {}", path.display(), message)
+            }
+            FixtureError::UnknownOwner { path, pet, owner } => write!(
+                f,
+                "Invalid {}: the owner {} of {} isn't an @username of the fixtures or the database",
+                path.display(),
+                owner,
+                pet
+            ),
+            FixtureError::Service(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ServiceError> for FixtureError {
+    fn from(e: ServiceError) -> FixtureError {
+        FixtureError::Service(e)
+    }
+}
+
+impl From<mongodb::error::Error> for FixtureError {
+    fn from(e: mongodb::error::Error) -> FixtureError {
+        FixtureError::Service(ServiceError::from(e))
+    }
+}
+
+impl Fixtures {
+    /// Loads the files of `environment` among `paths`, directories are read in the order of
+    /// their file names
+    pub fn load(paths: &[PathBuf], environment: Environment) -> Result<Self, FixtureError> {
+        let mut fixtures = Fixtures::default();
+        for path in paths {
+            for file in fixture_files(path)? {
+                let fixture = read_file(&file)?;
+                if !fixture.environments.is_empty() && !fixture.environments.contains(&environment)
+                {
+                    continue;
+                }
+                fixtures.owners.extend(fixture.owners);
+                let pets = fixture.pets.into_iter().map(|pet| (file.clone(), pet));
+                fixtures.pets.extend(pets);
+            }
+        }
+        Ok(fixtures)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty() && self.pets.is_empty()
+    }
+
+    /// Checks that every pet refers to an owner of the fixtures, to run before the collections
+    /// are dropped as the owners of the database are dropped with them
+    pub fn check_owners(&self) -> Result<(), FixtureError> {
+        let usernames: HashSet<&str> = self.owners.iter().map(|o| o.username.as_str()).collect();
+        for (path, pet) in &self.pets {
+            let owner = match &pet.owner {
+                Some(owner) => owner,
+                None => continue,
+            };
+            match owner.strip_prefix('@') {
+                Some(username) if usernames.contains(username) => {}
+                _ => {
+                    return Err(FixtureError::UnknownOwner {
+                        path: path.clone(),
+                        pet: pet.name.clone(),
+                        owner: owner.clone(),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fixture_files(path: &Path) -> Result<Vec<PathBuf>, FixtureError> {
+    let read_error = |e: std::io::Error| FixtureError::Read {
+        path: path.to_owned(),
+        message: e.to_string(),
+    };
+    if !fs::metadata(path).map_err(read_error)?.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path).map_err(read_error)? {
+        let file = entry.map_err(read_error)?.path();
+        if format_of(&file).is_some() {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+enum Format {
+    Json,
+    Yaml,
+}
+
+fn format_of(path: &Path) -> Option<Format> {
+    match path.extension()?.to_str()? {
+        "json" => Some(Format::Json),
+        "yaml" | "yml" => Some(Format::Yaml),
+        _ => None,
+    }
+}
+
+fn read_file(path: &Path) -> Result<FixtureFile, FixtureError> {
+    let contents = fs::read_to_string(path).map_err(|e| FixtureError::Read {
+        path: path.to_owned(),
+        message: e.to_string(),
+    })?;
+    let parsed = match format_of(path) {
+        Some(Format::Json) => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        Some(Format::Yaml) => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+        None => Err("expected a .json, .yaml or .yml file".to_owned()),
+    };
+    parsed.map_err(|message| FixtureError::Parse {
+        path: path.to_owned(),
+        message,
+    })
+}
+
+/// What a seed wrote
+#[derive(Debug, Default)]
+pub struct SeedReport {
+    pub owners_inserted: usize,
+    pub owners_updated: usize,
+    pub pets_inserted: usize,
+    pub pets_updated: usize,
+}
+
+impl fmt::Display for SeedReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Owners: {} inserted, {} updated. Pets: {} inserted, {} updated.",
+            self.owners_inserted, self.owners_updated, self.pets_inserted, self.pets_updated
+        )
+    }
+}
+
+/// Inserts the fixtures into the collections, which are expected to be empty
+pub fn insert(clients: &Clients, fixtures: Fixtures) -> Result<SeedReport, FixtureError> {
+    let mut report = SeedReport::default();
+    let owners = clients.mongo.get_mongo_service("owners").unwrap();
+    if !fixtures.owners.is_empty() {
+        let count = fixtures.owners.len();
+        let _: Vec<ID> = owners.insert_many(fixtures.owners, None)?;
+        report.owners_inserted = count;
+    }
+    let pets = resolve_owners(clients, fixtures.pets)?;
+    if !pets.is_empty() {
+        let count = pets.len();
+        let service = clients.mongo.get_mongo_service("pets").unwrap();
+        let _: Vec<ID> = service.insert_many(pets, None)?;
+        report.pets_inserted = count;
+    }
+    Ok(report)
+}
+
+/// Inserts the fixtures or updates the documents they match, owners by username and pets by
+/// name and owner, without touching the other documents
+pub fn upsert(clients: &Clients, fixtures: Fixtures) -> Result<SeedReport, FixtureError> {
+    let mut report = SeedReport::default();
+    let owners = clients.mongo.get_mongo_service("owners").unwrap();
+    for owner in &fixtures.owners {
+        let filter = doc! { "username": &owner.username };
+        if upsert_one(owners.data_source(), filter, to_document(owner)?)? {
+            report.owners_inserted += 1;
+        } else {
+            report.owners_updated += 1;
+        }
+    }
+    let pets = clients.mongo.get_mongo_service("pets").unwrap();
+    for pet in resolve_owners(clients, fixtures.pets)? {
+        let filter = doc! {
+            "name": pet.get_str("name").unwrap_or(""),
+            "owner": pet.get("owner").cloned().unwrap_or(Bson::Null),
+        };
+        if upsert_one(pets.data_source(), filter, pet)? {
+            report.pets_inserted += 1;
+        } else {
+            report.pets_updated += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Returns whether the document was inserted, the node details are set like the services
+/// set them
+fn upsert_one(
+    collection: &mongodb::Collection,
+    filter: Document,
+    mut fields: Document,
+) -> Result<bool, FixtureError> {
+    let now = Utc::now().timestamp();
+    fields.insert("node.date_modified", now);
+    let update = doc! {
+        "$set": fields,
+        "$setOnInsert": {
+            "node.id": Uuid::new_v4().to_hyphenated().to_string(),
+            "node.date_created": now,
+        },
+    };
+    let options = UpdateOptions {
+        upsert: Some(true),
+        ..UpdateOptions::default()
+    };
+    let result = collection.update_one(filter, update, options)?;
+    Ok(result.upserted_id.is_some())
+}
+
+fn to_document(value: &impl Serialize) -> Result<Document, FixtureError> {
+    match bson::to_bson(value) {
+        Ok(Bson::Document(document)) => Ok(document),
+        Ok(_) => Err(ServiceError::ParseError("expected a document".to_owned()).into()),
+        Err(e) => Err(ServiceError::from(e).into()),
+    }
+}
+
+/// The documents of the pets with the ids of their owners, looked up by username in the
+/// database so owners of earlier seeds can be referred to
+fn resolve_owners(
+    clients: &Clients,
+    pets: Vec<(PathBuf, PetFixture)>,
+) -> Result<Vec<Document>, FixtureError> {
+    let usernames: Vec<&str> = pets
+        .iter()
+        .filter_map(|(_, pet)| pet.owner.as_deref())
+        .filter_map(|owner| owner.strip_prefix('@'))
+        .collect();
+    let mut ids: HashMap<String, Bson> = HashMap::new();
+    if !usernames.is_empty() {
+        let owners = clients.mongo.get_mongo_service("owners").unwrap();
+        let cursor = owners
+            .data_source()
+            .find(doc! { "username": { "$in": usernames } }, None)?;
+        for owner in cursor {
+            let owner = owner?;
+            if let (Ok(username), Some(id)) = (owner.get_str("username"), owner.get("_id")) {
+                ids.insert(username.to_owned(), id.clone());
+            }
+        }
+    }
+    let mut documents = Vec::new();
+    for (path, pet) in pets {
+        let owner = match &pet.owner {
+            Some(owner) => {
+                let id = owner
+                    .strip_prefix('@')
+                    .and_then(|username| ids.get(username));
+                match id {
+                    Some(id) => id.clone(),
+                    None => {
+                        return Err(FixtureError::UnknownOwner {
+                            path,
+                            pet: pet.name,
+                            owner: owner.clone(),
+                        })
+                    }
+                }
+            }
+            None => Bson::Null,
+        };
+        let mut document = to_document(&pet)?;
+        document.insert("owner", owner);
+        documents.push(document);
+    }
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A directory of its own for every test, as tests run in parallel
+    fn fixture_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fixtures_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    const OWNERS_YAML: &str = "owners:\n  \
+        - { username: jsmith, first_name: John, last_name: Smith, gender: Male }\n\
+        pets:\n  \
+        - { name: Fido, pet_type: Dog, age: 10, gender: Male, owner: \"@jsmith\" }\n";
+
+    const PETS_JSON: &str = r#"{
+        "environments": ["production"],
+        "pets": [{ "name": "Cleo", "pet_type": "Cat", "gender": "Female", "owner": "@jsmith" }]
+    }"#;
+
+    fn names(fixtures: &Fixtures) -> Vec<&str> {
+        fixtures
+            .pets
+            .iter()
+            .map(|(_, pet)| pet.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn loads_the_files_of_the_environment() {
+        let dir = fixture_dir(
+            "environment",
+            &[
+                ("a.yaml", OWNERS_YAML),
+                ("b.json", PETS_JSON),
+                ("notes.txt", "not a fixture"),
+            ],
+        );
+        let development =
+            Fixtures::load(std::slice::from_ref(&dir), Environment::Development).unwrap();
+        let production =
+            Fixtures::load(std::slice::from_ref(&dir), Environment::Production).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(development.owners.len(), 1);
+        assert_eq!(development.owners[0].username, "jsmith");
+        assert_eq!(names(&development), vec!["Fido"]);
+        assert_eq!(names(&production), vec!["Fido", "Cleo"]);
+        assert_eq!(production.pets[1].0, dir.join("b.json"));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let dir = fixture_dir(
+            "unknown_fields",
+            &[
+                ("file.yaml", "owners: []\nanimals: []\n"),
+                (
+                    "pet.json",
+                    r#"{ "pets": [{ "name": "Rex", "pet_type": "Dog", "gender": "Male", "color": "brown" }] }"#,
+                ),
+            ],
+        );
+        let file = Fixtures::load(&[dir.join("file.yaml")], Environment::Development);
+        let pet = Fixtures::load(&[dir.join("pet.json")], Environment::Development);
+        fs::remove_dir_all(&dir).unwrap();
+
+        match file {
+            Err(FixtureError::Parse { path, message }) => {
+                assert_eq!(path, dir.join("file.yaml"));
+                assert!(message.contains("animals"), "{}", message);
+            }
+            _ => panic!("expected a parse error"),
+        }
+        match pet {
+            Err(FixtureError::Parse { message, .. }) => {
+                assert!(message.contains("color"), "{}", message)
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn a_missing_file_is_a_read_error() {
+        let path = env::temp_dir().join(format!("fixtures_missing_{}.yaml", std::process::id()));
+        let result = Fixtures::load(&[path], Environment::Development);
+        assert!(matches!(result, Err(FixtureError::Read { .. })));
+    }
+
+    #[test]
+    fn checks_the_owners_are_fixtures() {
+        let dir = fixture_dir(
+            "owners",
+            &[
+                ("a.yaml", OWNERS_YAML),
+                (
+                    "b.yaml",
+                    "pets:\n  - { name: Rex, pet_type: Dog, gender: Male, owner: jsmith }\n",
+                ),
+                (
+                    "c.yaml",
+                    "pets:\n  - { name: Tom, pet_type: Cat, gender: Male, owner: \"@nobody\" }\n",
+                ),
+                (
+                    "d.yaml",
+                    "pets:\n  - { name: Stray, pet_type: Cat, gender: Female }\n",
+                ),
+            ],
+        );
+        let load = |files: &[&str]| {
+            let paths: Vec<PathBuf> = files.iter().map(|file| dir.join(file)).collect();
+            Fixtures::load(&paths, Environment::Development).unwrap()
+        };
+        let valid = load(&["a.yaml", "d.yaml"]);
+        let without_at = load(&["a.yaml", "b.yaml"]);
+        let unknown = load(&["a.yaml", "c.yaml"]);
+        let elsewhere = load(&["d.yaml", "a.yaml"]);
+        let orphan = load(&["b.yaml"]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(valid.check_owners().is_ok());
+        assert!(elsewhere.check_owners().is_ok());
+        match without_at.check_owners() {
+            Err(FixtureError::UnknownOwner { path, pet, owner }) => {
+                assert_eq!(path, dir.join("b.yaml"));
+                assert_eq!(pet, "Rex");
+                assert_eq!(owner, "jsmith");
+            }
+            _ => panic!("expected an unknown owner"),
+        }
+        assert!(matches!(
+            unknown.check_owners(),
+            Err(FixtureError::UnknownOwner { ref owner, .. }) if owner == "@nobody"
+        ));
+        assert!(orphan.check_owners().is_err());
+    }
+}
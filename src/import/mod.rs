@@ -0,0 +1,616 @@
+// Bulk import of pets and owners, shared by the import routes and the import binary
+
+use crate::db::Clients;
+use crate::models::common::{parse_id, Gender};
+use crate::models::{NewOwner, NewPet, PetTypes};
+
+use bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb_base_service::{BaseService, ServiceError, ID};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Rows inserted with a single `insert_many`
+const BATCH_SIZE: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ImportFormat::Csv),
+            "ndjson" => Ok(ImportFormat::Ndjson),
+            other => Err(format!(
+                "Unknown import format {}, expected csv or ndjson",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collection {
+    Pets,
+    Owners,
+}
+
+impl FromStr for Collection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pets" => Ok(Collection::Pets),
+            "owners" => Ok(Collection::Owners),
+            other => Err(format!(
+                "Unknown collection {}, expected pets or owners",
+                other
+            )),
+        }
+    }
+}
+
+/// The outcome of an import, rows are numbered by their line in the file
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct ImportReport {
+    /// Nothing was inserted, the rows were only validated
+    pub dry_run: bool,
+    /// Number of rows read
+    pub total: usize,
+    /// Number of rows that passed validation
+    pub valid: usize,
+    /// Number of rows inserted
+    pub inserted: usize,
+    pub errors: Vec<RowError>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RowError {
+    pub row: usize,
+    pub message: String,
+}
+
+/// Errors that stop the whole import, errors of single rows are in the report instead
+#[derive(Debug)]
+pub enum ImportError {
+    InvalidFile(String),
+    Service(ServiceError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::InvalidFile(message) => write!(f, "Invalid file: {}", message),
+            ImportError::Service(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ServiceError> for ImportError {
+    fn from(e: ServiceError) -> ImportError {
+        ImportError::Service(e)
+    }
+}
+
+impl From<mongodb::error::Error> for ImportError {
+    fn from(e: mongodb::error::Error) -> ImportError {
+        ImportError::Service(ServiceError::from(e))
+    }
+}
+
+/// A pet row, the owner is either the id in `owner` or the username in `owner_username`
+/// so the csv of the pets export with `include_owner` can be imported as is
+#[derive(Deserialize)]
+struct PetRow {
+    name: String,
+    pet_type: PetTypes,
+    age: Option<i32>,
+    gender: Gender,
+    owner: Option<String>,
+    owner_username: Option<String>,
+}
+
+/// Parses, validates and, unless `dry_run`, inserts the rows of `input`
+pub fn import(
+    clients: &Clients,
+    collection: Collection,
+    format: ImportFormat,
+    input: &[u8],
+    dry_run: bool,
+) -> Result<ImportReport, ImportError> {
+    import_with(
+        collection,
+        format,
+        input,
+        dry_run,
+        |ids, usernames| find_existing(clients, ids, usernames),
+        |documents, report| insert(clients, collection, documents, report),
+    )
+}
+
+/// `import` with the lookup of the existing owners and the insert passed in
+fn import_with(
+    collection: Collection,
+    format: ImportFormat,
+    input: &[u8],
+    dry_run: bool,
+    find_existing: impl FnOnce(Vec<Bson>, Vec<Bson>) -> Result<Existing, ImportError>,
+    insert: impl FnOnce(Vec<(usize, Document)>, &mut ImportReport),
+) -> Result<ImportReport, ImportError> {
+    let mut report = ImportReport {
+        dry_run,
+        ..ImportReport::default()
+    };
+    let documents = validate(collection, format, input, &mut report, find_existing)?;
+    if !dry_run {
+        insert(documents, &mut report);
+    }
+    report.errors.sort_by_key(|e| e.row);
+    Ok(report)
+}
+
+/// The owners the rows of a file refer to, or whose usernames they take, that are already
+/// in the database
+#[derive(Default)]
+struct Existing {
+    owner_ids: HashSet<ID>,
+    owners_by_username: HashMap<String, ID>,
+}
+
+/// Parses and validates the rows of `input`, `find_existing` is given the owner ids and the
+/// usernames of the valid rows to look them up at once
+fn validate(
+    collection: Collection,
+    format: ImportFormat,
+    input: &[u8],
+    report: &mut ImportReport,
+    find_existing: impl FnOnce(Vec<Bson>, Vec<Bson>) -> Result<Existing, ImportError>,
+) -> Result<Vec<(usize, Document)>, ImportError> {
+    let documents = match collection {
+        Collection::Pets => {
+            let rows = parsed_rows(read_rows::<PetRow>(format, input)?, report);
+            let ids = rows
+                .iter()
+                .filter_map(|(_, pet)| pet.owner.as_ref())
+                .map(|owner| parse_id(owner).to_bson())
+                .collect();
+            let usernames = rows
+                .iter()
+                .filter_map(|(_, pet)| pet.owner_username.clone())
+                .map(Bson::from)
+                .collect();
+            let existing = find_existing(ids, usernames)?;
+            validate_pets(rows, &existing, report)?
+        }
+        Collection::Owners => {
+            let rows = parsed_rows(read_rows::<NewOwner>(format, input)?, report);
+            let usernames = rows
+                .iter()
+                .map(|(_, owner)| Bson::from(owner.username.clone()))
+                .collect();
+            let existing = find_existing(Vec::new(), usernames)?;
+            validate_owners(rows, existing, report)?
+        }
+    };
+    report.valid = documents.len();
+    Ok(documents)
+}
+
+type Rows<T> = Vec<(usize, Result<T, String>)>;
+
+fn read_rows<T: DeserializeOwned>(
+    format: ImportFormat,
+    input: &[u8],
+) -> Result<Rows<T>, ImportError> {
+    match format {
+        ImportFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input);
+            let headers = reader
+                .headers()
+                .map_err(|e| ImportError::InvalidFile(e.to_string()))?
+                .clone();
+            let mut rows = Vec::new();
+            for (index, record) in reader.records().enumerate() {
+                let row = match record {
+                    Ok(record) => {
+                        let line = record.position().map(|p| p.line() as usize);
+                        let item = record
+                            .deserialize::<T>(Some(&headers))
+                            .map_err(|e| csv_error_message(&e));
+                        (line.unwrap_or(index + 2), item)
+                    }
+                    Err(e) => {
+                        let line = e.position().map(|p| p.line() as usize);
+                        (line.unwrap_or(index + 2), Err(csv_error_message(&e)))
+                    }
+                };
+                rows.push(row);
+            }
+            Ok(rows)
+        }
+        ImportFormat::Ndjson => Ok(input
+            .split(|b| *b == b'\n')
+            .enumerate()
+            .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|(index, line)| {
+                let item = serde_json::from_slice::<T>(line).map_err(|e| e.to_string());
+                (index + 1, item)
+            })
+            .collect()),
+    }
+}
+
+/// The message of a csv error without the position, the row is already in the report
+fn csv_error_message(e: &csv::Error) -> String {
+    match e.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => match err.field() {
+            Some(field) => format!("column {}: {}", field + 1, err.kind()),
+            None => err.kind().to_string(),
+        },
+        _ => e.to_string(),
+    }
+}
+
+/// The rows that could be parsed, the others are reported
+fn parsed_rows<T>(rows: Rows<T>, report: &mut ImportReport) -> Vec<(usize, T)> {
+    report.total = rows.len();
+    rows.into_iter()
+        .filter_map(|(row, item)| match item {
+            Ok(item) => Some((row, item)),
+            Err(message) => {
+                report.errors.push(RowError { row, message });
+                None
+            }
+        })
+        .collect()
+}
+
+/// Looks up every owner the rows refer to by id or username at once
+fn find_existing(
+    clients: &Clients,
+    ids: Vec<Bson>,
+    usernames: Vec<Bson>,
+) -> Result<Existing, ImportError> {
+    let mut existing = Existing::default();
+    if ids.is_empty() && usernames.is_empty() {
+        return Ok(existing);
+    }
+    let service = clients.mongo.get_mongo_service("owners").unwrap();
+    let filter = doc! { "$or": [
+        { "_id": { "$in": ids } },
+        { "username": { "$in": usernames } },
+    ] };
+    for owner in service.data_source().find(Some(filter), None)? {
+        let owner = owner?;
+        let id = match owner.get("_id") {
+            Some(id) => ID::with_bson(id),
+            None => continue,
+        };
+        if let Ok(username) = owner.get_str("username") {
+            existing
+                .owners_by_username
+                .insert(username.to_owned(), id.clone());
+        }
+        existing.owner_ids.insert(id);
+    }
+    Ok(existing)
+}
+
+fn validate_pets(
+    rows: Vec<(usize, PetRow)>,
+    existing: &Existing,
+    report: &mut ImportReport,
+) -> Result<Vec<(usize, Document)>, ImportError> {
+    let mut documents = Vec::new();
+    for (row, pet) in rows {
+        let owner = match (&pet.owner_username, &pet.owner) {
+            (Some(username), _) => match existing.owners_by_username.get(username) {
+                Some(id) => Some(id.clone()),
+                None => {
+                    report.errors.push(RowError {
+                        row,
+                        message: format!("Unable to find owner with username {}", username),
+                    });
+                    continue;
+                }
+            },
+            (None, Some(owner)) => {
+                let id = parse_id(owner);
+                if !existing.owner_ids.contains(&id) {
+                    report.errors.push(RowError {
+                        row,
+                        message: format!("Unable to find owner {}", owner),
+                    });
+                    continue;
+                }
+                Some(id)
+            }
+            (None, None) => None,
+        };
+        if pet.name.is_empty() {
+            report.errors.push(RowError {
+                row,
+                message: "name can't be empty".to_owned(),
+            });
+            continue;
+        }
+        let new_pet = NewPet {
+            name: pet.name,
+            pet_type: pet.pet_type,
+            age: pet.age,
+            gender: pet.gender,
+            owner,
+        };
+        documents.push((row, to_document(&new_pet)?));
+    }
+    Ok(documents)
+}
+
+/// Usernames are unique, both in the database and in the file
+fn validate_owners(
+    rows: Vec<(usize, NewOwner)>,
+    existing: Existing,
+    report: &mut ImportReport,
+) -> Result<Vec<(usize, Document)>, ImportError> {
+    let mut taken: HashSet<String> = existing.owners_by_username.into_keys().collect();
+    let mut documents = Vec::new();
+    for (row, owner) in rows {
+        let message = if owner.username.is_empty() {
+            "username can't be empty".to_owned()
+        } else if !taken.insert(owner.username.clone()) {
+            format!("The username {} is already taken", owner.username)
+        } else {
+            documents.push((row, to_document(&owner)?));
+            continue;
+        };
+        report.errors.push(RowError { row, message });
+    }
+    Ok(documents)
+}
+
+fn to_document<T: Serialize>(item: &T) -> Result<Document, ImportError> {
+    match bson::to_bson(item).map_err(ServiceError::from)? {
+        Bson::Document(document) => Ok(document),
+        _ => Err(ImportError::InvalidFile("row is not a document".to_owned())),
+    }
+}
+
+/// Inserts the documents in batches, a failed insert is reported on its row and the
+/// rest of the batch is still inserted
+fn insert(
+    clients: &Clients,
+    collection: Collection,
+    documents: Vec<(usize, Document)>,
+    report: &mut ImportReport,
+) {
+    let name = match collection {
+        Collection::Pets => "pets",
+        Collection::Owners => "owners",
+    };
+    let service = clients.mongo.get_mongo_service(name).unwrap();
+    for batch in documents.chunks(BATCH_SIZE) {
+        let items: Vec<Document> = batch.iter().map(|(_, doc)| doc.clone()).collect();
+        match service.insert_many(items, None) {
+            Ok(ids) => report.inserted += ids.len(),
+            Err(e) => match bulk_write_errors(&e) {
+                Some(errors) => {
+                    report.inserted += batch.len() - errors.len();
+                    for (index, message) in errors {
+                        report.errors.push(RowError {
+                            row: batch[index].0,
+                            message,
+                        });
+                    }
+                }
+                None => {
+                    let message = e.to_string();
+                    report.errors.extend(batch.iter().map(|(row, _)| RowError {
+                        row: *row,
+                        message: message.clone(),
+                    }));
+                }
+            },
+        }
+    }
+    clients.cache.invalidate_collection(name);
+}
+
+/// The index in the batch and the message of every document `insert_many` failed on
+fn bulk_write_errors(e: &ServiceError) -> Option<Vec<(usize, String)>> {
+    let kind = match e {
+        ServiceError::MongoError(e) => &e.kind,
+        _ => return None,
+    };
+    match kind.as_ref() {
+        ErrorKind::BulkWriteError(failure) => Some(
+            failure
+                .write_errors
+                .iter()
+                .flatten()
+                .map(|e| (e.index, e.message.clone()))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+
+    const OWNER: &str = "5e9f8f8f8f8f8f8f8f8f8f8f";
+
+    fn existing() -> Existing {
+        let id = parse_id(OWNER);
+        let mut existing = Existing::default();
+        existing.owner_ids.insert(id.clone());
+        existing.owners_by_username.insert("jdoe".to_owned(), id);
+        existing
+    }
+
+    fn run(
+        collection: Collection,
+        format: ImportFormat,
+        input: &str,
+        dry_run: bool,
+    ) -> ImportReport {
+        import_with(
+            collection,
+            format,
+            input.as_bytes(),
+            dry_run,
+            |_, _| Ok(existing()),
+            |documents, report| report.inserted += documents.len(),
+        )
+        .unwrap()
+    }
+
+    fn errors(report: &ImportReport) -> Vec<(usize, &str)> {
+        report
+            .errors
+            .iter()
+            .map(|e| (e.row, e.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_bad_csv_rows_by_line() {
+        let input = "name,pet_type,age,gender\n\
+                     Rex,Dog,3,Male\n\
+                     Tom,Dragon,2,Male\n\
+                     Kit,Cat,old,Female\n\
+                     ,Cat,1,Other\n";
+        let report = run(Collection::Pets, ImportFormat::Csv, input, false);
+        assert_eq!(report.total, 4);
+        assert_eq!(report.valid, 1);
+        assert_eq!(report.inserted, 1);
+        let errors = errors(&report);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].0, 3);
+        assert!(errors[0].1.contains("Dragon"), "{}", errors[0].1);
+        assert_eq!(errors[1].0, 4);
+        assert!(errors[1].1.starts_with("column 3: "), "{}", errors[1].1);
+        assert_eq!(errors[2], (5, "name can't be empty"));
+    }
+
+    #[test]
+    fn reports_bad_ndjson_rows_by_line() {
+        let input = "{\"name\":\"Rex\",\"pet_type\":\"Dog\",\"gender\":\"Male\"}\n\
+                     \n\
+                     {\"name\":\"Tom\"\n\
+                     {\"name\":\"Kit\",\"pet_type\":\"Cat\",\"gender\":\"Female\"}\n";
+        let report = run(Collection::Pets, ImportFormat::Ndjson, input, false);
+        assert_eq!(report.total, 3);
+        assert_eq!(report.valid, 2);
+        let errors = errors(&report);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 3);
+    }
+
+    #[test]
+    fn reports_unknown_owners() {
+        let unknown = ObjectId::new().unwrap().to_hex();
+        let input = format!(
+            "name,pet_type,gender,owner,owner_username\n\
+             Rex,Dog,Male,{},\n\
+             Tom,Cat,Male,$oid:{},\n\
+             Kit,Cat,Female,{},\n\
+             Bo,Dog,Male,,jdoe\n\
+             Fin,Fish,Other,,nobody\n",
+            OWNER, OWNER, unknown
+        );
+        let report = run(Collection::Pets, ImportFormat::Csv, &input, false);
+        assert_eq!(report.total, 5);
+        assert_eq!(report.valid, 3);
+        let not_found = format!("Unable to find owner {}", unknown);
+        assert_eq!(
+            errors(&report),
+            vec![
+                (4, not_found.as_str()),
+                (6, "Unable to find owner with username nobody"),
+            ]
+        );
+    }
+
+    #[test]
+    fn looks_up_the_owners_of_the_parsed_rows() {
+        let input = format!(
+            "name,pet_type,gender,owner,owner_username\n\
+             Rex,Dog,Male,{},\n\
+             Bo,Dog,Male,,jdoe\n\
+             Tom,Dragon,Male,,ghost\n",
+            OWNER
+        );
+        let mut report = ImportReport::default();
+        validate(
+            Collection::Pets,
+            ImportFormat::Csv,
+            input.as_bytes(),
+            &mut report,
+            |ids, usernames| {
+                assert_eq!(ids, vec![parse_id(OWNER).to_bson()]);
+                assert_eq!(usernames, vec![Bson::from("jdoe")]);
+                Ok(existing())
+            },
+        )
+        .unwrap();
+        assert_eq!(report.valid, 2);
+    }
+
+    #[test]
+    fn rejects_taken_and_duplicate_usernames() {
+        let input = "username,first_name,last_name,gender\n\
+                     jdoe,John,Doe,Male\n\
+                     asmith,Anna,Smith,Female\n\
+                     asmith,Adam,Smith,Male\n\
+                     ,No,Name,Other\n";
+        let report = run(Collection::Owners, ImportFormat::Csv, input, false);
+        assert_eq!(report.total, 4);
+        assert_eq!(report.valid, 1);
+        assert_eq!(report.inserted, 1);
+        assert_eq!(
+            errors(&report),
+            vec![
+                (2, "The username jdoe is already taken"),
+                (4, "The username asmith is already taken"),
+                (5, "username can't be empty"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dry_run_counts_without_inserting() {
+        let input = "name,pet_type,gender\nRex,Dog,Male\nTom,Dragon,Male\n";
+        let report = run(Collection::Pets, ImportFormat::Csv, input, true);
+        assert!(report.dry_run);
+        assert_eq!(report.total, 2);
+        assert_eq!(report.valid, 1);
+        assert_eq!(report.inserted, 0);
+        assert_eq!(report.errors.len(), 1);
+    }
+
+    #[test]
+    fn a_file_without_a_readable_header_is_invalid() {
+        let result = import_with(
+            Collection::Pets,
+            ImportFormat::Csv,
+            b"\xff\xfe,\n",
+            true,
+            |_, _| Ok(Existing::default()),
+            |_, _| {},
+        );
+        assert!(matches!(result, Err(ImportError::InvalidFile(_))));
+    }
+}
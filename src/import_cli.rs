@@ -0,0 +1,124 @@
+#[allow(dead_code)]
+mod cache;
+// only the database and cache settings are used here
+#[allow(dead_code)]
+mod config;
+mod db;
+#[allow(dead_code)]
+mod environment;
+mod import;
+mod logging;
+// only the database and cache metrics are recorded here and never served
+#[allow(dead_code)]
+mod metrics;
+#[allow(dead_code)]
+mod models;
+// spans are only exported by the server
+#[allow(dead_code)]
+mod telemetry;
+
+use std::io::Read;
+use std::process;
+use std::sync::Arc;
+
+use crate::cache::QueryCache;
+use crate::config::Config;
+use crate::db::Clients;
+use crate::import::{Collection, ImportFormat};
+
+const USAGE: &str = "usage: import <pets|owners> <file|-> [--format csv|ndjson] [--dry-run]";
+
+struct Args {
+    collection: Collection,
+    path: String,
+    format: ImportFormat,
+    dry_run: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut format = None;
+    let mut dry_run = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--format" => {
+                let value = args.next().ok_or("--format needs a value")?;
+                format = Some(value.parse()?);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        return Err(USAGE.to_owned());
+    }
+    let path = positional.pop().unwrap();
+    let collection = positional.pop().unwrap().parse()?;
+    // the format defaults to the extension of the file
+    let format = format.unwrap_or_else(|| {
+        if path.ends_with(".ndjson") || path.ends_with(".jsonl") {
+            ImportFormat::Ndjson
+        } else {
+            ImportFormat::Csv
+        }
+    });
+    Ok(Args {
+        collection,
+        path,
+        format,
+        dry_run,
+    })
+}
+
+fn read_input(path: &str) -> std::io::Result<Vec<u8>> {
+    let mut input = Vec::new();
+    if path == "-" {
+        std::io::stdin().read_to_end(&mut input)?;
+    } else {
+        std::fs::File::open(path)?.read_to_end(&mut input)?;
+    }
+    Ok(input)
+}
+
+fn main() {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    logging::init(&config.log, config.log_format);
+
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let input = read_input(&args.path).unwrap_or_else(|e| {
+        eprintln!("Unable to read {}: {}", args.path, e);
+        process::exit(1);
+    });
+
+    let (database, mongo) = db::mongo::connect(&config.mongo, &config.slow_queries);
+    // the unique indexes reject rows that duplicate existing documents
+    db::indexes::sync_on_startup(&database, &models::indexes(), config.mongo.indexes);
+    let db_clients = Clients {
+        database,
+        mongo,
+        cache: Arc::new(QueryCache::from_config(&config.cache)),
+        request_id: None,
+    };
+    let report = import::import(
+        &db_clients,
+        args.collection,
+        args.format,
+        &input,
+        args.dry_run,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    if !report.errors.is_empty() {
+        process::exit(1);
+    }
+}
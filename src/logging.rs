@@ -0,0 +1,239 @@
+// Log records as json lines for the log pipeline, or as text, with the id of the request
+// they're logged for and the fields of events such as the access log
+
+use crate::config::LogFormat;
+
+use chrono::{SecondsFormat, Utc};
+use env_logger::fmt::Formatter;
+use env_logger::Builder;
+use log::{Level, Record};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::time::Duration;
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    // the fields of the event being logged, the formatter runs on the thread that logs it
+    static FIELDS: RefCell<Option<Map<String, Value>>> = const { RefCell::new(None) };
+}
+
+/// Logs with `filters` in `format`
+pub fn init(filters: &str, format: LogFormat) {
+    let mut builder = Builder::new();
+    builder.parse_filters(filters);
+    match format {
+        LogFormat::Json => builder.format(format_json),
+        LogFormat::Text => builder.format(format_text),
+    };
+    builder.init();
+}
+
+/// Logs a record with fields, which are keys of the json object or `key=value` pairs
+/// after the message of the text format
+pub fn event(level: Level, target: &str, message: &str, fields: Map<String, Value>) {
+    FIELDS.with(|current| *current.borrow_mut() = Some(fields));
+    log::logger().log(
+        &Record::builder()
+            .level(level)
+            .target(target)
+            .args(format_args!("{}", message))
+            .build(),
+    );
+    FIELDS.with(|current| current.borrow_mut().take());
+}
+
+/// Milliseconds with microsecond precision
+pub fn duration_ms(duration: Duration) -> Value {
+    let micros = duration.as_micros() as f64;
+    Value::from(micros / 1000.0)
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    writeln!(buf, "{}", json_line(record))
+}
+
+/// The json object of a record, with the id of the request and the fields of the event
+fn json_line(record: &Record) -> Value {
+    let mut entry = Map::new();
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    entry.insert("timestamp".to_owned(), Value::from(timestamp));
+    entry.insert("level".to_owned(), Value::from(record.level().to_string()));
+    entry.insert("target".to_owned(), Value::from(record.target()));
+    entry.insert("message".to_owned(), Value::from(record.args().to_string()));
+    if let Some(request_id) = current_request_id() {
+        entry.insert("request_id".to_owned(), Value::from(request_id));
+    }
+    FIELDS.with(|fields| {
+        if let Some(fields) = &*fields.borrow() {
+            entry.extend(fields.clone());
+        }
+    });
+    Value::Object(entry)
+}
+
+fn format_text(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    write!(
+        buf,
+        "[{} {:<5} {}]",
+        buf.timestamp(),
+        record.level(),
+        record.target()
+    )?;
+    if let Some(request_id) = current_request_id() {
+        write!(buf, " request_id={}", request_id)?;
+    }
+    write!(buf, " {}", record.args())?;
+    FIELDS.with(|fields| {
+        if let Some(fields) = &*fields.borrow() {
+            for (key, value) in fields {
+                write!(buf, " {}={}", key, value)?;
+            }
+        }
+        Ok::<(), io::Error>(())
+    })?;
+    writeln!(buf)
+}
+
+/// The request the thread is working for
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// Makes `id` the request the records of the thread are logged for until dropped
+pub struct Entered {
+    previous: Option<String>,
+}
+
+pub fn enter_request(id: Option<String>) -> Entered {
+    let previous = REQUEST_ID.with(|current| current.replace(id));
+    Entered { previous }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// A logger of the tests that keeps the json lines logged on a thread while it captures
+// only used by the tests of the main binary
+#[cfg(test)]
+#[allow(dead_code)]
+pub mod capture {
+    use super::json_line;
+    use log::{LevelFilter, Log, Metadata, Record};
+    use serde_json::Value;
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    thread_local! {
+        static LINES: RefCell<Option<Vec<Value>>> = const { RefCell::new(None) };
+    }
+
+    struct CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LINES.with(|lines| {
+                if let Some(lines) = lines.borrow_mut().as_mut() {
+                    lines.push(json_line(record));
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Starts capturing the records logged on this thread
+    pub fn start() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_boxed_logger(Box::new(CaptureLogger)).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+        LINES.with(|lines| *lines.borrow_mut() = Some(Vec::new()));
+    }
+
+    /// Stops capturing and returns the json lines logged since `start`
+    pub fn finish() -> Vec<Value> {
+        LINES
+            .with(|lines| lines.borrow_mut().take())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn logs_events_as_json_lines_with_the_request_id() {
+        capture::start();
+        {
+            let _entered = enter_request(Some("abc-123".to_owned()));
+            let mut fields = Map::new();
+            fields.insert("status".to_owned(), json!(200));
+            fields.insert(
+                "duration_ms".to_owned(),
+                duration_ms(Duration::from_micros(1500)),
+            );
+            event(Level::Warn, "access", "GET /pets 200", fields);
+        }
+        log::info!(target: "outside", "no request");
+        let lines = capture::finish();
+
+        assert_eq!(lines.len(), 2);
+        let line = lines[0].as_object().unwrap();
+        let keys: Vec<&str> = line.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys.iter()
+                .copied()
+                .collect::<std::collections::BTreeSet<_>>(),
+            [
+                "duration_ms",
+                "level",
+                "message",
+                "request_id",
+                "status",
+                "target",
+                "timestamp"
+            ]
+            .iter()
+            .copied()
+            .collect()
+        );
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "access");
+        assert_eq!(line["message"], "GET /pets 200");
+        assert_eq!(line["request_id"], "abc-123");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["duration_ms"], 1.5);
+        let timestamp = line["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+        assert!(timestamp.ends_with('Z'));
+
+        // the request was left and the fields of the event aren't kept
+        assert!(lines[1].get("request_id").is_none());
+        assert!(lines[1].get("status").is_none());
+        assert_eq!(lines[1]["message"], "no request");
+    }
+
+    #[test]
+    fn entering_a_request_restores_the_previous_one() {
+        let outer = enter_request(Some("outer".to_owned()));
+        {
+            let _inner = enter_request(Some("inner".to_owned()));
+            assert_eq!(current_request_id().as_deref(), Some("inner"));
+        }
+        assert_eq!(current_request_id().as_deref(), Some("outer"));
+        drop(outer);
+        assert_eq!(current_request_id(), None);
+    }
+}
@@ -1,42 +1,96 @@
-#[macro_use]
-extern crate cached;
-
-use actix_web::middleware::{DefaultHeaders, Logger};
 use actix_web::{App, HttpServer};
-use dotenv::dotenv;
-use std::io;
+use log::{error, info, warn};
 use std::sync::Arc;
-use uuid::Uuid;
+use std::{io, process};
 
+mod cache;
+mod config;
 mod db;
+mod environment;
+mod federation;
+mod import;
+mod logging;
+mod metrics;
 mod models;
 mod routes;
 mod schema;
+// printing and diffing are only used by the schema binary
+#[allow(dead_code)]
+mod sdl;
+mod telemetry;
 
+use crate::cache::QueryCache;
+use crate::config::Config;
 use crate::db::Clients;
-use crate::routes::app_routes;
+use crate::environment::Environment;
+use crate::routes::{
+    app_routes, assign_request_id, json_config, log_requests, query_config, track_requests,
+    GraphQLOptions, HealthChecks, PersistedQueryStore,
+};
 use crate::schema::create_schema;
 
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
-    std::env::set_var("RUST_LOG", "info,actix_web=warn");
-    env_logger::init();
-    dotenv().ok();
-
-    let port = dotenv::var("PORT").unwrap_or("8080".to_owned());
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    logging::init(&config.log, config.log_format);
+    info!("starting in {} mode", config.environment);
+    telemetry::init(&config.tracing).unwrap_or_else(|e| {
+        eprintln!("Unable to export spans: {}", e);
+        process::exit(1);
+    });
 
+    let (database, mongo) = db::mongo::connect(&config.mongo, &config.slow_queries);
+    db::indexes::sync_on_startup(&database, &models::indexes(), config.mongo.indexes);
+    match db::environment::marked(&database) {
+        Ok(Some(marked))
+            if marked != Environment::Production && config.environment.is_production() =>
+        {
+            error!(
+                "The database is marked as {} in _meta, refusing to use it in production",
+                marked
+            );
+            process::exit(1);
+        }
+        Ok(_) if config.environment.is_production() => {
+            if let Err(e) = db::environment::mark_production(&database) {
+                warn!("Unable to mark the database as production: {}", e);
+            }
+        }
+        Ok(Some(marked)) if marked != config.environment => warn!(
+            "The database is marked as {} but the server runs in {} mode",
+            marked, config.environment
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Unable to read the environment of the database: {}", e),
+    }
     let db_clients = Arc::new(Clients {
-        mongo: db::mongo::connect(),
+        database,
+        mongo,
+        cache: Arc::new(QueryCache::from_config(&config.cache)),
+        request_id: None,
     });
 
     let gql = std::sync::Arc::new(create_schema());
+    let persisted_queries = Arc::new(PersistedQueryStore::from_config(&config.persisted_queries));
+    let graphql_options = Arc::new(GraphQLOptions::from_config(&config));
+    let health_checks = Arc::new(HealthChecks::start(&config.health, &config.mongo));
+    let port = config.server.port;
     // Start http server
     HttpServer::new(move || {
         App::new()
             .data(gql.clone())
             .data(db_clients.clone())
-            .wrap(DefaultHeaders::new().header("x-request-id", Uuid::new_v4().to_string()))
-            .wrap(Logger::new("IP:%a DATETIME:%t REQUEST:\"%r\" STATUS: %s DURATION:%D X-REQUEST-ID:%{x-request-id}o"))
+            .data(persisted_queries.clone())
+            .data(graphql_options.clone())
+            .data(health_checks.clone())
+            .app_data(json_config())
+            .app_data(query_config())
+            .wrap_fn(track_requests)
+            .wrap_fn(log_requests)
+            .wrap_fn(assign_request_id)
             .configure(app_routes)
     })
     .bind(format!("0.0.0.0:{}", port))?
@@ -0,0 +1,215 @@
+// Prometheus metrics shared by the routes, the schema, the database and the caches
+
+use crate::db::mongo::command_collection;
+
+use lazy_static::lazy_static;
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder,
+    HistogramTimer, HistogramVec, IntCounter, IntCounterVec, TextEncoder,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Operation names are chosen by clients, only this many distinct names get series of their own
+const MAX_OPERATION_NAMES: usize = 100;
+const MAX_OPERATION_NAME_LENGTH: usize = 64;
+/// The label of the operations past the limit or whose name isn't a GraphQL name
+const OTHER_OPERATION: &str = "other";
+
+lazy_static! {
+    static ref OPERATION_NAMES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by route and status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time to respond to HTTP requests by route and status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref GRAPHQL_OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "graphql_operations_total",
+        "GraphQL operations by operation name and type",
+        &["operation", "type"]
+    )
+    .unwrap();
+    static ref GRAPHQL_OPERATION_DURATION: HistogramVec = register_histogram_vec!(
+        "graphql_operation_duration_seconds",
+        "Time to execute GraphQL operations, including cached responses",
+        &["operation", "type"]
+    )
+    .unwrap();
+    static ref GRAPHQL_RESOLVER_DURATION: HistogramVec = register_histogram_vec!(
+        "graphql_resolver_duration_seconds",
+        "Time spent in the resolvers of GraphQL fields",
+        &["type", "field"]
+    )
+    .unwrap();
+    static ref MONGODB_OPERATION_DURATION: HistogramVec = register_histogram_vec!(
+        "mongodb_operation_duration_seconds",
+        "Latency of MongoDB commands by collection",
+        &["collection", "command", "status"]
+    )
+    .unwrap();
+    static ref SPANS_DROPPED: IntCounter = register_int_counter!(
+        "tracing_spans_dropped_total",
+        "Spans dropped because the queue of the exporter was full"
+    )
+    .unwrap();
+    static ref CACHE_HITS: IntCounterVec = register_int_counter_vec!(
+        "cache_hits_total",
+        "Lookups that found a cached result, by cache or cached query",
+        &["cache"]
+    )
+    .unwrap();
+    static ref CACHE_MISSES: IntCounterVec = register_int_counter_vec!(
+        "cache_misses_total",
+        "Lookups that didn't find a cached result, by cache or cached query",
+        &["cache"]
+    )
+    .unwrap();
+}
+
+/// The metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// `route` is the pattern of the route, e.g. `/pets/{id}`, so ids don't add labels
+pub fn observe_request(method: &str, route: &str, status: u16, duration: Duration) {
+    let status = status.to_string();
+    let labels = [method, route, status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_graphql_operation(operation: &str, operation_type: &str, duration: Duration) {
+    let operation = operation_label(&mut OPERATION_NAMES.lock().unwrap(), operation);
+    let labels = [operation, operation_type];
+    GRAPHQL_OPERATIONS.with_label_values(&labels).inc();
+    GRAPHQL_OPERATION_DURATION
+        .with_label_values(&labels)
+        .observe(duration.as_secs_f64());
+}
+
+/// The first names seen are kept as they are, the others are counted as `other`
+fn operation_label<'a>(seen: &mut HashSet<String>, operation: &'a str) -> &'a str {
+    let is_name = operation.len() <= MAX_OPERATION_NAME_LENGTH
+        && !operation.starts_with(|c: char| c.is_ascii_digit())
+        && operation
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_name || operation.is_empty() {
+        return OTHER_OPERATION;
+    }
+    if seen.contains(operation) {
+        return operation;
+    }
+    if seen.len() < MAX_OPERATION_NAMES {
+        seen.insert(operation.to_owned());
+        operation
+    } else {
+        OTHER_OPERATION
+    }
+}
+
+pub fn count_dropped_span() {
+    SPANS_DROPPED.inc();
+}
+
+/// Times a resolver until the returned timer is dropped
+pub fn time_resolver(type_name: &str, field: &str) -> HistogramTimer {
+    GRAPHQL_RESOLVER_DURATION
+        .with_label_values(&[type_name, field])
+        .start_timer()
+}
+
+pub fn cache_hit(cache: &str) {
+    CACHE_HITS.with_label_values(&[cache]).inc();
+}
+
+pub fn cache_miss(cache: &str) {
+    CACHE_MISSES.with_label_values(&[cache]).inc();
+}
+
+/// Records the latency of every command the MongoDB client sends
+///
+/// Only the started event has the command and its collection, so the collection is kept
+/// by request id until the command succeeds or fails.
+#[derive(Default)]
+pub struct MongoCommandMetrics {
+    collections: Mutex<HashMap<i32, String>>,
+}
+
+impl MongoCommandMetrics {
+    fn observe(&self, request_id: i32, command: &str, status: &str, duration: Duration) {
+        let collection = self.collections.lock().unwrap().remove(&request_id);
+        MONGODB_OPERATION_DURATION
+            .with_label_values(&[collection.as_deref().unwrap_or(""), command, status])
+            .observe(duration.as_secs_f64());
+    }
+}
+
+impl CommandEventHandler for MongoCommandMetrics {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        let collection = command_collection(&event);
+        self.collections
+            .lock()
+            .unwrap()
+            .insert(event.request_id, collection.to_owned());
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.observe(event.request_id, &event.command_name, "ok", event.duration);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.observe(
+            event.request_id,
+            &event.command_name,
+            "error",
+            event.duration,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_operations_by_name_up_to_a_limit() {
+        let mut seen = HashSet::new();
+        assert_eq!(operation_label(&mut seen, "allPets"), "allPets");
+        for i in 1..MAX_OPERATION_NAMES {
+            assert_eq!(
+                operation_label(&mut seen, &format!("op{}", i)),
+                format!("op{}", i)
+            );
+        }
+        assert_eq!(operation_label(&mut seen, "oneTooMany"), OTHER_OPERATION);
+        assert_eq!(operation_label(&mut seen, "allPets"), "allPets");
+    }
+
+    #[test]
+    fn labels_invalid_names_as_other() {
+        let mut seen = HashSet::new();
+        let long = "a".repeat(MAX_OPERATION_NAME_LENGTH + 1);
+        for name in &["", "1st", "all pets", "allPets{}", long.as_str()] {
+            assert_eq!(operation_label(&mut seen, name), OTHER_OPERATION);
+        }
+        assert!(seen.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+// only the database settings and the query cache of the clients are used here
+#[allow(dead_code)]
+mod cache;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod db;
+#[allow(dead_code)]
+mod environment;
+mod logging;
+// only the database metrics are recorded here and never served
+#[allow(dead_code)]
+mod metrics;
+mod migrations;
+// only the indexes of the models are used here
+#[allow(dead_code)]
+mod models;
+// spans are only exported by the server
+#[allow(dead_code)]
+mod telemetry;
+
+use std::process;
+
+use crate::config::Config;
+use crate::db::indexes::{self, ReconcileOptions};
+use crate::migrations::{Migration, MigrationError, Migrator, State, MIGRATIONS};
+
+const USAGE: &str = "usage: migrate <status|up|down|unlock|indexes> [--to <version>] [--dry-run] [--drop-undeclared]";
+
+#[derive(PartialEq)]
+enum Command {
+    Status,
+    Up,
+    Down,
+    Unlock,
+    Indexes,
+}
+
+struct Args {
+    command: Command,
+    target: Option<u32>,
+    dry_run: bool,
+    drop_undeclared: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut command = None;
+    let mut target = None;
+    let mut dry_run = false;
+    let mut drop_undeclared = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--drop-undeclared" => drop_undeclared = true,
+            "--to" => {
+                let value = args.next().ok_or("--to needs a version")?;
+                let version = value
+                    .parse()
+                    .map_err(|_| format!("Invalid version {}", value))?;
+                target = Some(version);
+            }
+            "status" if command.is_none() => command = Some(Command::Status),
+            "up" if command.is_none() => command = Some(Command::Up),
+            "down" if command.is_none() => command = Some(Command::Down),
+            "unlock" if command.is_none() => command = Some(Command::Unlock),
+            "indexes" if command.is_none() => command = Some(Command::Indexes),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let command = command.ok_or(USAGE)?;
+    if target.is_some() && command != Command::Up && command != Command::Down {
+        return Err("--to only applies to up and down".to_owned());
+    }
+    if drop_undeclared && command != Command::Indexes {
+        return Err("--drop-undeclared only applies to indexes".to_owned());
+    }
+    Ok(Args {
+        command,
+        target,
+        dry_run,
+        drop_undeclared,
+    })
+}
+
+fn print_status(migrator: &Migrator) -> Result<(), MigrationError> {
+    let status = migrator.status()?;
+    if status.is_empty() {
+        println!("No migrations");
+    }
+    for migration in status {
+        let state = match migration.state {
+            State::Pending => "pending".to_owned(),
+            State::Applied(at) => format!("applied {}", at.to_rfc3339()),
+            State::Changed(at) => format!("applied {}, changed since", at.to_rfc3339()),
+            State::Unknown(at) => format!("applied {}, unknown", at.to_rfc3339()),
+        };
+        println!("{:>5} {:<40} {}", migration.version, migration.name, state);
+    }
+    Ok(())
+}
+
+/// Creates the missing indexes and those that changed, or only reports them with
+/// `--dry-run`, returns false when drift is left
+fn sync_indexes(migrator: &Migrator, args: &Args) -> Result<bool, MigrationError> {
+    let options = ReconcileOptions {
+        create_missing: !args.dry_run,
+        recreate_changed: !args.dry_run,
+        drop_undeclared: args.drop_undeclared && !args.dry_run,
+    };
+    let drift = indexes::reconcile(migrator.database(), &models::indexes(), options)?;
+    if drift.is_empty() {
+        println!("Indexes are up to date");
+    }
+    for drift in &drift {
+        println!("{}", drift);
+    }
+    Ok(drift.iter().all(|drift| drift.fixed))
+}
+
+fn print_plan(action: &str, plan: &[&Migration]) {
+    for migration in plan {
+        println!("Would {} {} {}", action, migration.version, migration.name);
+    }
+}
+
+/// Identifies the instance holding the lock
+fn lock_holder() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_owned());
+    format!("{} (pid {})", host, process::id())
+}
+
+/// Returns false when index drift is left, so the caller exits non-zero once the lock is
+/// released
+fn run(migrator: &Migrator, args: &Args) -> Result<bool, MigrationError> {
+    match args.command {
+        Command::Status => return print_status(migrator).map(|_| true),
+        Command::Unlock => {
+            if migrator.unlock()? {
+                println!("Removed the lock");
+            } else {
+                println!("Migrations weren't locked");
+            }
+            return Ok(true);
+        }
+        // a dry run only reads the indexes
+        Command::Indexes if args.dry_run => return sync_indexes(migrator, args),
+        Command::Indexes => {
+            // indexes are built under the lock like migrations, which may depend on them
+            let _lock = migrator.lock(&lock_holder())?;
+            return sync_indexes(migrator, args);
+        }
+        Command::Up | Command::Down => {}
+    }
+    if args.dry_run {
+        match args.command {
+            Command::Up => print_plan("apply", &migrator.plan_up(args.target)?),
+            _ => print_plan("undo", &migrator.plan_down(args.target)?),
+        }
+        return Ok(true);
+    }
+    // planned once the lock is held, so it's what another instance left
+    let lock = migrator.lock(&lock_holder())?;
+    let result = if args.command == Command::Up {
+        let plan = migrator.plan_up(args.target)?;
+        if plan.is_empty() {
+            println!("Nothing to apply");
+        }
+        migrator.up(&lock, &plan, |migration, duration| {
+            println!(
+                "Applied {} {} in {}ms",
+                migration.version,
+                migration.name,
+                duration.as_millis()
+            )
+        })
+    } else {
+        let plan = migrator.plan_down(args.target)?;
+        if plan.is_empty() {
+            println!("Nothing to undo");
+        }
+        migrator.down(&lock, &plan, |migration, duration| {
+            println!(
+                "Undid {} {} in {}ms",
+                migration.version,
+                migration.name,
+                duration.as_millis()
+            )
+        })
+    };
+    result.map(|_| true)
+}
+
+fn main() {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    logging::init(&config.log, config.log_format);
+
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let (database, _) = db::mongo::connect(&config.mongo, &config.slow_queries);
+    let result = Migrator::new(&database, MIGRATIONS).and_then(|migrator| run(&migrator, &args));
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,676 @@
+// Versioned changes to the documents of the database, written in Rust and applied in order
+// by the migrate binary
+//
+// A migration is a module of this directory with an `up` function, and a `down` function
+// that undoes it when it can be undone, listed in `MIGRATIONS`. Applied migrations are
+// recorded in `_migrations` with the checksum of their source, so a migration that's edited
+// after it was applied is reported instead of leaving databases silently out of sync.
+
+mod v0001_rename_pet_kind;
+
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Utc};
+use log::warn;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::FindOptions;
+use mongodb::Database;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const MIGRATIONS_COLLECTION: &str = "_migrations";
+const LOCK_COLLECTION: &str = "_migrations_lock";
+/// The id of the lock document, there's a single lock for the database
+const LOCK_ID: &str = "migrate";
+/// How long a lock is held without being renewed, it's renewed after every migration and an
+/// instance may take over a lock that expired
+const LOCK_LEASE_MINUTES: i64 = 60;
+/// The code of the write error of a duplicate `_id`
+const DUPLICATE_KEY: i32 = 11000;
+
+pub type Step = fn(&Database) -> Result<(), mongodb::error::Error>;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    /// The source of the migration's module, `include_str!("v0001_name.rs")`
+    pub source: &'static str,
+    pub up: Step,
+    /// `None` when the migration can't be undone
+    pub down: Option<Step>,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.source.as_bytes()))
+    }
+}
+
+/// Every migration by version
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    name: "rename_pet_kind",
+    source: include_str!("v0001_rename_pet_kind.rs"),
+    up: v0001_rename_pet_kind::up,
+    down: Some(v0001_rename_pet_kind::down),
+}];
+
+/// A migration recorded in `_migrations`
+pub struct Applied {
+    pub version: u32,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+pub enum State {
+    Pending,
+    Applied(DateTime<Utc>),
+    /// Applied, but its source changed since
+    Changed(DateTime<Utc>),
+    /// Applied, but it isn't in `MIGRATIONS`, e.g. it was applied by a newer version
+    Unknown(DateTime<Utc>),
+}
+
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: String,
+    pub state: State,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Mongo(mongodb::error::Error),
+    /// `MIGRATIONS` isn't ordered by version or has a version twice
+    Unordered(u32),
+    Locked {
+        holder: String,
+        since: String,
+    },
+    /// Another instance took over the lock after it expired
+    LockLost,
+    /// A document of `_migrations` that isn't a recorded migration
+    Malformed(String),
+    /// Applied migrations whose source changed since
+    Changed(Vec<u32>),
+    /// Applied migrations that aren't in `MIGRATIONS`
+    Unknown(Vec<u32>),
+    /// A pending migration is older than the last applied one, which would apply it out
+    /// of order
+    OutOfOrder {
+        version: u32,
+        applied: u32,
+    },
+    UnknownTarget(u32),
+    Irreversible(u32),
+    Failed {
+        version: u32,
+        error: mongodb::error::Error,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Mongo(e) => write!(f, "{}", e),
+            MigrationError::Unordered(version) => write!(
+                f,
+                "Migration {} is out of order or listed twice in MIGRATIONS",
+                version
+            ),
+            MigrationError::Locked { holder, since } => write!(
+                f,
+                "Migrations are locked by {} since {}, the lock expires {} minutes after it was last renewed or run `migrate unlock` if it's no longer running",
+                holder, since, LOCK_LEASE_MINUTES
+            ),
+            MigrationError::LockLost => write!(
+                f,
+                "The lock expired and was taken over by another instance, check `migrate status` before migrating again"
+            ),
+            MigrationError::Malformed(e) => {
+                write!(f, "Malformed document in {}: {}", MIGRATIONS_COLLECTION, e)
+            }
+            MigrationError::Changed(versions) => write!(
+                f,
+                "Migrations {} changed since they were applied",
+                list(versions)
+            ),
+            MigrationError::Unknown(versions) => write!(
+                f,
+                "Migrations {} are applied but unknown to this version",
+                list(versions)
+            ),
+            MigrationError::OutOfOrder { version, applied } => write!(
+                f,
+                "Migration {} is older than the applied migration {}, give it a newer version",
+                version, applied
+            ),
+            MigrationError::UnknownTarget(version) => {
+                write!(f, "There's no migration {}", version)
+            }
+            MigrationError::Irreversible(version) => {
+                write!(f, "Migration {} can't be undone", version)
+            }
+            MigrationError::Failed { version, error } => {
+                write!(f, "Migration {} failed: {}", version, error)
+            }
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for MigrationError {
+    fn from(e: mongodb::error::Error) -> MigrationError {
+        MigrationError::Mongo(e)
+    }
+}
+
+fn list(versions: &[u32]) -> String {
+    let versions: Vec<String> = versions.iter().map(u32::to_string).collect();
+    versions.join(", ")
+}
+
+pub struct Migrator<'a> {
+    database: &'a Database,
+    migrations: &'a [Migration],
+}
+
+impl<'a> Migrator<'a> {
+    pub fn new(
+        database: &'a Database,
+        migrations: &'a [Migration],
+    ) -> Result<Self, MigrationError> {
+        for pair in migrations.windows(2) {
+            if pair[0].version >= pair[1].version {
+                return Err(MigrationError::Unordered(pair[1].version));
+            }
+        }
+        Ok(Migrator {
+            database,
+            migrations,
+        })
+    }
+
+    pub fn database(&self) -> &'a Database {
+        self.database
+    }
+
+    /// The migrations recorded in `_migrations` by version
+    pub fn applied(&self) -> Result<Vec<Applied>, MigrationError> {
+        let options = FindOptions {
+            sort: Some(doc! { "_id": 1 }),
+            ..FindOptions::default()
+        };
+        let cursor = self
+            .database
+            .collection(MIGRATIONS_COLLECTION)
+            .find(None, options)?;
+        let mut applied = Vec::new();
+        for document in cursor {
+            applied.push(parse_applied(&document?).map_err(MigrationError::Malformed)?);
+        }
+        Ok(applied)
+    }
+
+    /// Every known and applied migration by version
+    pub fn status(&self) -> Result<Vec<MigrationStatus>, MigrationError> {
+        Ok(status(self.migrations, &self.applied()?))
+    }
+
+    /// The migrations `up` applies, in order, up to `target` or all of them
+    pub fn plan_up(&self, target: Option<u32>) -> Result<Vec<&'a Migration>, MigrationError> {
+        plan_up(self.migrations, &self.applied()?, target)
+    }
+
+    /// The migrations `down` undoes, newest first, down to but not including `target`, or
+    /// the last applied one
+    pub fn plan_down(&self, target: Option<u32>) -> Result<Vec<&'a Migration>, MigrationError> {
+        plan_down(self.migrations, &self.applied()?, target)
+    }
+
+    /// Applies a plan of `plan_up` and records every migration once it's applied, stops at
+    /// the first that fails or when the lock was lost
+    pub fn up(
+        &self,
+        lock: &Lock,
+        plan: &[&Migration],
+        mut applied: impl FnMut(&Migration, Duration),
+    ) -> Result<(), MigrationError> {
+        let migrations = self.database.collection(MIGRATIONS_COLLECTION);
+        for migration in plan {
+            let started = Instant::now();
+            (migration.up)(self.database).map_err(|error| MigrationError::Failed {
+                version: migration.version,
+                error,
+            })?;
+            migrations.insert_one(
+                doc! {
+                    "_id": migration.version as i64,
+                    "name": migration.name,
+                    "checksum": migration.checksum(),
+                    "applied_at": Utc::now(),
+                },
+                None,
+            )?;
+            applied(migration, started.elapsed());
+            lock.renew()?;
+        }
+        Ok(())
+    }
+
+    /// Undoes a plan of `plan_down` and removes every migration from `_migrations` once
+    /// it's undone, stops at the first that fails or when the lock was lost
+    pub fn down(
+        &self,
+        lock: &Lock,
+        plan: &[&Migration],
+        mut undone: impl FnMut(&Migration, Duration),
+    ) -> Result<(), MigrationError> {
+        let migrations = self.database.collection(MIGRATIONS_COLLECTION);
+        for migration in plan {
+            let down = migration
+                .down
+                .ok_or(MigrationError::Irreversible(migration.version))?;
+            let started = Instant::now();
+            down(self.database).map_err(|error| MigrationError::Failed {
+                version: migration.version,
+                error,
+            })?;
+            migrations.delete_one(doc! { "_id": migration.version as i64 }, None)?;
+            undone(migration, started.elapsed());
+            lock.renew()?;
+        }
+        Ok(())
+    }
+
+    /// Takes the lock of the database, which fails while another instance holds it and
+    /// takes it over when it expired
+    pub fn lock(&self, holder: &str) -> Result<Lock<'a>, MigrationError> {
+        let locks = self.database.collection(LOCK_COLLECTION);
+        let acquired = Lock {
+            database: self.database,
+            holder: holder.to_owned(),
+        };
+        let lock = doc! { "_id": LOCK_ID, "holder": holder, "since": Utc::now() };
+        match locks.insert_one(lock, None) {
+            Ok(_) => return Ok(acquired),
+            Err(e) if !is_duplicate_key(&e) => return Err(e.into()),
+            Err(_) => {}
+        }
+        let lock = locks
+            .find_one(doc! { "_id": LOCK_ID }, None)?
+            .unwrap_or_else(Document::new);
+        let previous = lock.get_str("holder").unwrap_or("unknown").to_owned();
+        let since = match lock.get("since") {
+            Some(Bson::UtcDatetime(since)) => *since,
+            _ => {
+                return Err(MigrationError::Locked {
+                    holder: previous,
+                    since: "unknown".to_owned(),
+                })
+            }
+        };
+        if !lease_expired(since, Utc::now()) {
+            return Err(MigrationError::Locked {
+                holder: previous,
+                since: since.to_rfc3339(),
+            });
+        }
+        // only when no other instance took it over or renewed it since it was read
+        let result = locks.update_one(
+            lock,
+            doc! { "$set": { "holder": holder, "since": Utc::now() } },
+            None,
+        )?;
+        if result.matched_count == 0 {
+            return Err(MigrationError::Locked {
+                holder: "another instance".to_owned(),
+                since: "just now".to_owned(),
+            });
+        }
+        warn!(
+            "Took over the lock of {} that expired, held since {}",
+            previous,
+            since.to_rfc3339()
+        );
+        Ok(acquired)
+    }
+
+    /// Removes the lock left by an instance that stopped while migrating, returns whether
+    /// there was one
+    pub fn unlock(&self) -> Result<bool, MigrationError> {
+        let result = self
+            .database
+            .collection(LOCK_COLLECTION)
+            .delete_one(doc! { "_id": LOCK_ID }, None)?;
+        Ok(result.deleted_count > 0)
+    }
+}
+
+/// A document of `_migrations`, written by `Migrator::up`
+fn parse_applied(document: &Document) -> Result<Applied, String> {
+    let version = match document.get("_id") {
+        Some(Bson::I64(version)) => u32::try_from(*version).ok(),
+        Some(Bson::I32(version)) => u32::try_from(*version).ok(),
+        _ => None,
+    };
+    let version = version.ok_or_else(|| format!("{} isn't a version", document))?;
+    let field = |name: &str| {
+        document
+            .get_str(name)
+            .map(str::to_owned)
+            .map_err(|_| format!("migration {} has no {}", version, name))
+    };
+    Ok(Applied {
+        version,
+        name: field("name")?,
+        checksum: field("checksum")?,
+        applied_at: document
+            .get_utc_datetime("applied_at")
+            .copied()
+            .map_err(|_| format!("migration {} has no applied_at", version))?,
+    })
+}
+
+fn lease_expired(since: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    now - since > chrono::Duration::minutes(LOCK_LEASE_MINUTES)
+}
+
+fn migration(migrations: &[Migration], version: u32) -> Option<&Migration> {
+    migrations.iter().find(|m| m.version == version)
+}
+
+fn target(migrations: &[Migration], target: Option<u32>) -> Result<Option<u32>, MigrationError> {
+    match target {
+        // 0 is before the first migration
+        Some(version) if version != 0 && migration(migrations, version).is_none() => {
+            Err(MigrationError::UnknownTarget(version))
+        }
+        target => Ok(target),
+    }
+}
+
+fn status(migrations: &[Migration], applied: &[Applied]) -> Vec<MigrationStatus> {
+    let mut status: Vec<MigrationStatus> = migrations
+        .iter()
+        .map(|migration| {
+            let state = match applied.iter().find(|a| a.version == migration.version) {
+                None => State::Pending,
+                Some(a) if a.checksum == migration.checksum() => State::Applied(a.applied_at),
+                Some(a) => State::Changed(a.applied_at),
+            };
+            MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_owned(),
+                state,
+            }
+        })
+        .collect();
+    for a in applied {
+        if migration(migrations, a.version).is_none() {
+            status.push(MigrationStatus {
+                version: a.version,
+                name: a.name.clone(),
+                state: State::Unknown(a.applied_at),
+            });
+        }
+    }
+    status.sort_by_key(|s| s.version);
+    status
+}
+
+/// The status, as long as no applied migration changed or is unknown
+fn checked_status(
+    migrations: &[Migration],
+    applied: &[Applied],
+) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let status = status(migrations, applied);
+    let versions = |f: fn(&State) -> bool| -> Vec<u32> {
+        status
+            .iter()
+            .filter(|s| f(&s.state))
+            .map(|s| s.version)
+            .collect()
+    };
+    let changed = versions(|state| matches!(state, State::Changed(_)));
+    if !changed.is_empty() {
+        return Err(MigrationError::Changed(changed));
+    }
+    let unknown = versions(|state| matches!(state, State::Unknown(_)));
+    if !unknown.is_empty() {
+        return Err(MigrationError::Unknown(unknown));
+    }
+    Ok(status)
+}
+
+fn plan_up<'a>(
+    migrations: &'a [Migration],
+    applied: &[Applied],
+    target: Option<u32>,
+) -> Result<Vec<&'a Migration>, MigrationError> {
+    let status = checked_status(migrations, applied)?;
+    let target = self::target(migrations, target)?.unwrap_or(u32::MAX);
+    let last_applied = status
+        .iter()
+        .filter(|s| !matches!(s.state, State::Pending))
+        .map(|s| s.version)
+        .max();
+    let mut plan = Vec::new();
+    for s in status.iter().filter(|s| matches!(s.state, State::Pending)) {
+        if let Some(applied) = last_applied.filter(|applied| *applied > s.version) {
+            return Err(MigrationError::OutOfOrder {
+                version: s.version,
+                applied,
+            });
+        }
+        if s.version <= target {
+            plan.extend(migration(migrations, s.version));
+        }
+    }
+    Ok(plan)
+}
+
+fn plan_down<'a>(
+    migrations: &'a [Migration],
+    applied: &[Applied],
+    target: Option<u32>,
+) -> Result<Vec<&'a Migration>, MigrationError> {
+    let status = checked_status(migrations, applied)?;
+    let target = self::target(migrations, target)?;
+    let mut applied: Vec<&'a Migration> = status
+        .iter()
+        .filter(|s| matches!(s.state, State::Applied(_)))
+        .filter_map(|s| migration(migrations, s.version))
+        .collect();
+    applied.reverse();
+    let plan: Vec<&'a Migration> = match target {
+        Some(target) => applied
+            .into_iter()
+            .take_while(|m| m.version > target)
+            .collect(),
+        None => applied.into_iter().take(1).collect(),
+    };
+    if let Some(migration) = plan.iter().find(|m| m.down.is_none()) {
+        return Err(MigrationError::Irreversible(migration.version));
+    }
+    Ok(plan)
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::WriteError(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+/// Held while migrating, released when dropped
+pub struct Lock<'a> {
+    database: &'a Database,
+    holder: String,
+}
+
+impl Lock<'_> {
+    /// Extends the lease, fails when another instance took over the lock
+    fn renew(&self) -> Result<(), MigrationError> {
+        let result = self.database.collection(LOCK_COLLECTION).update_one(
+            doc! { "_id": LOCK_ID, "holder": &self.holder },
+            doc! { "$set": { "since": Utc::now() } },
+            None,
+        )?;
+        if result.matched_count == 0 {
+            return Err(MigrationError::LockLost);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        let _ = self
+            .database
+            .collection(LOCK_COLLECTION)
+            .delete_one(doc! { "_id": LOCK_ID, "holder": &self.holder }, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn noop(_: &Database) -> Result<(), mongodb::error::Error> {
+        Ok(())
+    }
+
+    const KNOWN: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "first",
+            source: "first",
+            up: noop,
+            down: Some(noop),
+        },
+        Migration {
+            version: 2,
+            name: "second",
+            source: "second",
+            up: noop,
+            down: None,
+        },
+        Migration {
+            version: 3,
+            name: "third",
+            source: "third",
+            up: noop,
+            down: Some(noop),
+        },
+    ];
+
+    fn applied(version: u32) -> Applied {
+        let migration = migration(KNOWN, version).unwrap();
+        Applied {
+            version,
+            name: migration.name.to_owned(),
+            checksum: migration.checksum(),
+            applied_at: Utc.timestamp(1_600_000_000, 0),
+        }
+    }
+
+    fn versions(plan: Result<Vec<&Migration>, MigrationError>) -> Vec<u32> {
+        plan.map(|plan| plan.iter().map(|m| m.version).collect())
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn plans_the_pending_migrations_up_to_the_target() {
+        assert_eq!(versions(plan_up(KNOWN, &[], None)), vec![1, 2, 3]);
+        assert_eq!(versions(plan_up(KNOWN, &[applied(1)], Some(2))), vec![2]);
+        assert_eq!(
+            versions(plan_up(KNOWN, &[applied(1)], Some(0))),
+            Vec::<u32>::new()
+        );
+        let all = [applied(1), applied(2), applied(3)];
+        assert_eq!(versions(plan_up(KNOWN, &all, None)), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn refuses_pending_migrations_older_than_the_applied_ones() {
+        let result = plan_up(KNOWN, &[applied(1), applied(3)], None);
+        assert!(matches!(
+            result,
+            Err(MigrationError::OutOfOrder {
+                version: 2,
+                applied: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn refuses_changed_and_unknown_migrations() {
+        let mut changed = applied(1);
+        changed.checksum = "edited".to_owned();
+        assert!(matches!(
+            plan_up(KNOWN, &[changed], None),
+            Err(MigrationError::Changed(ref versions)) if versions == &[1]
+        ));
+        let mut unknown = applied(1);
+        unknown.version = 4;
+        assert!(matches!(
+            plan_down(KNOWN, &[applied(1), unknown], None),
+            Err(MigrationError::Unknown(ref versions)) if versions == &[4]
+        ));
+        assert!(matches!(
+            plan_up(KNOWN, &[], Some(5)),
+            Err(MigrationError::UnknownTarget(5))
+        ));
+    }
+
+    #[test]
+    fn plans_the_applied_migrations_down_to_the_target() {
+        let all = [applied(1), applied(2), applied(3)];
+        assert_eq!(versions(plan_down(KNOWN, &all, None)), vec![3]);
+        assert_eq!(versions(plan_down(KNOWN, &all, Some(2))), vec![3]);
+        assert_eq!(versions(plan_down(KNOWN, &[applied(1)], Some(0))), vec![1]);
+        assert_eq!(versions(plan_down(KNOWN, &[], Some(0))), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn refuses_to_undo_irreversible_migrations() {
+        let all = [applied(1), applied(2), applied(3)];
+        assert!(matches!(
+            plan_down(KNOWN, &all, Some(0)),
+            Err(MigrationError::Irreversible(2))
+        ));
+    }
+
+    #[test]
+    fn refuses_malformed_applied_migrations() {
+        let at = Utc.timestamp(1_600_000_000, 0);
+        let recorded = doc! { "_id": 1i64, "name": "first", "checksum": "abc", "applied_at": at };
+        assert_eq!(parse_applied(&recorded).unwrap().version, 1);
+        let malformed = [
+            doc! { "_id": "first", "name": "first", "checksum": "abc", "applied_at": at },
+            doc! { "_id": -1i64, "name": "first", "checksum": "abc", "applied_at": at },
+            doc! { "_id": 1i64, "name": "first", "applied_at": at },
+            doc! { "_id": 1i64, "name": "first", "checksum": "abc" },
+        ];
+        for document in &malformed {
+            assert!(parse_applied(document).is_err(), "{}", document);
+        }
+    }
+
+    #[test]
+    fn locks_expire_after_the_lease() {
+        let since = Utc.timestamp(1_600_000_000, 0);
+        let renewed = since + chrono::Duration::minutes(LOCK_LEASE_MINUTES);
+        assert!(!lease_expired(since, renewed));
+        assert!(lease_expired(since, renewed + chrono::Duration::seconds(1)));
+    }
+
+    #[test]
+    fn migrations_are_ordered() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+    }
+}
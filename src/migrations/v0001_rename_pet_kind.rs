@@ -0,0 +1,24 @@
+// Pets written before the field was named `pet_type` have their type in `kind`
+//
+// `down` names it `kind` again on every pet, for going back to a version that reads `kind`.
+
+use bson::doc;
+use mongodb::Database;
+
+pub fn up(database: &Database) -> Result<(), mongodb::error::Error> {
+    database.collection("pets").update_many(
+        doc! { "kind": { "$exists": true }, "pet_type": { "$exists": false } },
+        doc! { "$rename": { "kind": "pet_type" } },
+        None,
+    )?;
+    Ok(())
+}
+
+pub fn down(database: &Database) -> Result<(), mongodb::error::Error> {
+    database.collection("pets").update_many(
+        doc! { "pet_type": { "$exists": true } },
+        doc! { "$rename": { "pet_type": "kind" } },
+        None,
+    )?;
+    Ok(())
+}
@@ -1,8 +1,53 @@
+use bson::oid::ObjectId;
+use mongodb_base_service::ID;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
 pub enum Gender {
     Male,
     Female,
     Other,
 }
+
+/// Ids in paths and imported rows are either the hex of an ObjectId or a string id
+// only used by the main and import binaries
+#[allow(dead_code)]
+pub fn parse_id(id: &str) -> ID {
+    let hex = id.trim_start_matches("$oid:");
+    match ObjectId::with_string(hex) {
+        Ok(oid) => ID::ObjectId(oid),
+        Err(_) => ID::String(id.to_owned()),
+    }
+}
+
+/// Reads a string id of a json body the way `parse_id` reads ids in paths, so an owner given
+/// as the plain hex of its ObjectId is found
+// only used by the main binary
+#[allow(dead_code)]
+pub fn normalize_id(id: ID) -> ID {
+    match id {
+        ID::String(id) => parse_id(&id),
+        id => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_hex_of_object_ids() {
+        let hex = "5f5d4c3b2a19080706050403";
+        let oid = ID::ObjectId(ObjectId::with_string(hex).unwrap());
+        assert_eq!(parse_id(hex), oid);
+        assert_eq!(parse_id(&format!("$oid:{}", hex)), oid);
+        assert_eq!(normalize_id(ID::String(hex.to_owned())), oid);
+        assert_eq!(normalize_id(oid.clone()), oid);
+        assert_eq!(parse_id("rex"), ID::String("rex".to_owned()));
+        assert_eq!(
+            normalize_id(ID::String("rex".to_owned())),
+            ID::String("rex".to_owned())
+        );
+    }
+}
@@ -0,0 +1,93 @@
+// JSON schemas of types from other crates that are serialized in the REST responses,
+// used with `#[schemars(with = "...")]`
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::json;
+
+fn schema(value: serde_json::Value) -> Schema {
+    serde_json::from_value(value).expect("valid schema")
+}
+
+/// An `ID`, ObjectIds are serialized as `{"$oid": "<hex>"}`
+pub enum IdSchema {}
+
+impl JsonSchema for IdSchema {
+    fn schema_name() -> String {
+        "ID".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schema(json!({
+            "oneOf": [
+                {
+                    "type": "object",
+                    "properties": { "$oid": { "type": "string" } },
+                    "required": ["$oid"]
+                },
+                { "type": "string" },
+                { "type": "integer", "format": "int64" }
+            ]
+        }))
+    }
+}
+
+/// `NodeDetails`, the dates are unix timestamps in seconds
+pub enum NodeDetailsSchema {}
+
+impl JsonSchema for NodeDetailsSchema {
+    fn schema_name() -> String {
+        "NodeDetails".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let id = serde_json::to_value(gen.subschema_for::<Option<IdSchema>>()).unwrap();
+        schema(json!({
+            "type": "object",
+            "properties": {
+                "date_created": { "type": "integer", "format": "int64", "nullable": true },
+                "date_modified": { "type": "integer", "format": "int64", "nullable": true },
+                "created_by_id": id,
+                "updated_by_id": id
+            }
+        }))
+    }
+}
+
+pub enum PageInfoSchema {}
+
+impl JsonSchema for PageInfoSchema {
+    fn schema_name() -> String {
+        "PageInfo".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schema(json!({
+            "type": "object",
+            "properties": {
+                "has_next_page": { "type": "boolean" },
+                "has_previous_page": { "type": "boolean" },
+                "start_cursor": { "type": "string", "nullable": true },
+                "next_cursor": { "type": "string", "nullable": true }
+            },
+            "required": ["has_next_page", "has_previous_page"]
+        }))
+    }
+}
+
+pub enum EdgeSchema {}
+
+impl JsonSchema for EdgeSchema {
+    fn schema_name() -> String {
+        "Edge".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schema(json!({
+            "type": "object",
+            "properties": { "cursor": { "type": "string" } },
+            "required": ["cursor"]
+        }))
+    }
+}
@@ -1,7 +1,21 @@
-mod common;
+// imported from `models::common` where they're used, so binaries that don't use them don't
+// compile an unused re-export
+pub mod common;
+mod json_schema;
 mod owners;
 mod pets;
 
-pub use common::Gender;
 pub use owners::*;
 pub use pets::*;
+
+use crate::db::indexes::CollectionIndexes;
+
+/// The indexes every model declares
+// not used by the schema binary, which doesn't connect
+#[allow(dead_code)]
+pub fn indexes() -> Vec<CollectionIndexes> {
+    vec![
+        CollectionIndexes::of::<Owner>(),
+        CollectionIndexes::of::<Pet>(),
+    ]
+}
@@ -1,22 +1,36 @@
+use crate::db::indexes::{Index, Indexed};
 use crate::db::Clients;
 use crate::models::common::Gender;
+use crate::models::json_schema::{EdgeSchema, IdSchema, NodeDetailsSchema, PageInfoSchema};
 use crate::models::pets::Pet;
+use crate::telemetry;
 use bson::doc;
 use chrono::{DateTime, Utc};
 use mongodb_base_service::{BaseService, Node, NodeDetails, ServiceError, ID};
 use mongodb_cursor_pagination::{Edge, FindResult, PageInfo};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Owner {
     #[serde(rename = "_id")]
     // Use MongoDB's special primary key field name when serializing
+    #[schemars(with = "IdSchema")]
     pub id: ID,
+    #[schemars(with = "NodeDetailsSchema")]
     pub node: NodeDetails,
-    username: String,
-    first_name: String,
-    last_name: String,
-    gender: Gender,
+    pub username: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub gender: Gender,
+}
+
+impl Indexed for Owner {
+    const COLLECTION: &'static str = "owners";
+    const INDEXES: &'static [Index] = &[Index {
+        keys: &[("username", 1)],
+        unique: true,
+    }];
 }
 
 impl Node for Owner {
@@ -57,6 +71,7 @@ impl Owner {
     }
 
     fn pets(&self, ctx: &Clients) -> Vec<Pet> {
+        let _span = telemetry::resolver("Owner", "pets");
         let service = &ctx.mongo.get_mongo_service("pets").unwrap();
         let filter = doc! { "owner": self.id.to_bson() };
         let result: Result<FindResult<Pet>, ServiceError> =
@@ -68,9 +83,11 @@ impl Owner {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct OwnerConnection {
+    #[schemars(with = "PageInfoSchema")]
     pub page_info: PageInfo,
+    #[schemars(with = "Vec<EdgeSchema>")]
     pub edges: Vec<Edge>,
     pub items: Vec<Owner>,
     pub total_count: i64,
@@ -106,15 +123,15 @@ impl From<FindResult<Owner>> for OwnerConnection {
     }
 }
 
-#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject, JsonSchema)]
 pub struct NewOwner {
     pub username: String,
-    first_name: String,
-    last_name: String,
-    gender: Gender,
+    pub first_name: String,
+    pub last_name: String,
+    pub gender: Gender,
 }
 
-#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject, JsonSchema)]
 pub struct UpdateOwner {
     /// Optional username to change the value to
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -2,13 +2,17 @@ use chrono::{DateTime, Utc};
 use log::warn;
 use mongodb_base_service::{BaseService, Node, NodeDetails, ServiceError, ID};
 use mongodb_cursor_pagination::{Edge, FindResult, PageInfo};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::db::indexes::{Index, Indexed};
 use crate::db::Clients;
 use crate::models::common::Gender;
+use crate::models::json_schema::{EdgeSchema, IdSchema, NodeDetailsSchema, PageInfoSchema};
 use crate::models::owners::Owner;
+use crate::telemetry;
 
-#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
 pub enum PetTypes {
     Cat,
     Dog,
@@ -17,17 +21,39 @@ pub enum PetTypes {
     Turtle,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Pet {
     #[serde(rename = "_id")]
     // Use MongoDB's special primary key field name when serializing
+    #[schemars(with = "IdSchema")]
     pub id: ID,
+    #[schemars(with = "NodeDetailsSchema")]
     pub node: NodeDetails,
-    name: String,
-    pet_type: PetTypes,
-    age: Option<i32>,
-    gender: Gender,
-    owner: Option<ID>,
+    pub name: String,
+    pub pet_type: PetTypes,
+    pub age: Option<i32>,
+    pub gender: Gender,
+    #[schemars(with = "Option<IdSchema>")]
+    pub owner: Option<ID>,
+}
+
+/// Pets are listed newest first, by type and by owner
+impl Indexed for Pet {
+    const COLLECTION: &'static str = "pets";
+    const INDEXES: &'static [Index] = &[
+        Index {
+            keys: &[("node.date_created", -1)],
+            unique: false,
+        },
+        Index {
+            keys: &[("pet_type", 1), ("node.date_created", -1)],
+            unique: false,
+        },
+        Index {
+            keys: &[("owner", 1), ("node.date_created", -1)],
+            unique: false,
+        },
+    ];
 }
 
 impl Node for Pet {
@@ -84,6 +110,7 @@ impl Pet {
     }
 
     fn owner(&self, ctx: &Clients) -> Option<Owner> {
+        let _span = telemetry::resolver("Pet", "owner");
         match &self.owner {
             None => None,
             Some(owner_id) => {
@@ -102,9 +129,11 @@ impl Pet {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct PetConnection {
+    #[schemars(with = "PageInfoSchema")]
     pub page_info: PageInfo,
+    #[schemars(with = "Vec<EdgeSchema>")]
     pub edges: Vec<Edge>,
     pub items: Vec<Pet>,
     pub total_count: i64,
@@ -140,16 +169,17 @@ impl From<FindResult<Pet>> for PetConnection {
     }
 }
 
-#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject, JsonSchema)]
 pub struct NewPet {
     pub name: String,
-    pet_type: PetTypes,
-    age: Option<i32>,
-    gender: Gender,
-    owner: Option<ID>,
+    pub pet_type: PetTypes,
+    pub age: Option<i32>,
+    pub gender: Gender,
+    #[schemars(with = "Option<IdSchema>")]
+    pub owner: Option<ID>,
 }
 
-#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject, JsonSchema)]
 pub struct UpdatePet {
     /// Optional name to change the value to
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -169,5 +199,6 @@ pub struct UpdatePet {
 
     /// optional owner
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<IdSchema>")]
     pub owner: Option<ID>,
 }
@@ -0,0 +1,122 @@
+use crate::logging;
+use crate::routes::request_id::request_id;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::Error;
+use log::Level;
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use std::time::Instant;
+
+/// Logged under its own target so it can be filtered, e.g. `RUST_LOG=info,access=off`
+const TARGET: &str = "access";
+
+/// Middleware that logs every request with its id, status and duration
+///
+/// Has to be inside `assign_request_id` to log the request's id.
+pub fn log_requests<S, B>(
+    req: ServiceRequest,
+    service: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let started = Instant::now();
+    let mut fields = Map::new();
+    fields.insert("method".to_owned(), Value::from(req.method().as_str()));
+    // not the query string, which has the variables of GET /graphql that aren't redacted here
+    fields.insert("path".to_owned(), Value::from(req.path()));
+    let remote_addr = req.connection_info().remote().map(str::to_owned);
+    fields.insert("remote_addr".to_owned(), json!(remote_addr));
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    fields.insert("user_agent".to_owned(), json!(user_agent));
+    let response = service.call(req);
+    async move {
+        let response = response.await?;
+        let status = response.status();
+        let message = format!(
+            "{} {} {}",
+            fields["method"].as_str().unwrap_or(""),
+            fields["path"].as_str().unwrap_or(""),
+            status.as_u16()
+        );
+        fields.insert(
+            "request_id".to_owned(),
+            json!(request_id(response.request())),
+        );
+        fields.insert("status".to_owned(), Value::from(status.as_u16()));
+        fields.insert(
+            "duration_ms".to_owned(),
+            logging::duration_ms(started.elapsed()),
+        );
+        let level = if status.is_server_error() {
+            Level::Error
+        } else {
+            Level::Info
+        };
+        logging::event(level, TARGET, &message, fields);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::request_id::assign_request_id;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    /// The access log lines of a request
+    async fn log_lines(req: TestRequest) -> Vec<Value> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(log_requests)
+                .wrap_fn(assign_request_id)
+                .route("/pets", web::get().to(HttpResponse::Ok))
+                .route("/fail", web::get().to(HttpResponse::InternalServerError)),
+        )
+        .await;
+        logging::capture::start();
+        test::call_service(&mut app, req.to_request()).await;
+        logging::capture::finish()
+            .into_iter()
+            .filter(|line| line["target"] == TARGET)
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn logs_a_line_per_request() {
+        let req = TestRequest::with_uri("/pets?owner=secret")
+            .header("X-Request-Id", "req-1")
+            .header("User-Agent", "curl/7.68")
+            .peer_addr("127.0.0.1:5000".parse().unwrap());
+        let lines = log_lines(req).await;
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "GET /pets 200");
+        assert_eq!(line["method"], "GET");
+        // without the query string
+        assert_eq!(line["path"], "/pets");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["request_id"], "req-1");
+        assert_eq!(line["user_agent"], "curl/7.68");
+        assert_eq!(line["remote_addr"], "127.0.0.1:5000");
+        assert!(line["duration_ms"].as_f64().unwrap() >= 0.0);
+    }
+
+    #[actix_rt::test]
+    async fn logs_server_errors_as_errors() {
+        let lines = log_lines(TestRequest::with_uri("/fail")).await;
+        assert_eq!(lines[0]["level"], "ERROR");
+        assert_eq!(lines[0]["status"], 500);
+        assert_eq!(lines[0]["user_agent"], Value::Null);
+        // the id the middleware assigned
+        assert_eq!(lines[0]["request_id"].as_str().unwrap().len(), 36);
+    }
+}
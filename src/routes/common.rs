@@ -0,0 +1,596 @@
+use crate::logging;
+use crate::routes::request_id::request_id;
+use crate::telemetry::{self, SpanContext};
+
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::error::{BlockingError, JsonPayloadError, QueryPayloadError};
+use actix_web::http::header::{
+    ETag, EntityTag, Header, HttpDate, IfMatch, IfModifiedSince, IfNoneMatch, LastModified,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, ResponseError};
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Utc};
+use log::error;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::Collection;
+use mongodb_base_service::{ServiceError, ID};
+use mongodb_cursor_pagination::PageInfo;
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DUPLICATE_KEY: i32 = 11000;
+/// The `Retry-After` of unavailable responses
+const RETRY_AFTER_SECONDS: u64 = 5;
+
+/// Errors returned by the REST routes, rendered as `{"error": {"code", "message"}}`
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    Conflict(String),
+    PreconditionFailed(String),
+    PayloadTooLarge(String),
+    /// Too much of the same work is already running, the client can retry later
+    Unavailable(String),
+    Internal(String),
+}
+
+impl ApiError {
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "BAD_REQUEST",
+            ApiError::NotFound(_) => "NOT_FOUND",
+            ApiError::Conflict(_) => "CONFLICT",
+            ApiError::PreconditionFailed(_) => "PRECONDITION_FAILED",
+            ApiError::PayloadTooLarge(_) => "PAYLOAD_TOO_LARGE",
+            ApiError::Unavailable(_) => "UNAVAILABLE",
+            ApiError::Internal(_) => "INTERNAL_SERVER_ERROR",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::PreconditionFailed(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::Unavailable(message)
+            | ApiError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let message = match self {
+            ApiError::Internal(message) => {
+                error!("{}", message);
+                "Internal server error".to_owned()
+            }
+            _ => self.to_string(),
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::Unavailable(_) = self {
+            response.header(header::RETRY_AFTER, RETRY_AFTER_SECONDS.to_string());
+        }
+        response.json(json!({
+            "error": { "code": self.code(), "message": message }
+        }))
+    }
+}
+
+impl From<ServiceError> for ApiError {
+    fn from(e: ServiceError) -> ApiError {
+        match e {
+            ServiceError::NotFound(message) => ApiError::NotFound(message),
+            ServiceError::InvalidCursor(_) | ServiceError::ParseError(_) => {
+                ApiError::BadRequest(e.to_string())
+            }
+            ServiceError::MongoError(ref inner) if is_duplicate_key(&inner.kind) => {
+                ApiError::Conflict("A document with the same key already exists".to_owned())
+            }
+            _ => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<BlockingError<ApiError>> for ApiError {
+    fn from(e: BlockingError<ApiError>) -> ApiError {
+        match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => ApiError::Internal("Blocking operation canceled".to_owned()),
+        }
+    }
+}
+
+fn is_duplicate_key(kind: &ErrorKind) -> bool {
+    match kind {
+        ErrorKind::WriteError(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::BulkWriteError(failure) => failure
+            .write_errors
+            .iter()
+            .flatten()
+            .any(|e| e.code == DUPLICATE_KEY),
+        _ => false,
+    }
+}
+
+/// The most items a page of a list has, larger limits are lowered to it
+pub const MAX_LIMIT: i32 = 100;
+
+/// Query params of the list routes, only one of `after`, `before` or `skip` is used
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Pagination {
+    #[serde(default, deserialize_with = "clamp_limit")]
+    pub limit: Option<i32>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub skip: Option<i32>,
+}
+
+/// A limit between 1 and `MAX_LIMIT`, as the database reads a limit of 0 as no limit
+fn clamp_limit<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+    let limit = Option::<i32>::deserialize(deserializer)?;
+    Ok(limit.map(|limit| limit.clamp(1, MAX_LIMIT)))
+}
+
+/// Responds with a page of a list, with `Link` headers to the next and previous pages
+/// that keep the other query params and the total in `X-Total-Count`
+///
+/// Lists have no `Last-Modified`, the newest item doesn't change when one is deleted, so
+/// only their `ETag` is validated.
+pub fn page_response<T: Serialize>(
+    req: &HttpRequest,
+    page_info: &PageInfo,
+    total_count: i64,
+    page: &T,
+) -> HttpResponse {
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+    let params: Vec<(String, String)> = params
+        .into_iter()
+        .filter(|(name, _)| name != "after" && name != "before" && name != "skip")
+        .collect();
+    let link = |cursor_param: &str, cursor: &str, rel: &str| {
+        let mut params = params.clone();
+        params.push((cursor_param.to_owned(), cursor.to_owned()));
+        let query = serde_urlencoded::to_string(params).unwrap_or_default();
+        format!("<{}?{}>; rel=\"{}\"", req.path(), query, rel)
+    };
+
+    let mut links = Vec::new();
+    if let (true, Some(cursor)) = (page_info.has_next_page, &page_info.next_cursor) {
+        links.push(link("after", cursor, "next"));
+    }
+    if let (true, Some(cursor)) = (page_info.has_previous_page, &page_info.start_cursor) {
+        links.push(link("before", cursor, "prev"));
+    }
+    let mut response = HttpResponse::Ok();
+    response.header("x-total-count", total_count.to_string());
+    if !links.is_empty() {
+        response.header(header::LINK, links.join(", "));
+    }
+    conditional_response(req, response, page, None)
+}
+
+/// Responds with the json of `body` and its `ETag`, and its `Last-Modified` when known,
+/// or with a 304 when the copy of a client that fetched it before is still current
+pub fn conditional_response<T: Serialize>(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    body: &T,
+    last_modified: Option<DateTime<Utc>>,
+) -> HttpResponse {
+    let body = match serde_json::to_vec(body) {
+        Ok(body) => body,
+        Err(e) => return ApiError::Internal(e.to_string()).error_response(),
+    };
+    let etag = etag(&body);
+    response.set(ETag(etag.clone()));
+    if let Some(last_modified) = last_modified {
+        response.set(LastModified(HttpDate::from(SystemTime::from(
+            last_modified,
+        ))));
+    }
+    let is_read = req.method() == Method::GET || req.method() == Method::HEAD;
+    if is_read && is_fresh(req, &etag, last_modified) {
+        return response.status(StatusCode::NOT_MODIFIED).finish();
+    }
+    response.content_type("application/json").body(body)
+}
+
+/// A strong ETag from the hash of the json of a response
+fn etag(body: &[u8]) -> EntityTag {
+    EntityTag::strong(format!("{:x}", Sha256::digest(body)))
+}
+
+/// Whether the client's copy matches `If-None-Match` or, without it, is at least as new
+/// as the resource by `If-Modified-Since`
+fn is_fresh(req: &HttpRequest, etag: &EntityTag, last_modified: Option<DateTime<Utc>>) -> bool {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return match IfNoneMatch::parse(req) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+            Err(_) => false,
+        };
+    }
+    match (last_modified, IfModifiedSince::parse(req)) {
+        (Some(last_modified), Ok(IfModifiedSince(since))) => {
+            // http dates only have seconds
+            match SystemTime::from(since).duration_since(UNIX_EPOCH) {
+                Ok(since) => last_modified.timestamp() <= since.as_secs() as i64,
+                Err(_) => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// The `If-Match` header of a conditional update or delete
+pub fn if_match(req: &HttpRequest) -> Result<Option<IfMatch>, ApiError> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(None);
+    }
+    let invalid = || ApiError::BadRequest("Invalid If-Match header".to_owned());
+    let if_match = IfMatch::parse(req).map_err(|_| invalid())?;
+    // the parser skips the tags it can't read, which would fail every write with a 412
+    if let IfMatch::Items(tags) = &if_match {
+        let mut items = 0;
+        for value in req.headers().get_all(header::IF_MATCH) {
+            let value = value.to_str().map_err(|_| invalid())?;
+            items += value
+                .split(',')
+                .filter(|item| !item.trim().is_empty())
+                .count();
+        }
+        if items == 0 || tags.len() != items {
+            return Err(invalid());
+        }
+    }
+    Ok(Some(if_match))
+}
+
+/// Fails with a 412 unless `If-Match` has the `ETag` of the current version of a resource,
+/// so that a client doesn't overwrite changes it hasn't seen
+pub fn check_if_match<T: Serialize>(if_match: &IfMatch, current: &T) -> Result<(), ApiError> {
+    let matches = match if_match {
+        IfMatch::Any => true,
+        IfMatch::Items(tags) => {
+            let body =
+                serde_json::to_vec(current).map_err(|e| ApiError::Internal(e.to_string()))?;
+            let etag = etag(&body);
+            tags.iter().any(|tag| tag.strong_eq(&etag))
+        }
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(changed_since_fetched())
+    }
+}
+
+/// Finds the document `id` as it's stored and fails with a 412 unless `If-Match` has its
+/// `ETag`, returns the document for a write that's conditional on it
+pub fn find_if_match<T>(
+    collection: &Collection,
+    id: &ID,
+    if_match: &IfMatch,
+    not_found: &str,
+) -> Result<Document, ApiError>
+where
+    T: DeserializeOwned + Serialize,
+{
+    let document = collection
+        .find_one(Some(doc! { "_id": id.to_bson() }), None)
+        .map_err(ServiceError::from)?
+        .ok_or_else(|| ApiError::NotFound(not_found.to_owned()))?;
+    let current: T = bson::from_bson(Bson::Document(document.clone()))
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    check_if_match(if_match, &current)?;
+    Ok(document)
+}
+
+/// Only matches `current` if it wasn't changed since it was read, so that two writes checked
+/// against the same `ETag` can't both succeed. `$literal` keeps values starting with `$` from
+/// being read as field paths.
+fn unchanged(current: &Document) -> Document {
+    doc! {
+        "_id": current.get("_id").cloned().unwrap_or(Bson::Null),
+        "$expr": { "$eq": ["$$ROOT", { "$literal": current.clone() }] },
+    }
+}
+
+fn changed_since_fetched() -> ApiError {
+    ApiError::PreconditionFailed("The resource has changed since it was fetched".to_owned())
+}
+
+/// Sets the fields of `update` like `BaseService::update_one` does, unless `current` was
+/// changed since it was read by `find_if_match`
+pub fn update_if_unchanged<T: Serialize>(
+    collection: &Collection,
+    current: &Document,
+    update: &T,
+) -> Result<(), ApiError> {
+    let mut fields = match bson::to_bson(update).map_err(ServiceError::from)? {
+        Bson::Document(fields) => fields,
+        _ => return Err(ApiError::BadRequest("Invalid update document".to_owned())),
+    };
+    fields.insert("node.date_modified", Utc::now().timestamp());
+    let result = collection
+        .update_one(unchanged(current), doc! { "$set": fields }, None)
+        .map_err(ServiceError::from)?;
+    if result.matched_count == 0 {
+        return Err(changed_since_fetched());
+    }
+    Ok(())
+}
+
+/// Deletes `current` unless it was changed since it was read by `find_if_match`
+pub fn delete_if_unchanged(collection: &Collection, current: &Document) -> Result<(), ApiError> {
+    let result = collection
+        .delete_one(unchanged(current), None)
+        .map_err(ServiceError::from)?;
+    if result.deleted_count == 0 {
+        return Err(changed_since_fetched());
+    }
+    Ok(())
+}
+
+/// Runs `f` on the blocking pool in the span of the request, so the spans of its resolvers
+/// and MongoDB commands are children of the request's, and with the request's id for the
+/// records it logs
+pub async fn traced_block<F, I, E>(req: &HttpRequest, f: F) -> Result<I, BlockingError<E>>
+where
+    F: FnOnce() -> Result<I, E> + Send + 'static,
+    I: Send + 'static,
+    E: Send + fmt::Debug + 'static,
+{
+    let context = req.extensions().get::<SpanContext>().copied();
+    let request_id = request_id(req);
+    web::block(move || {
+        let _entered = telemetry::enter(context);
+        let _request = logging::enter_request(request_id);
+        f()
+    })
+    .await
+}
+
+/// Returns bodies that can't be parsed as json errors instead of plain text
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|e: JsonPayloadError, _: &HttpRequest| {
+        ApiError::BadRequest(e.to_string()).into()
+    })
+}
+
+/// Returns query strings that can't be parsed as json errors instead of plain text
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|e: QueryPayloadError, _: &HttpRequest| {
+        ApiError::BadRequest(e.to_string()).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use chrono::TimeZone;
+
+    fn body() -> serde_json::Value {
+        json!({ "id": "1", "name": "Fido" })
+    }
+
+    fn body_etag() -> EntityTag {
+        etag(&serde_json::to_vec(&body()).unwrap())
+    }
+
+    fn modified() -> DateTime<Utc> {
+        Utc.timestamp(1_600_000_000, 0)
+    }
+
+    fn http_date(time: DateTime<Utc>) -> String {
+        HttpDate::from(SystemTime::from(time)).to_string()
+    }
+
+    fn respond(req: TestRequest) -> HttpResponse {
+        conditional_response(
+            &req.to_http_request(),
+            HttpResponse::Ok(),
+            &body(),
+            Some(modified()),
+        )
+    }
+
+    #[test]
+    fn sets_the_etag_of_the_json_and_the_last_modified() {
+        let response = respond(TestRequest::default());
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        let etag = headers.get(header::ETAG).unwrap().to_str().unwrap();
+        assert_eq!(etag, body_etag().to_string());
+        // a strong tag of the hex sha256
+        assert_eq!(etag.len(), 64 + 2);
+        assert!(etag.starts_with('"'));
+        let last_modified = headers.get(header::LAST_MODIFIED).unwrap();
+        assert_eq!(last_modified.to_str().unwrap(), http_date(modified()));
+    }
+
+    #[test]
+    fn the_etag_changes_with_the_body() {
+        let other = etag(&serde_json::to_vec(&json!({ "id": "1", "name": "Rex" })).unwrap());
+        assert_ne!(body_etag(), other);
+        assert_eq!(body_etag(), etag(&serde_json::to_vec(&body()).unwrap()));
+    }
+
+    #[test]
+    fn if_none_match_of_the_etag_is_not_modified() {
+        let tag = body_etag().to_string();
+        let response = respond(TestRequest::with_header("if-none-match", tag.as_str()));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.headers().contains_key(header::ETAG));
+
+        let weak = format!("\"other\", W/{}", tag);
+        let response = respond(TestRequest::with_header("if-none-match", weak));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = respond(TestRequest::with_header("if-none-match", "*"));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = respond(TestRequest::with_header("if-none-match", "\"other\""));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_modified_since_the_last_change_is_not_modified() {
+        let same = http_date(modified());
+        let response = respond(TestRequest::with_header("if-modified-since", same));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let before = http_date(modified() - chrono::Duration::seconds(1));
+        let response = respond(TestRequest::with_header("if-modified-since", before));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        let req = TestRequest::with_header("if-none-match", "\"other\"")
+            .header("if-modified-since", http_date(modified()));
+        assert_eq!(respond(req).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn only_reads_are_not_modified() {
+        let req = TestRequest::with_header("if-none-match", "*").method(Method::PUT);
+        assert_eq!(respond(req).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn lists_without_last_modified_ignore_if_modified_since() {
+        let req =
+            TestRequest::with_header("if-modified-since", http_date(Utc::now())).to_http_request();
+        let response = conditional_response(&req, HttpResponse::Ok(), &body(), None);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::LAST_MODIFIED));
+    }
+
+    #[test]
+    fn a_malformed_if_match_is_a_bad_request() {
+        for value in &["\"unterminated", "not quoted", "\"a\", b", ""] {
+            let req = TestRequest::with_header("if-match", *value).to_http_request();
+            let error = if_match(&req).unwrap_err();
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST, "{}", value);
+        }
+
+        let req = TestRequest::default().to_http_request();
+        assert!(if_match(&req).unwrap().is_none());
+    }
+
+    #[test]
+    fn a_stale_if_match_fails_the_precondition() {
+        let parse = |value: &str| {
+            let req = TestRequest::with_header("if-match", value).to_http_request();
+            if_match(&req).unwrap().unwrap()
+        };
+        let current = body_etag().to_string();
+        assert!(check_if_match(&parse(&current), &body()).is_ok());
+        assert!(check_if_match(&parse("*"), &body()).is_ok());
+        assert!(check_if_match(&parse(&format!("\"stale\", {}", current)), &body()).is_ok());
+
+        let stale = check_if_match(&parse("\"stale\""), &body()).unwrap_err();
+        assert_eq!(stale.status_code(), StatusCode::PRECONDITION_FAILED);
+        // If-Match compares strongly
+        let weak = check_if_match(&parse(&format!("W/{}", current)), &body()).unwrap_err();
+        assert_eq!(weak.status_code(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[test]
+    fn writes_only_match_the_document_as_it_was_read() {
+        let current = doc! { "_id": 1, "name": "$name" };
+        let filter = unchanged(&current);
+        assert_eq!(filter.get("_id"), Some(&Bson::I32(1)));
+        let expected = doc! { "$eq": ["$$ROOT", { "$literal": current }] };
+        assert_eq!(filter.get_document("$expr").unwrap(), &expected);
+    }
+
+    #[test]
+    fn clamps_the_limit_of_a_page() {
+        let limit = |query: &str| {
+            web::Query::<Pagination>::from_query(query)
+                .unwrap()
+                .into_inner()
+                .limit
+        };
+        assert_eq!(limit(""), None);
+        assert_eq!(limit("limit=20"), Some(20));
+        assert_eq!(limit("limit=100000"), Some(MAX_LIMIT));
+        assert_eq!(limit("limit=0"), Some(1));
+        assert_eq!(limit("limit=-5"), Some(1));
+        assert!(web::Query::<Pagination>::from_query("limit=many").is_err());
+    }
+
+    #[test]
+    fn maps_service_errors_to_statuses() {
+        use mongodb::error::WriteError;
+
+        let not_found = ApiError::from(ServiceError::NotFound("Unable to find pet".to_owned()));
+        assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+
+        let write_error = |code| {
+            let kind = ErrorKind::WriteError(WriteFailure::WriteError(WriteError {
+                code,
+                code_name: None,
+                message: "E11000 duplicate key error".to_owned(),
+            }));
+            ApiError::from(ServiceError::MongoError(mongodb::error::Error::from(kind)))
+        };
+        assert_eq!(
+            write_error(DUPLICATE_KEY).status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            write_error(2).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn renders_errors_as_json() {
+        let response = ApiError::Conflict("taken".to_owned()).error_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = match response.body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes.clone(),
+            _ => panic!("expected a body"),
+        };
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "error": { "code": "CONFLICT", "message": "taken" } })
+        );
+
+        let response = ApiError::Internal("connection refused".to_owned()).error_response();
+        let body = match response.body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes.clone(),
+            _ => panic!("expected a body"),
+        };
+        assert!(!String::from_utf8_lossy(&body).contains("connection refused"));
+    }
+}
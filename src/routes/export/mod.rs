@@ -0,0 +1,603 @@
+use crate::db::Clients;
+use crate::models::{Owner, Pet};
+use crate::routes::common::ApiError;
+use crate::routes::pets::PetFilters;
+
+use actix_web::http::header;
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse};
+use bson::{doc, Bson, Document};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::SinkExt;
+use lazy_static::lazy_static;
+use log::error;
+use mongodb::options::FindOptions;
+use mongodb::Cursor;
+use mongodb_base_service::{BaseService, ServiceError, ID};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Rows encoded and sent to the client at once
+const BATCH_SIZE: usize = 100;
+/// Batches waiting to be sent before reading from the cursor is paused
+const BUFFERED_BATCHES: usize = 4;
+/// Exports read their cursor on threads of their own rather than the blocking pool, which
+/// they'd hold for as long as the client takes to download
+const EXPORT_WORKERS: usize = 4;
+/// Exports waiting for a worker, the next ones are refused until one is free
+const QUEUED_EXPORTS: usize = 16;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    static ref EXPORTS: Mutex<SyncSender<Job>> = Mutex::new(start_workers());
+}
+
+fn start_workers() -> SyncSender<Job> {
+    let (sender, receiver) = sync_channel::<Job>(QUEUED_EXPORTS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for i in 0..EXPORT_WORKERS {
+        let receiver = receiver.clone();
+        thread::Builder::new()
+            .name(format!("export-{}", i))
+            .spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    // a job that panics ends its stream but not the worker
+                    Ok(job) => {
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            error!("an export panicked");
+                        }
+                    }
+                    Err(_) => return,
+                }
+            })
+            .expect("unable to start the export workers");
+    }
+    sender
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    /// The export format the `Accept` header asks for, None when it prefers json
+    pub fn from_accept(req: &HttpRequest) -> Option<Self> {
+        let accept = req.headers().get(header::ACCEPT)?.to_str().ok()?;
+        accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+            .find_map(|media_type| match media_type {
+                "text/csv" => Some(Some(ExportFormat::Csv)),
+                "application/x-ndjson" => Some(Some(ExportFormat::Ndjson)),
+                "application/json" | "*/*" => Some(None),
+                _ => None,
+            })
+            .flatten()
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Query params of the export routes
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportParams {
+    /// `csv` or `ndjson`, defaults to the `Accept` header and then csv
+    format: Option<String>,
+    /// Adds the owner's fields to the pet rows
+    #[serde(default)]
+    pub include_owner: bool,
+}
+
+impl ExportParams {
+    fn format(&self, req: &HttpRequest) -> Result<ExportFormat, ApiError> {
+        match self.format.as_deref() {
+            Some("csv") => Ok(ExportFormat::Csv),
+            Some("ndjson") => Ok(ExportFormat::Ndjson),
+            Some(other) => Err(ApiError::BadRequest(format!(
+                "Unknown export format {}, expected csv or ndjson",
+                other
+            ))),
+            None => Ok(ExportFormat::from_accept(req).unwrap_or(ExportFormat::Csv)),
+        }
+    }
+}
+
+pub async fn export_pets(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    filters: web::Query<PetFilters>,
+    params: web::Query<ExportParams>,
+) -> Result<HttpResponse, ApiError> {
+    let format = params.format(&req)?;
+    stream_pets(
+        clients.get_ref().clone(),
+        filters.into_inner(),
+        format,
+        params.include_owner,
+    )
+    .await
+}
+
+pub async fn export_owners(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    params: web::Query<ExportParams>,
+) -> Result<HttpResponse, ApiError> {
+    let format = params.format(&req)?;
+    stream_owners(clients.get_ref().clone(), format).await
+}
+
+/// Streams every pet matching the filters, optionally with the fields of its owner
+pub async fn stream_pets(
+    clients: Arc<Clients>,
+    filters: PetFilters,
+    format: ExportFormat,
+    include_owner: bool,
+) -> Result<HttpResponse, ApiError> {
+    let cursor = {
+        let clients = clients.clone();
+        web::block(move || open_cursor(&clients, "pets", filters.to_document())).await?
+    };
+    let mut columns = PET_COLUMNS.to_vec();
+    if include_owner {
+        columns.extend(OWNER_OF_PET_COLUMNS);
+    }
+    let to_rows = move |pets: Vec<Pet>| {
+        let owners = if include_owner {
+            find_owners(&clients, &pets)
+        } else {
+            HashMap::new()
+        };
+        pets.iter()
+            .map(|pet| {
+                let mut row = pet_row(pet);
+                if include_owner {
+                    let owner = pet
+                        .owner
+                        .as_ref()
+                        .and_then(|id| owners.get(&id.to_string()));
+                    row.extend(owner_columns(owner));
+                }
+                row
+            })
+            .collect()
+    };
+    stream_rows(format, "pets", columns, cursor, to_rows)
+}
+
+/// Streams every owner
+pub async fn stream_owners(
+    clients: Arc<Clients>,
+    format: ExportFormat,
+) -> Result<HttpResponse, ApiError> {
+    let cursor = web::block(move || open_cursor(&clients, "owners", None)).await?;
+    let columns = OWNER_COLUMNS.to_vec();
+    stream_rows(format, "owners", columns, cursor, |owners: Vec<Owner>| {
+        owners.iter().map(owner_row).collect()
+    })
+}
+
+fn open_cursor(
+    clients: &Clients,
+    collection: &str,
+    filter: Option<Document>,
+) -> Result<Cursor, ApiError> {
+    let service = clients.mongo.get_mongo_service(collection).unwrap();
+    let options = FindOptions::builder()
+        .sort(service.default_sort())
+        .batch_size(BATCH_SIZE as u32)
+        .build();
+    let cursor = service
+        .data_source()
+        .find(filter, options)
+        .map_err(ServiceError::from)?;
+    Ok(cursor)
+}
+
+/// The owners of a batch of pets by id, found with a single query so only the owners of the
+/// batch are held
+fn find_owners(clients: &Clients, pets: &[Pet]) -> HashMap<String, Owner> {
+    let ids: Vec<Bson> = pets
+        .iter()
+        .filter_map(|pet| pet.owner.as_ref())
+        .map(ID::to_bson)
+        .collect();
+    if ids.is_empty() {
+        return HashMap::new();
+    }
+    let service = clients.mongo.get_mongo_service("owners").unwrap();
+    let cursor = match service
+        .data_source()
+        .find(Some(doc! { "_id": { "$in": ids } }), None)
+    {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            error!("unable to find owners for export: {}", e);
+            return HashMap::new();
+        }
+    };
+    cursor
+        .filter_map(|result| {
+            let owner = result
+                .map_err(|e| e.to_string())
+                .and_then(|doc| bson::from_bson(Bson::Document(doc)).map_err(|e| e.to_string()));
+            owner
+                .map_err(|e| error!("unable to find owners for export: {}", e))
+                .ok()
+        })
+        .map(|owner: Owner| (owner.id.to_string(), owner))
+        .collect()
+}
+
+/// A flat record, the column names are the csv headers and the ndjson keys
+type Row = Vec<(&'static str, Value)>;
+
+/// The columns of the rows, known before any row so an empty csv export still has a header
+const PET_COLUMNS: &[&str] = &[
+    "id",
+    "name",
+    "pet_type",
+    "age",
+    "gender",
+    "owner",
+    "date_created",
+    "date_modified",
+];
+const OWNER_COLUMNS: &[&str] = &[
+    "id",
+    "username",
+    "first_name",
+    "last_name",
+    "gender",
+    "date_created",
+    "date_modified",
+];
+const OWNER_OF_PET_COLUMNS: &[&str] = &[
+    "owner_username",
+    "owner_first_name",
+    "owner_last_name",
+    "owner_gender",
+];
+
+fn pet_row(pet: &Pet) -> Row {
+    vec![
+        ("id", Value::from(pet.id.to_string())),
+        ("name", Value::from(pet.name.clone())),
+        ("pet_type", serde_json::to_value(pet.pet_type).unwrap()),
+        ("age", pet.age.map(Value::from).unwrap_or(Value::Null)),
+        ("gender", serde_json::to_value(pet.gender).unwrap()),
+        (
+            "owner",
+            pet.owner
+                .as_ref()
+                .map(|id| Value::from(id.to_string()))
+                .unwrap_or(Value::Null),
+        ),
+        ("date_created", date(pet.node.date_created())),
+        ("date_modified", date(pet.node.date_modified())),
+    ]
+}
+
+fn owner_row(owner: &Owner) -> Row {
+    vec![
+        ("id", Value::from(owner.id.to_string())),
+        ("username", Value::from(owner.username.clone())),
+        ("first_name", Value::from(owner.first_name.clone())),
+        ("last_name", Value::from(owner.last_name.clone())),
+        ("gender", serde_json::to_value(owner.gender).unwrap()),
+        ("date_created", date(owner.node.date_created())),
+        ("date_modified", date(owner.node.date_modified())),
+    ]
+}
+
+/// The owner's fields added to a pet row, empty when the pet has no owner
+fn owner_columns(owner: Option<&Owner>) -> Row {
+    let field = |f: fn(&Owner) -> Value| owner.map(f).unwrap_or(Value::Null);
+    vec![
+        ("owner_username", field(|o| Value::from(o.username.clone()))),
+        (
+            "owner_first_name",
+            field(|o| Value::from(o.first_name.clone())),
+        ),
+        (
+            "owner_last_name",
+            field(|o| Value::from(o.last_name.clone())),
+        ),
+        (
+            "owner_gender",
+            field(|o| serde_json::to_value(o.gender).unwrap()),
+        ),
+    ]
+}
+
+fn date(date: Option<chrono::DateTime<chrono::Utc>>) -> Value {
+    date.map(|d| Value::from(d.to_rfc3339()))
+        .unwrap_or(Value::Null)
+}
+
+/// Encodes batches of rows, the csv header is written before the first row or alone when
+/// the export finishes without rows
+struct Encoder {
+    format: ExportFormat,
+    columns: Vec<&'static str>,
+    wrote_header: bool,
+}
+
+impl Encoder {
+    fn new(format: ExportFormat, columns: Vec<&'static str>) -> Self {
+        Encoder {
+            format,
+            columns,
+            wrote_header: false,
+        }
+    }
+
+    fn encode(&mut self, rows: &[Row]) -> Result<Bytes, String> {
+        match self.format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                if !self.wrote_header {
+                    writer
+                        .write_record(&self.columns)
+                        .map_err(|e| e.to_string())?;
+                    self.wrote_header = true;
+                }
+                for row in rows {
+                    writer
+                        .write_record(row.iter().map(|(_, value)| csv_field(value)))
+                        .map_err(|e| e.to_string())?;
+                }
+                writer
+                    .into_inner()
+                    .map(Bytes::from)
+                    .map_err(|e| e.to_string())
+            }
+            ExportFormat::Ndjson => {
+                let mut buffer = Vec::new();
+                for row in rows {
+                    let object: Map<String, Value> = row
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), value.clone()))
+                        .collect();
+                    serde_json::to_writer(&mut buffer, &object).map_err(|e| e.to_string())?;
+                    buffer.push(b'\n');
+                }
+                Ok(Bytes::from(buffer))
+            }
+        }
+    }
+
+    /// What's left to send once every row was encoded, the header of a csv without rows
+    fn finish(&mut self) -> Result<Option<Bytes>, String> {
+        match self.format {
+            ExportFormat::Csv if !self.wrote_header => self.encode(&[]).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn csv_field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Reads the cursor on an export worker and streams the encoded rows, reading pauses while
+/// the client is slower than the database so documents are never all held in memory.
+/// `to_rows` is given the documents a batch at a time.
+fn stream_rows<T, F>(
+    format: ExportFormat,
+    name: &str,
+    columns: Vec<&'static str>,
+    cursor: Cursor,
+    mut to_rows: F,
+) -> Result<HttpResponse, ApiError>
+where
+    T: DeserializeOwned + 'static,
+    F: FnMut(Vec<T>) -> Vec<Row> + Send + 'static,
+{
+    let (mut sender, receiver) = mpsc::channel::<Result<Bytes, ApiError>>(BUFFERED_BATCHES);
+    let export: Job = Box::new(move || {
+        let mut encoder = Encoder::new(format, columns);
+        let mut items = Vec::with_capacity(BATCH_SIZE);
+        let mut documents = cursor.peekable();
+        while let Some(result) = documents.next() {
+            let item = result
+                .map_err(|e| e.to_string())
+                .and_then(|doc| bson::from_bson(Bson::Document(doc)).map_err(|e| e.to_string()));
+            let chunk = match item {
+                Ok(item) => {
+                    items.push(item);
+                    if items.len() < BATCH_SIZE && documents.peek().is_some() {
+                        continue;
+                    }
+                    let batch = std::mem::replace(&mut items, Vec::with_capacity(BATCH_SIZE));
+                    encoder.encode(&to_rows(batch))
+                }
+                Err(e) => Err(e),
+            };
+            let chunk = chunk.map_err(|e| ApiError::Internal(format!("export failed: {}", e)));
+            let failed = chunk.is_err();
+            // the client went away
+            if block_on(sender.send(chunk)).is_err() || failed {
+                return;
+            }
+        }
+        let chunk = encoder
+            .finish()
+            .map_err(|e| ApiError::Internal(format!("export failed: {}", e)));
+        if let Some(chunk) = chunk.transpose() {
+            let _ = block_on(sender.send(chunk));
+        }
+    });
+    match EXPORTS.lock().unwrap().try_send(export) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            return Err(ApiError::Unavailable(
+                "Too many exports are running, retry later".to_owned(),
+            ))
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            return Err(ApiError::Internal("The export workers stopped".to_owned()))
+        }
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.{}\"", name, format.extension()),
+        )
+        .streaming(receiver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn accept(value: &str) -> Option<ExportFormat> {
+        let req = TestRequest::with_header("accept", value).to_http_request();
+        ExportFormat::from_accept(&req)
+    }
+
+    fn names(row: &Row) -> Vec<&str> {
+        row.iter().map(|(name, _)| *name).collect()
+    }
+
+    fn pet() -> Pet {
+        serde_json::from_value(json!({
+            "_id": "$oid:5f1b3c4d5e6f708192a3b4c5",
+            "node": { "date_created": 1_600_000_000, "date_modified": null },
+            "name": "Rex, Jr.",
+            "pet_type": "Dog",
+            "age": 3,
+            "gender": "Male",
+            "owner": null,
+        }))
+        .unwrap()
+    }
+
+    fn owner() -> Owner {
+        serde_json::from_value(json!({
+            "_id": "$oid:5f1b3c4d5e6f708192a3b4c6",
+            "node": {},
+            "username": "jsmith",
+            "first_name": "John",
+            "last_name": "Smith",
+            "gender": "Male",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn the_format_is_the_first_one_accepted() {
+        assert_eq!(accept("text/csv"), Some(ExportFormat::Csv));
+        assert_eq!(
+            accept("application/x-ndjson; charset=utf-8"),
+            Some(ExportFormat::Ndjson)
+        );
+        assert_eq!(
+            accept("text/html, text/csv;q=0.9, application/json"),
+            Some(ExportFormat::Csv)
+        );
+        assert_eq!(accept("application/json, text/csv"), None);
+        assert_eq!(accept("*/*"), None);
+        assert_eq!(accept("text/html"), None);
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(ExportFormat::from_accept(&req), None);
+    }
+
+    #[test]
+    fn rows_have_the_columns_of_the_header() {
+        let pet = pet();
+        let owner = owner();
+        assert_eq!(names(&pet_row(&pet)), PET_COLUMNS);
+        assert_eq!(names(&owner_row(&owner)), OWNER_COLUMNS);
+        assert_eq!(names(&owner_columns(Some(&owner))), OWNER_OF_PET_COLUMNS);
+        assert_eq!(names(&owner_columns(None)), OWNER_OF_PET_COLUMNS);
+    }
+
+    #[test]
+    fn csv_has_the_header_once_before_the_rows() {
+        let mut row = pet_row(&pet());
+        row.extend(owner_columns(Some(&owner())));
+        let mut columns = PET_COLUMNS.to_vec();
+        columns.extend(OWNER_OF_PET_COLUMNS);
+        let mut encoder = Encoder::new(ExportFormat::Csv, columns);
+        let first = encoder.encode(&[row.clone()]).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&first).unwrap(),
+            "id,name,pet_type,age,gender,owner,date_created,date_modified,\
+             owner_username,owner_first_name,owner_last_name,owner_gender\n\
+             5f1b3c4d5e6f708192a3b4c5,\"Rex, Jr.\",Dog,3,Male,,2020-09-13T12:26:40+00:00,,\
+             jsmith,John,Smith,Male\n"
+        );
+        let second = encoder.encode(&[row]).unwrap();
+        assert!(second.starts_with(b"5f1b3c4d5e6f708192a3b4c5,"));
+        assert_eq!(encoder.finish(), Ok(None));
+    }
+
+    #[test]
+    fn an_empty_csv_export_has_the_header() {
+        let mut encoder = Encoder::new(ExportFormat::Csv, OWNER_COLUMNS.to_vec());
+        let header = encoder.finish().unwrap().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&header).unwrap(),
+            "id,username,first_name,last_name,gender,date_created,date_modified\n"
+        );
+    }
+
+    #[test]
+    fn ndjson_has_an_object_per_line() {
+        let mut encoder = Encoder::new(ExportFormat::Ndjson, OWNER_COLUMNS.to_vec());
+        assert_eq!(encoder.finish(), Ok(None));
+        let rows = [owner_row(&owner()), owner_row(&owner())];
+        let encoded = encoder.encode(&rows).unwrap();
+        let lines: Vec<Value> = std::str::from_utf8(&encoded)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["username"], json!("jsmith"));
+        assert_eq!(lines[0]["date_created"], Value::Null);
+    }
+
+    #[test]
+    fn workers_keep_running_after_a_job_panics() {
+        let jobs = start_workers();
+        for _ in 0..EXPORT_WORKERS {
+            jobs.send(Box::new(|| panic!("export failed"))).unwrap();
+        }
+        let (done, finished) = sync_channel(1);
+        jobs.send(Box::new(move || done.send(()).unwrap())).unwrap();
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(()));
+    }
+}
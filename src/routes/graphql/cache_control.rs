@@ -0,0 +1,301 @@
+use graphql_parser::query::{
+    Definition, Document, FragmentDefinition, OperationDefinition, Selection, SelectionSet,
+    TypeCondition,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::cache::collection_tag;
+use crate::schema::Schema;
+
+/// Max-age in seconds of the data of a type, used by the fields returning the type
+const TYPE_HINTS: &[(&str, u64)] = &[
+    ("Owner", 60),
+    ("OwnerConnection", 30),
+    ("Pet", 60),
+    ("PetConnection", 30),
+    ("_Service", 3600),
+];
+
+/// Max-age in seconds of single fields, which takes precedence over the hint of their type
+const FIELD_HINTS: &[(&str, &str, u64)] = &[("Owner", "pets", 30), ("Pet", "owner", 30)];
+
+/// The collections types are read from, cached responses are invalidated by writes to them
+const TYPE_COLLECTIONS: &[(&str, &str)] = &[("Owner", "owners"), ("Pet", "pets")];
+
+/// Whether a response can be stored by shared caches or only by the client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheScope {
+    Public,
+    Private,
+}
+
+impl fmt::Display for CacheScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheScope::Public => write!(f, "public"),
+            CacheScope::Private => write!(f, "private"),
+        }
+    }
+}
+
+/// The `Cache-Control` of a response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CachePolicy {
+    pub max_age: u64,
+    pub scope: CacheScope,
+}
+
+impl CachePolicy {
+    pub fn no_store() -> Self {
+        CachePolicy {
+            max_age: 0,
+            scope: CacheScope::Public,
+        }
+    }
+
+    /// The policy of a batch, which is only as cacheable as its least cacheable response
+    pub fn merge(self, other: CachePolicy) -> Self {
+        let scope = if self.scope == CacheScope::Private || other.scope == CacheScope::Private {
+            CacheScope::Private
+        } else {
+            CacheScope::Public
+        };
+        CachePolicy {
+            max_age: self.max_age.min(other.max_age),
+            scope,
+        }
+    }
+
+    pub fn header_value(&self) -> String {
+        if self.max_age == 0 {
+            "no-store".to_owned()
+        } else {
+            format!("{}, max-age={}", self.scope, self.max_age)
+        }
+    }
+}
+
+/// The max-age of a query composed from the hints of every field it selects, and the
+/// tags of the collections its data is read from
+pub struct CacheHints {
+    pub max_age: u64,
+    pub tags: Vec<String>,
+}
+
+/// Computes the hints of a query operation, mutations and subscriptions are never cached
+///
+/// The max-age is the lowest hint of the selected fields. A field without a hint uses the
+/// hint of the type it returns, or inherits the max-age of its parent, except root fields
+/// which aren't cached without a hint.
+pub fn cache_hints(
+    schema: &Schema,
+    document: &Document,
+    operation: &OperationDefinition,
+) -> Option<CacheHints> {
+    let selection_set = match operation {
+        OperationDefinition::SelectionSet(s) => s,
+        OperationDefinition::Query(q) => &q.selection_set,
+        OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_) => return None,
+    };
+    let query_type = schema.schema.concrete_query_type().name()?;
+    let mut walker = Walker {
+        schema,
+        fragments: document
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                Definition::Fragment(f) => Some((f.name.as_str(), f)),
+                Definition::Operation(_) => None,
+            })
+            .collect(),
+        visited: HashSet::new(),
+        max_age: u64::MAX,
+        collections: HashSet::new(),
+    };
+    walker.visit(query_type, selection_set, true);
+    Some(CacheHints {
+        // a query that selects no fields with data, e.g. only `__typename`
+        max_age: if walker.max_age == u64::MAX {
+            0
+        } else {
+            walker.max_age
+        },
+        tags: walker.collections.into_iter().map(collection_tag).collect(),
+    })
+}
+
+struct Walker<'a> {
+    schema: &'a Schema,
+    fragments: HashMap<&'a str, &'a FragmentDefinition>,
+    /// The fragments visited and whether they were spread in the root selection, the hints of
+    /// a fragment only depend on both so it's only visited once, which also stops fragments
+    /// that spread each other
+    visited: HashSet<(&'a str, bool)>,
+    max_age: u64,
+    collections: HashSet<&'static str>,
+}
+
+impl<'a> Walker<'a> {
+    fn visit(&mut self, type_name: &str, selection_set: &'a SelectionSet, root: bool) {
+        let parent = match self.schema.schema.concrete_type_by_name(type_name) {
+            Some(parent) => parent,
+            None => return,
+        };
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => {
+                    // unknown fields are reported when the query is validated
+                    let meta_field = match parent.field_by_name(&field.name) {
+                        Some(meta_field) => meta_field,
+                        None => continue,
+                    };
+                    let return_type = meta_field.field_type.innermost_name();
+                    let hint =
+                        field_hint(type_name, &field.name).or_else(|| type_hint(return_type));
+                    match hint {
+                        Some(max_age) => self.max_age = self.max_age.min(max_age),
+                        None if root => self.max_age = 0,
+                        None => {}
+                    }
+                    if let Some(collection) = collection(return_type) {
+                        self.collections.insert(collection);
+                    }
+                    if !field.selection_set.items.is_empty() {
+                        self.visit(return_type, &field.selection_set, false);
+                    }
+                }
+                Selection::InlineFragment(inline) => {
+                    let type_name = match &inline.type_condition {
+                        Some(TypeCondition::On(name)) => name.as_str(),
+                        None => type_name,
+                    };
+                    self.visit(type_name, &inline.selection_set, root);
+                }
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.fragment_name.as_str();
+                    let fragment = match self.fragments.get(name) {
+                        Some(fragment) => *fragment,
+                        None => continue,
+                    };
+                    if !self.visited.insert((name, root)) {
+                        continue;
+                    }
+                    let TypeCondition::On(type_name) = &fragment.type_condition;
+                    self.visit(type_name, &fragment.selection_set, root);
+                }
+            }
+        }
+    }
+}
+
+fn type_hint(type_name: &str) -> Option<u64> {
+    TYPE_HINTS
+        .iter()
+        .find(|(name, _)| *name == type_name)
+        .map(|(_, max_age)| *max_age)
+}
+
+fn field_hint(type_name: &str, field_name: &str) -> Option<u64> {
+    FIELD_HINTS
+        .iter()
+        .find(|(t, f, _)| *t == type_name && *f == field_name)
+        .map(|(_, _, max_age)| *max_age)
+}
+
+fn collection(type_name: &str) -> Option<&'static str> {
+    TYPE_COLLECTIONS
+        .iter()
+        .find(|(name, _)| *name == type_name)
+        .map(|(_, collection)| *collection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::create_schema;
+
+    fn hints(query: &str) -> CacheHints {
+        let schema = create_schema();
+        let document = graphql_parser::parse_query(query).unwrap();
+        let operation = document
+            .definitions
+            .iter()
+            .find_map(|definition| match definition {
+                Definition::Operation(operation) => Some(operation),
+                Definition::Fragment(_) => None,
+            })
+            .unwrap();
+        cache_hints(&schema, &document, operation).unwrap()
+    }
+
+    fn tags(hints: &CacheHints) -> Vec<&str> {
+        let mut tags: Vec<&str> = hints.tags.iter().map(String::as_str).collect();
+        tags.sort();
+        tags
+    }
+
+    #[test]
+    fn uses_the_lowest_hint_of_the_fields() {
+        let hints = hints("{ petById(id: \"1\") { name owner { username } } }");
+        assert_eq!(hints.max_age, 30);
+        let mut expected = vec![collection_tag("owners"), collection_tag("pets")];
+        expected.sort();
+        assert_eq!(tags(&hints), expected);
+    }
+
+    #[test]
+    fn root_fields_without_a_hint_are_not_cached() {
+        let query = "{ _entities(representations: []) { __typename } }";
+        assert_eq!(hints(query).max_age, 0);
+    }
+
+    #[test]
+    fn visits_inline_fragments() {
+        let query = "
+            {
+                ownerById(id: \"1\") { username }
+                ... on Query { petsByType(petType: Dog) { items { name } } }
+            }
+        ";
+        let hints = hints(query);
+        assert_eq!(hints.max_age, 30);
+        let mut expected = vec![collection_tag("owners"), collection_tag("pets")];
+        expected.sort();
+        assert_eq!(tags(&hints), expected);
+
+        let query = "
+            {
+                ownerById(id: \"1\") { username }
+                ... { _entities(representations: []) { __typename } }
+            }
+        ";
+        assert_eq!(self::hints(query).max_age, 0);
+    }
+
+    #[test]
+    fn visits_named_fragments() {
+        let query = "
+            query { ownerById(id: \"1\") { ...Owner } ...Root }
+            fragment Owner on Owner { pets { items { name } } }
+            fragment Root on Query { _entities(representations: []) { __typename } }
+        ";
+        let hints = hints(query);
+        assert_eq!(hints.max_age, 0);
+        let mut expected = vec![collection_tag("owners"), collection_tag("pets")];
+        expected.sort();
+        assert_eq!(tags(&hints), expected);
+    }
+
+    #[test]
+    fn stops_at_fragments_that_spread_each_other() {
+        let query = "
+            query { ...A }
+            fragment A on Query { allPets { items { name } } ...B }
+            fragment B on Query { ...A }
+        ";
+        let hints = hints(query);
+        assert_eq!(hints.max_age, 30);
+        assert_eq!(tags(&hints), vec![collection_tag("pets")]);
+    }
+}
@@ -0,0 +1,496 @@
+use actix_web::HttpRequest;
+use bson::{Bson, Document};
+use chrono::{DateTime, SecondsFormat, Utc};
+use graphql_parser::query::{
+    self, Definition, FragmentDefinition, OperationDefinition, Selection, SelectionSet,
+    TypeCondition,
+};
+use juniper::meta::MetaType;
+use mongodb::Database;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use super::GraphQLOptions;
+use crate::db::slow_queries::{self, EXPLAINABLE};
+use crate::schema::Schema;
+use crate::telemetry::{CommandTiming, DebugTrace, ResolverTiming};
+
+/// Header with the token of `graphql.debug_token`
+pub const DEBUG_TOKEN_HEADER: &str = "x-debug-token";
+
+/// Commands explained per response, every explain is another command sent to the database
+const MAX_EXPLAINED_COMMANDS: usize = 10;
+
+/// Whether the request has the debug token, never when there's no token configured
+pub fn is_debug_request(req: &HttpRequest, options: &GraphQLOptions) -> bool {
+    let token = match &options.debug_token {
+        Some(token) => token,
+        None => return false,
+    };
+    match req.headers().get(DEBUG_TOKEN_HEADER) {
+        Some(value) => same_token(value.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compares every byte so the time it takes doesn't tell how much of a token is right
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The operation a debug response executed and the data it returned
+pub struct Executed<'a> {
+    pub document: &'a query::Document,
+    pub operation: &'a OperationDefinition,
+    pub data: &'a Value,
+}
+
+/// The extensions of a debug response, the resolver timings in the Apollo tracing format and
+/// the MongoDB commands the resolvers sent with the query plans of the first
+/// `MAX_EXPLAINED_COMMANDS`
+///
+/// The queries are explained once the response is resolved, so it doesn't change the
+/// timings. Juniper doesn't tell resolvers the path of their field, so the paths are found
+/// by walking the executed selection set along the data of the response.
+pub fn extensions(
+    trace: &DebugTrace,
+    schema: &Schema,
+    database: &Database,
+    executed: Option<Executed>,
+) -> Value {
+    let fields = executed.map_or_else(Vec::new, |executed| executed_fields(schema, &executed));
+    let resolvers: Vec<Value> = resolver_paths(&trace.resolvers, &fields)
+        .into_iter()
+        .zip(&trace.resolvers)
+        .map(|(path, resolver)| resolver_timing(path, resolver, schema))
+        .collect();
+    let commands: Vec<Value> = trace
+        .commands
+        .iter()
+        .enumerate()
+        .map(|(i, command)| command_timing(command, database, i < MAX_EXPLAINED_COMMANDS))
+        .collect();
+    json!({
+        "tracing": {
+            "version": 1,
+            "startTime": timestamp(trace.start_time),
+            "endTime": timestamp(trace.start_time + trace.duration),
+            "duration": nanos(trace.duration),
+            "execution": { "resolvers": resolvers },
+        },
+        "mongodb": { "commands": commands },
+    })
+}
+
+fn resolver_timing(path: Vec<Value>, resolver: &ResolverTiming, schema: &Schema) -> Value {
+    json!({
+        "path": path,
+        "parentType": resolver.parent_type,
+        "fieldName": resolver.field_name,
+        "returnType": return_type(schema, &resolver.parent_type, &resolver.field_name),
+        "startOffset": nanos(resolver.start_offset),
+        "duration": nanos(resolver.duration),
+    })
+}
+
+/// A field of the response, in the order juniper resolves them
+struct ExecutedField {
+    path: Vec<Value>,
+    /// None when the field was selected on an interface or union and the response doesn't
+    /// have the `__typename` of the object
+    parent_type: Option<String>,
+    field_name: String,
+}
+
+/// The fields of the executed selection set that are in the response data, depth first and
+/// in the order of the query like juniper resolves them
+fn executed_fields(schema: &Schema, executed: &Executed) -> Vec<ExecutedField> {
+    let (root_type, selection_set) = match executed.operation {
+        OperationDefinition::SelectionSet(s) => (schema.schema.concrete_query_type(), s),
+        OperationDefinition::Query(q) => (schema.schema.concrete_query_type(), &q.selection_set),
+        OperationDefinition::Mutation(m) => match schema.schema.concrete_mutation_type() {
+            Some(mutation_type) => (mutation_type, &m.selection_set),
+            None => return Vec::new(),
+        },
+        OperationDefinition::Subscription(_) => return Vec::new(),
+    };
+    let data = match executed.data.as_object() {
+        Some(data) => data,
+        None => return Vec::new(),
+    };
+    let mut walker = FieldWalker {
+        schema,
+        fragments: executed
+            .document
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                Definition::Fragment(f) => Some((f.name.as_str(), f)),
+                Definition::Operation(_) => None,
+            })
+            .collect(),
+        spreading: Vec::new(),
+        path: Vec::new(),
+        fields: Vec::new(),
+    };
+    if let Some(root_type) = root_type.name() {
+        walker.visit(root_type, selection_set, data);
+    }
+    walker.fields
+}
+
+struct FieldWalker<'a> {
+    schema: &'a Schema,
+    fragments: HashMap<&'a str, &'a FragmentDefinition>,
+    /// The fragments being spread, a fragment spreading itself isn't followed
+    spreading: Vec<&'a str>,
+    path: Vec<Value>,
+    fields: Vec<ExecutedField>,
+}
+
+impl<'a> FieldWalker<'a> {
+    /// Visits a selection set on an object of the response
+    fn visit(
+        &mut self,
+        type_name: &str,
+        selection_set: &'a SelectionSet,
+        object: &Map<String, Value>,
+    ) {
+        let meta_type = match self.schema.schema.concrete_type_by_name(type_name) {
+            Some(meta_type) => meta_type,
+            None => return,
+        };
+        let parent_type = if meta_type.is_abstract() {
+            object.get("__typename").and_then(Value::as_str)
+        } else {
+            Some(type_name)
+        };
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => {
+                    let key = field.alias.as_ref().unwrap_or(&field.name);
+                    // fields skipped with `@skip` or `@include` aren't in the response
+                    let value = match object.get(key) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    self.path.push(Value::from(key.as_str()));
+                    self.fields.push(ExecutedField {
+                        path: self.path.clone(),
+                        parent_type: parent_type.map(str::to_owned),
+                        field_name: field.name.clone(),
+                    });
+                    let field_type = parent_type
+                        .and_then(|parent| self.schema.schema.concrete_type_by_name(parent))
+                        .and_then(|parent| parent.field_by_name(&field.name))
+                        .or_else(|| meta_type.field_by_name(&field.name));
+                    if let Some(field_type) = field_type {
+                        let return_type = field_type.field_type.innermost_name();
+                        self.descend(return_type, &field.selection_set, value);
+                    }
+                    self.path.pop();
+                }
+                Selection::InlineFragment(inline) => {
+                    if let Some(type_name) =
+                        self.fragment_type(type_name, parent_type, inline.type_condition.as_ref())
+                    {
+                        self.visit(type_name, &inline.selection_set, object);
+                    }
+                }
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.fragment_name.as_str();
+                    let fragment = match self.fragments.get(name) {
+                        Some(fragment) => *fragment,
+                        None => continue,
+                    };
+                    if self.spreading.contains(&name) {
+                        continue;
+                    }
+                    if let Some(type_name) =
+                        self.fragment_type(type_name, parent_type, Some(&fragment.type_condition))
+                    {
+                        self.spreading.push(name);
+                        self.visit(type_name, &fragment.selection_set, object);
+                        self.spreading.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Visits the value of a field, the items of lists are in the path by their index
+    fn descend(&mut self, type_name: &str, selection_set: &'a SelectionSet, value: &Value) {
+        match value {
+            Value::Object(object) => self.visit(type_name, selection_set, object),
+            Value::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.path.push(Value::from(i));
+                    self.descend(type_name, selection_set, item);
+                    self.path.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The type a fragment's fields are selected on, None when the object isn't of the
+    /// fragment's type
+    fn fragment_type<'b>(
+        &self,
+        type_name: &'b str,
+        parent_type: Option<&str>,
+        condition: Option<&'b TypeCondition>,
+    ) -> Option<&'b str> {
+        let condition = match condition {
+            Some(TypeCondition::On(condition)) => condition.as_str(),
+            None => return Some(type_name),
+        };
+        match self.schema.schema.concrete_type_by_name(condition) {
+            Some(MetaType::Object(_)) => match parent_type {
+                Some(parent_type) if parent_type != condition => None,
+                _ => Some(condition),
+            },
+            // a fragment on an interface or union the object is part of
+            _ => Some(type_name),
+        }
+    }
+}
+
+/// The path of every timed resolver, the resolvers ran in the order of the executed fields so
+/// each one is the next field with its parent type and name
+fn resolver_paths(resolvers: &[ResolverTiming], fields: &[ExecutedField]) -> Vec<Vec<Value>> {
+    let mut next = 0;
+    resolvers
+        .iter()
+        .map(|resolver| {
+            let found = fields[next..].iter().position(|field| {
+                // fields of interfaces and unions of unknown type match any parent type
+                let same_parent = match &field.parent_type {
+                    Some(parent_type) => *parent_type == resolver.parent_type,
+                    None => true,
+                };
+                field.field_name == resolver.field_name && same_parent
+            });
+            match found {
+                Some(i) => {
+                    next += i + 1;
+                    fields[next - 1].path.clone()
+                }
+                // the response couldn't be walked, e.g. the query didn't parse
+                None => vec![Value::from(resolver.field_name.as_str())],
+            }
+        })
+        .collect()
+}
+
+fn return_type(schema: &Schema, parent_type: &str, field_name: &str) -> Option<String> {
+    match schema.schema.concrete_type_by_name(parent_type)? {
+        MetaType::Object(object) => object
+            .fields
+            .iter()
+            .find(|field| field.name == field_name)
+            .map(|field| field.field_type.to_string()),
+        _ => None,
+    }
+}
+
+fn command_timing(timing: &CommandTiming, database: &Database, explain_it: bool) -> Value {
+    let command = &timing.command;
+    let mut entry = Map::new();
+    entry.insert("command".to_owned(), json!(timing.command_name));
+    entry.insert("collection".to_owned(), json!(timing.collection));
+    entry.insert("resolver".to_owned(), json!(timing.resolver));
+    entry.insert("startOffset".to_owned(), json!(nanos(timing.start_offset)));
+    entry.insert("duration".to_owned(), json!(nanos(timing.duration)));
+    // `count` and `distinct` have their filter in `query`
+    let filter = command.get("filter").or_else(|| command.get("query"));
+    entry.insert("filter".to_owned(), to_json(filter));
+    entry.insert("sort".to_owned(), to_json(command.get("sort")));
+    if let Some(pipeline) = command.get("pipeline") {
+        entry.insert("pipeline".to_owned(), to_json(Some(pipeline)));
+    }
+    if let Some(failure) = &timing.failure {
+        entry.insert("error".to_owned(), json!(failure));
+    } else if EXPLAINABLE.contains(&timing.command_name.as_str()) {
+        let explained = if explain_it {
+            explain(database, command)
+        } else {
+            let skipped = format!(
+                "only the first {} commands are explained",
+                MAX_EXPLAINED_COMMANDS
+            );
+            json!({ "error": skipped })
+        };
+        entry.insert("explain".to_owned(), explained);
+    }
+    Value::Object(entry)
+}
+
+fn explain(database: &Database, command: &Document) -> Value {
+    let explained = match slow_queries::explain(database, command) {
+        Ok(explained) => explained,
+        Err(e) => return json!({ "error": e }),
+    };
+    match slow_queries::summarize_plan(&explained) {
+        Some(plan) => json!({
+            "plan": plan.stages,
+            "collectionScan": plan.collection_scan,
+            "winningPlan": Value::from(Bson::Document(plan.winning_plan)),
+        }),
+        None => json!({ "error": "no winning plan" }),
+    }
+}
+
+fn to_json(value: Option<&Bson>) -> Value {
+    value.map_or(Value::Null, |value| value.clone().into())
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::create_schema;
+
+    fn timing(parent_type: &str, field_name: &str) -> ResolverTiming {
+        ResolverTiming {
+            parent_type: parent_type.to_owned(),
+            field_name: field_name.to_owned(),
+            start_offset: Duration::default(),
+            duration: Duration::default(),
+        }
+    }
+
+    fn paths(query: &str, data: Value, resolvers: &[ResolverTiming]) -> Vec<Value> {
+        let schema = create_schema();
+        let document = graphql_parser::parse_query(query).unwrap();
+        let operation = document
+            .definitions
+            .iter()
+            .find_map(|definition| match definition {
+                Definition::Operation(operation) => Some(operation),
+                Definition::Fragment(_) => None,
+            })
+            .unwrap();
+        let executed = Executed {
+            document: &document,
+            operation,
+            data: &data,
+        };
+        let fields = executed_fields(&schema, &executed);
+        resolver_paths(resolvers, &fields)
+            .into_iter()
+            .map(Value::from)
+            .collect()
+    }
+
+    #[test]
+    fn paths_have_the_index_of_list_items() {
+        let query = "{ allPets { items { name owner { pets { items { name } } } } } }";
+        let data = json!({ "allPets": { "items": [
+            { "name": "Rex", "owner": { "pets": { "items": [{ "name": "Rex" }] } } },
+            { "name": "Tom", "owner": null },
+        ] } });
+        let resolvers = [
+            timing("Query", "allPets"),
+            timing("Pet", "owner"),
+            timing("Owner", "pets"),
+            timing("Pet", "owner"),
+        ];
+        assert_eq!(
+            paths(query, data, &resolvers),
+            vec![
+                json!(["allPets"]),
+                json!(["allPets", "items", 0, "owner"]),
+                json!(["allPets", "items", 0, "owner", "pets"]),
+                json!(["allPets", "items", 1, "owner"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_use_aliases_and_follow_fragments() {
+        let query = "query Pets($skip: Boolean!) {
+            dogs: petsByType(petType: DOG) { ...pets }
+            cats: petsByType(petType: CAT) @skip(if: $skip) { ...pets }
+            owners: allOwners { items { ... on Owner { pets { totalCount } } } }
+        }
+        fragment pets on PetConnection { items { owner { username } } }";
+        let data = json!({
+            "dogs": { "items": [{ "owner": { "username": "jsmith" } }] },
+            "owners": { "items": [{ "pets": { "totalCount": 1 } }] },
+        });
+        let resolvers = [
+            timing("Query", "petsByType"),
+            timing("Pet", "owner"),
+            timing("Query", "allOwners"),
+            timing("Owner", "pets"),
+        ];
+        assert_eq!(
+            paths(query, data, &resolvers),
+            vec![
+                json!(["dogs"]),
+                json!(["dogs", "items", 0, "owner"]),
+                json!(["owners"]),
+                json!(["owners", "items", 0, "pets"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn entities_are_told_apart_by_their_typename() {
+        let query = "query($representations: [_Any!]!) {
+            _entities(representations: $representations) {
+                __typename
+                ... on Pet { owner { username } }
+                ... on Owner { pets { totalCount } }
+            }
+        }";
+        let data = json!({ "_entities": [
+            { "__typename": "Owner", "pets": { "totalCount": 0 } },
+            { "__typename": "Pet", "owner": null },
+        ] });
+        let resolvers = [
+            timing("Query", "_entities"),
+            timing("Owner", "pets"),
+            timing("Pet", "owner"),
+        ];
+        assert_eq!(
+            paths(query, data, &resolvers),
+            vec![
+                json!(["_entities"]),
+                json!(["_entities", 0, "pets"]),
+                json!(["_entities", 1, "owner"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolvers_are_in_the_apollo_tracing_format() {
+        let schema = create_schema();
+        let resolver = ResolverTiming {
+            parent_type: "Query".to_owned(),
+            field_name: "petsByType".to_owned(),
+            start_offset: Duration::from_nanos(41_000),
+            duration: Duration::from_nanos(2_350_000),
+        };
+        assert_eq!(
+            resolver_timing(vec![json!("dogs")], &resolver, &schema),
+            json!({
+                "path": ["dogs"],
+                "parentType": "Query",
+                "fieldName": "petsByType",
+                "returnType": "PetConnection!",
+                "startOffset": 41_000,
+                "duration": 2_350_000,
+            })
+        );
+    }
+}
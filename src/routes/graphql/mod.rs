@@ -1,31 +1,512 @@
+mod cache_control;
+mod debug;
+mod options;
+mod persisted;
+mod request;
+
 use crate::db::Clients;
+use crate::logging;
+use crate::metrics;
+use crate::routes::common::traced_block;
+use crate::routes::request_id::request_id;
 use crate::schema::Schema;
+use crate::telemetry;
 
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{error, http::header, web, Error, HttpRequest, HttpResponse};
+use graphql_parser::query::{Document, OperationDefinition};
 use juniper::http::graphiql::graphiql_source;
-use juniper::http::GraphQLRequest;
+use juniper::http::{GraphQLRequest, GraphQLResponse};
+use juniper::{graphql_value, FieldError};
+use log::{log_enabled, Level};
+use serde_json::{Map, Value};
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cache_control::{CachePolicy, CacheScope};
+use debug::is_debug_request;
+pub use options::GraphQLOptions;
+use persisted::hash_query;
+pub use persisted::PersistedQueryStore;
+use request::{GraphQLBatchPayload, GraphQLGetParams, GraphQLPayload, OperationType};
 
-pub async fn graphiql() -> HttpResponse {
-    let port = dotenv::var("PORT").unwrap_or("8080".to_owned());
-    let html = graphiql_source(&format!("http://localhost:{}/graphql", port));
+pub async fn graphiql(options: web::Data<Arc<GraphQLOptions>>) -> HttpResponse {
+    if !options.graphiql {
+        return HttpResponse::NotFound().finish();
+    }
+    let html = graphiql_source(&options.public_url);
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(html)
 }
 
+/// Name of the full responses in the query cache, `CACHE_TTL_GRAPHQL_RESPONSE` caps how
+/// long they're kept
+const RESPONSE_CACHE: &str = "graphql_response";
+
+#[derive(Clone)]
+struct CachedResponse {
+    body: String,
+    created: Instant,
+}
+
+/// Why a response isn't cached
+enum Uncached {
+    Errors(String),
+    Json(serde_json::error::Error),
+}
+
+/// Executes and serializes a request, the bool is whether the response has no errors
+///
+/// Debug requests record what their resolvers did and return it in the extensions.
+fn execute_request(
+    schema: &Schema,
+    clients: &Clients,
+    options: &GraphQLOptions,
+    request: GraphQLRequest,
+    debug: bool,
+    operation: Option<(&Document, &OperationDefinition)>,
+) -> Result<(String, bool), serde_json::error::Error> {
+    let recording = if debug {
+        Some(telemetry::record_debug_trace())
+    } else {
+        None
+    };
+    let response = request.execute(schema, clients);
+    let trace = recording.map(telemetry::DebugRecording::finish);
+    let mut value = serde_json::to_value(&response)?;
+    let ok = value.get("errors").is_none();
+    if options.mask_errors {
+        options::mask_errors(&mut value);
+    }
+    add_request_id(&mut value, clients.request_id.as_deref());
+    if let Some(trace) = trace {
+        let executed = match (operation, value.get("data")) {
+            (Some((document, operation)), Some(data)) => Some(debug::Executed {
+                document,
+                operation,
+                data,
+            }),
+            _ => None,
+        };
+        let extensions = debug::extensions(&trace, schema, &clients.database, executed);
+        if let Some(response) = value.as_object_mut() {
+            response.insert("extensions".to_owned(), extensions);
+        }
+    }
+    Ok((serde_json::to_string(&value)?, ok))
+}
+
+/// Serializes a response with a single error, for requests that aren't executed
+fn error_response(
+    error: FieldError,
+    request_id: Option<&str>,
+) -> Result<String, serde_json::error::Error> {
+    let mut value = serde_json::to_value(GraphQLResponse::error(error))?;
+    add_request_id(&mut value, request_id);
+    serde_json::to_string(&value)
+}
+
+/// Adds the id of the request to the extensions of every error, so a client can report it
+/// and it can be found in the logs
+fn add_request_id(response: &mut Value, request_id: Option<&str>) {
+    let request_id = match request_id {
+        Some(request_id) => request_id,
+        None => return,
+    };
+    let errors = match response.get_mut("errors").and_then(|e| e.as_array_mut()) {
+        Some(errors) => errors,
+        None => return,
+    };
+    for error in errors.iter_mut().filter_map(Value::as_object_mut) {
+        let extensions = error
+            .entry("extensions")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Some(extensions) = extensions.as_object_mut() {
+            extensions.insert("requestId".to_owned(), Value::from(request_id));
+        }
+    }
+}
+
+/// The context of a request, with the request's id for the resolvers and errors
+fn request_context(req: &HttpRequest, clients: &Clients) -> Clients {
+    Clients {
+        request_id: request_id(req),
+        ..clients.clone()
+    }
+}
+
+/// Logged under its own target so operations can be filtered apart from the access log
+const LOG_TARGET: &str = "graphql";
+
+/// Executes a payload whose query has already been resolved in a span of its own, records
+/// its duration by `operationName` and logs it
+fn run(
+    schema: &Schema,
+    clients: &Clients,
+    options: &GraphQLOptions,
+    payload: GraphQLPayload,
+    principal: Option<&str>,
+    debug: bool,
+) -> Result<(String, CachePolicy), serde_json::error::Error> {
+    let started = Instant::now();
+    let operation = payload
+        .operation_name
+        .clone()
+        .unwrap_or_else(|| "anonymous".to_owned());
+    let operation_type = payload
+        .operation_type()
+        .map_or("invalid", OperationType::as_str);
+    let mut span = telemetry::span(format!("{} {}", operation_type, operation));
+    span.set_attribute("graphql.operation.name", operation.as_str());
+    span.set_attribute("graphql.operation.type", operation_type);
+    let variables = if log_enabled!(target: LOG_TARGET, Level::Info) {
+        Some(serde_json::to_value(&payload.variables)?)
+    } else {
+        None
+    };
+    let result = respond(schema, clients, options, payload, principal, debug);
+    metrics::observe_graphql_operation(&operation, operation_type, started.elapsed());
+    if let Some(variables) = variables {
+        let mut fields = Map::new();
+        fields.insert("operation_name".to_owned(), Value::from(operation.as_str()));
+        fields.insert("operation_type".to_owned(), Value::from(operation_type));
+        fields.insert("variables".to_owned(), options.redact(variables));
+        fields.insert(
+            "duration_ms".to_owned(),
+            logging::duration_ms(started.elapsed()),
+        );
+        if let Ok((body, _)) = &result {
+            let (count, codes) = error_codes(body);
+            fields.insert("error_count".to_owned(), Value::from(count));
+            fields.insert("error_codes".to_owned(), Value::from(codes));
+        }
+        let message = format!("{} {}", operation_type, operation);
+        logging::event(Level::Info, LOG_TARGET, &message, fields);
+    }
+    result
+}
+
+/// The number of errors of a response and their distinct codes, errors such as syntax
+/// errors have no code
+fn error_codes(body: &str) -> (usize, Vec<String>) {
+    // responses without errors aren't parsed again
+    if !body.contains("\"errors\"") {
+        return (0, Vec::new());
+    }
+    let value: Value = serde_json::from_str(body).unwrap_or_default();
+    let errors = match value.get("errors").and_then(Value::as_array) {
+        Some(errors) => errors,
+        None => return (0, Vec::new()),
+    };
+    let mut codes: Vec<String> = errors
+        .iter()
+        .filter_map(|error| error.pointer("/extensions/code")?.as_str())
+        .map(str::to_owned)
+        .collect();
+    codes.sort();
+    codes.dedup();
+    (errors.len(), codes)
+}
+
+/// Responses to queries without errors are cached for the max-age composed from the cache
+/// hints of their fields, debug responses are always executed and never cached
+fn respond(
+    schema: &Schema,
+    clients: &Clients,
+    options: &GraphQLOptions,
+    payload: GraphQLPayload,
+    principal: Option<&str>,
+    debug: bool,
+) -> Result<(String, CachePolicy), serde_json::error::Error> {
+    if !options.introspection && payload.is_introspection() {
+        let error: FieldError = FieldError::new(
+            "GraphQL introspection is not allowed",
+            graphql_value!({ "code": "INTROSPECTION_DISABLED" }),
+        );
+        let body = error_response(error, clients.request_id.as_deref())?;
+        return Ok((body, CachePolicy::no_store()));
+    }
+    let document = payload.document();
+    let operation = document
+        .as_ref()
+        .and_then(|document| Some((document, payload.operation(document)?)));
+    let hints = operation
+        .and_then(|(document, operation)| cache_control::cache_hints(schema, document, operation));
+    let (document, hints) = match (&document, hints) {
+        (Some(document), Some(hints)) if hints.max_age > 0 && !debug => (document, hints),
+        _ => {
+            let request = payload.into_request();
+            let (body, _) = execute_request(schema, clients, options, request, debug, operation)?;
+            return Ok((body, CachePolicy::no_store()));
+        }
+    };
+
+    let key = response_key(document, &payload, principal)?;
+    let max_age = Duration::from_secs(hints.max_age);
+    let result =
+        clients
+            .cache
+            .get_or_insert_with_max_age(RESPONSE_CACHE, &key, hints.tags, max_age, || {
+                let request = payload.into_request();
+                match execute_request(schema, clients, options, request, false, None) {
+                    Ok((body, true)) => Ok(CachedResponse {
+                        body,
+                        created: Instant::now(),
+                    }),
+                    Ok((body, false)) => Err(Uncached::Errors(body)),
+                    Err(e) => Err(Uncached::Json(e)),
+                }
+            });
+    match result {
+        Ok(response) => {
+            let age = response.created.elapsed().as_secs();
+            let policy = CachePolicy {
+                max_age: hints.max_age.saturating_sub(age),
+                scope: if principal.is_some() {
+                    CacheScope::Private
+                } else {
+                    CacheScope::Public
+                },
+            };
+            Ok((response.body, policy))
+        }
+        Err(Uncached::Errors(body)) => Ok((body, CachePolicy::no_store())),
+        Err(Uncached::Json(e)) => Err(e),
+    }
+}
+
+/// Keys a response by the normalized query, so formatting and comments don't matter, the
+/// operation, the variables and the principal the response may be private to
+fn response_key(
+    document: &Document,
+    payload: &GraphQLPayload,
+    principal: Option<&str>,
+) -> Result<String, serde_json::error::Error> {
+    let variables = sort_keys(serde_json::to_value(&payload.variables)?);
+    Ok(hash_query(&format!(
+        "{}\n{}\n{}\n{}",
+        document,
+        payload.operation_name.as_deref().unwrap_or(""),
+        variables,
+        principal.unwrap_or("")
+    )))
+}
+
+/// Sorts the keys of objects so the same variables sent in another order have the same key
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(name, value)| (name, sort_keys(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+/// Responses to requests with credentials are only cached for the same credentials and are
+/// private to the client
+fn principal(req: &HttpRequest) -> Option<String> {
+    let authorization = req.headers().get(header::AUTHORIZATION)?;
+    Some(hash_query(&String::from_utf8_lossy(
+        authorization.as_bytes(),
+    )))
+}
+
+fn execute(
+    schema: &Schema,
+    clients: &Clients,
+    persisted_queries: &PersistedQueryStore,
+    options: &GraphQLOptions,
+    payload: GraphQLPayload,
+    principal: Option<&str>,
+    debug: bool,
+) -> Result<(String, CachePolicy), serde_json::error::Error> {
+    match persisted_queries.resolve(payload) {
+        Ok(payload) => run(schema, clients, options, payload, principal, debug),
+        Err(e) => {
+            let body = error_response(e, clients.request_id.as_deref())?;
+            Ok((body, CachePolicy::no_store()))
+        }
+    }
+}
+
 pub async fn graphql(
+    req: HttpRequest,
+    st: web::Data<Arc<Schema>>,
+    clients: web::Data<Arc<Clients>>,
+    persisted_queries: web::Data<Arc<PersistedQueryStore>>,
+    options: web::Data<Arc<GraphQLOptions>>,
+    data: web::Json<GraphQLBatchPayload>,
+) -> Result<HttpResponse, Error> {
+    let principal = principal(&req);
+    let clients = request_context(&req, &clients);
+    let debug = is_debug_request(&req, &options);
+    let (result, policy) = traced_block(&req, move || {
+        let principal = principal.as_deref();
+        match data.into_inner() {
+            GraphQLBatchPayload::Single(payload) => execute(
+                &st,
+                &clients,
+                &persisted_queries,
+                &options,
+                payload,
+                principal,
+                debug,
+            ),
+            GraphQLBatchPayload::Batch(payloads) => {
+                // operations are executed in order and returned in the same order
+                let mut responses = Vec::new();
+                let mut batch_policy: Option<CachePolicy> = None;
+                for payload in payloads {
+                    let (response, policy) = execute(
+                        &st,
+                        &clients,
+                        &persisted_queries,
+                        &options,
+                        payload,
+                        principal,
+                        debug,
+                    )?;
+                    responses.push(response);
+                    batch_policy = Some(match batch_policy {
+                        Some(batch_policy) => batch_policy.merge(policy),
+                        None => policy,
+                    });
+                }
+                let policy = batch_policy.unwrap_or_else(CachePolicy::no_store);
+                Ok((format!("[{}]", responses.join(",")), policy))
+            }
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header(header::CACHE_CONTROL, policy.header_value())
+        .body(result))
+}
+
+/// Executes queries sent as query params so they can be cached by a CDN, mutations are rejected
+pub async fn graphql_get(
+    req: HttpRequest,
     st: web::Data<Arc<Schema>>,
     clients: web::Data<Arc<Clients>>,
-    data: web::Json<GraphQLRequest>,
+    persisted_queries: web::Data<Arc<PersistedQueryStore>>,
+    options: web::Data<Arc<GraphQLOptions>>,
+    params: web::Query<GraphQLGetParams>,
 ) -> Result<HttpResponse, Error> {
-    let result = web::block(move || {
-        let res = data.execute(&st, &clients);
-        Ok::<_, serde_json::error::Error>(serde_json::to_string(&res)?)
+    let payload = params
+        .into_inner()
+        .into_payload()
+        .map_err(error::ErrorBadRequest)?;
+    let payload = match persisted_queries.resolve(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            return Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .header(
+                    header::CACHE_CONTROL,
+                    CachePolicy::no_store().header_value(),
+                )
+                .body(error_response(e, request_id(&req).as_deref())?))
+        }
+    };
+    if let Some(operation_type) = payload.operation_type() {
+        if operation_type != OperationType::Query {
+            return Ok(HttpResponse::MethodNotAllowed()
+                .header(header::ALLOW, "POST")
+                .content_type("application/json")
+                .body(r#"{"errors":[{"message":"Only queries can be sent with GET"}]}"#));
+        }
+    }
+    let principal = principal(&req);
+    let clients = request_context(&req, &clients);
+    let debug = is_debug_request(&req, &options);
+    let (result, policy) = traced_block(&req, move || {
+        run(
+            &st,
+            &clients,
+            &options,
+            payload,
+            principal.as_deref(),
+            debug,
+        )
     })
     .await?;
     Ok(HttpResponse::Ok()
         .content_type("application/json")
+        .header(header::CACHE_CONTROL, policy.header_value())
         .body(result))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn counts_errors_and_their_distinct_codes() {
+        let body = r#"{"data":null,"errors":[
+            {"message":"a","extensions":{"code":"NOT_FOUND"}},
+            {"message":"b"},
+            {"message":"c","extensions":{"code":"BAD_REQUEST"}},
+            {"message":"d","extensions":{"code":"NOT_FOUND"}}
+        ]}"#;
+        assert_eq!(
+            error_codes(body),
+            (4, vec!["BAD_REQUEST".to_owned(), "NOT_FOUND".to_owned()])
+        );
+    }
+
+    #[test]
+    fn has_no_errors_without_an_errors_array() {
+        assert_eq!(error_codes(r#"{"data":{"pets":[]}}"#), (0, Vec::new()));
+        assert_eq!(
+            error_codes(r#"{"data":{"errors":"a field"}}"#),
+            (0, Vec::new())
+        );
+        assert_eq!(error_codes("not json \"errors\""), (0, Vec::new()));
+    }
+
+    #[test]
+    fn adds_the_request_id_to_every_error() {
+        let mut response = json!({
+            "data": null,
+            "errors": [
+                { "message": "a", "extensions": { "code": "NOT_FOUND" } },
+                { "message": "b" },
+            ],
+        });
+        add_request_id(&mut response, Some("req-1"));
+        assert_eq!(
+            response["errors"],
+            json!([
+                { "message": "a", "extensions": { "code": "NOT_FOUND", "requestId": "req-1" } },
+                { "message": "b", "extensions": { "requestId": "req-1" } },
+            ])
+        );
+
+        let mut data = json!({ "data": { "allPets": [] } });
+        add_request_id(&mut data, Some("req-1"));
+        assert_eq!(data, json!({ "data": { "allPets": [] } }));
+    }
+
+    #[test]
+    fn errors_of_requests_that_werent_executed_have_the_request_id() {
+        let error = FieldError::new("Unknown persisted query", juniper::Value::null());
+        let body = error_response(error, Some("req-2")).unwrap();
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["errors"][0]["extensions"]["requestId"], "req-2");
+
+        let error = FieldError::new("Unknown persisted query", juniper::Value::null());
+        let body: Value = serde_json::from_str(&error_response(error, None).unwrap()).unwrap();
+        assert!(body["errors"][0].get("extensions").is_none());
+    }
+}
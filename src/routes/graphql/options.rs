@@ -0,0 +1,134 @@
+use serde_json::Value;
+
+use crate::config::Config;
+
+const MASKED_MESSAGE: &str = "Internal server error";
+const REDACTED: &str = "[REDACTED]";
+
+/// Settings for the graphql endpoints that depend on the environment
+///
+/// Introspection and graphiql are disabled in production unless they're
+/// explicitly enabled with `graphql.introspection` and `graphql.graphiql`.
+pub struct GraphQLOptions {
+    pub introspection: bool,
+    pub graphiql: bool,
+    pub mask_errors: bool,
+    /// The url graphiql sends queries to
+    pub public_url: String,
+    /// Lowercase parts of the names of variables that aren't logged
+    pub redacted_variables: Vec<String>,
+    /// The token of the requests that get debug extensions
+    pub debug_token: Option<String>,
+}
+
+impl GraphQLOptions {
+    pub fn from_config(config: &Config) -> Self {
+        let enabled_by_default = !config.environment.is_production();
+        let graphql = &config.graphql;
+        let public_url = graphql
+            .public_url
+            .clone()
+            .unwrap_or_else(|| format!("http://localhost:{}/graphql", config.server.port));
+        GraphQLOptions {
+            introspection: graphql.introspection.unwrap_or(enabled_by_default),
+            graphiql: graphql.graphiql.unwrap_or(enabled_by_default),
+            mask_errors: config.environment.is_production(),
+            public_url,
+            redacted_variables: graphql
+                .redacted_variables
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+            debug_token: graphql.debug_token.clone(),
+        }
+    }
+
+    /// Replaces the values of variables and fields of input objects whose name contains one
+    /// of `redacted_variables`
+    pub fn redact(&self, value: Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(name, value)| {
+                        let lowercase = name.to_lowercase();
+                        if self
+                            .redacted_variables
+                            .iter()
+                            .any(|r| lowercase.contains(r))
+                        {
+                            (name, Value::from(REDACTED))
+                        } else {
+                            (name, self.redact(value))
+                        }
+                    })
+                    .collect(),
+            ),
+            Value::Array(items) => {
+                Value::Array(items.into_iter().map(|item| self.redact(item)).collect())
+            }
+            value => value,
+        }
+    }
+}
+
+/// Replaces the message of errors raised while resolving fields unless they have an
+/// error code, which marks them as safe to show to clients
+pub fn mask_errors(response: &mut Value) {
+    let errors = match response.get_mut("errors").and_then(|e| e.as_array_mut()) {
+        Some(errors) => errors,
+        None => return,
+    };
+    for error in errors.iter_mut() {
+        let has_code = error.pointer("/extensions/code").is_some();
+        if let Some(error) = error.as_object_mut() {
+            // errors without a path are request errors (parsing, validation)
+            if error.contains_key("path") && !has_code {
+                error.insert("message".to_owned(), Value::from(MASKED_MESSAGE));
+                error.remove("extensions");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn options(redacted_variables: &[&str]) -> GraphQLOptions {
+        GraphQLOptions {
+            introspection: true,
+            graphiql: true,
+            mask_errors: false,
+            public_url: String::new(),
+            redacted_variables: redacted_variables.iter().map(|r| r.to_string()).collect(),
+            debug_token: None,
+        }
+    }
+
+    #[test]
+    fn redacts_variables_whose_name_contains_a_redacted_part() {
+        let options = options(&["password", "token"]);
+        let variables = json!({
+            "username": "jsmith",
+            "newPassword": "hunter2",
+            "input": { "apiToken": "abc", "name": "Fido" },
+            "owners": [{ "Password": "x", "age": 3 }],
+        });
+        assert_eq!(
+            options.redact(variables),
+            json!({
+                "username": "jsmith",
+                "newPassword": REDACTED,
+                "input": { "apiToken": REDACTED, "name": "Fido" },
+                "owners": [{ "Password": REDACTED, "age": 3 }],
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_variables_without_redacted_parts() {
+        let variables = json!({ "password": "hunter2", "ids": ["1", "2"] });
+        assert_eq!(options(&[]).redact(variables.clone()), variables);
+    }
+}
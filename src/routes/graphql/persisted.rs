@@ -0,0 +1,198 @@
+use cached::{Cached, SizedCache};
+use juniper::{graphql_value, FieldError};
+use log::{info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+use super::request::GraphQLPayload;
+use crate::config::PersistedQueriesConfig;
+use crate::metrics;
+
+/// Name of the cache of queries registered by clients in the cache metrics
+const RUNTIME_CACHE: &str = "persisted_queries";
+
+const SUPPORTED_VERSION: i32 = 1;
+
+#[derive(Deserialize)]
+pub struct PersistedQuery {
+    version: i32,
+    #[serde(rename = "sha256Hash")]
+    sha256_hash: String,
+}
+
+/// Stores queries by their sha256 hash for automatic persisted queries (APQ)
+///
+/// Registered queries are loaded from a manifest at startup and never evicted,
+/// queries registered by clients at runtime are kept in a bounded cache. In
+/// allowlist mode only the registered queries can be executed.
+pub struct PersistedQueryStore {
+    registered: HashMap<String, String>,
+    runtime: Mutex<SizedCache<String, String>>,
+    allowlist_only: bool,
+}
+
+impl PersistedQueryStore {
+    pub fn new(
+        registered: HashMap<String, String>,
+        cache_size: usize,
+        allowlist_only: bool,
+    ) -> Self {
+        PersistedQueryStore {
+            registered,
+            runtime: Mutex::new(SizedCache::with_size(cache_size)),
+            allowlist_only,
+        }
+    }
+
+    /// Builds the store from the `persisted_queries` config
+    pub fn from_config(config: &PersistedQueriesConfig) -> Self {
+        let registered = match &config.manifest {
+            Some(path) => load_manifest(path),
+            None => HashMap::new(),
+        };
+        if config.allowlist && registered.is_empty() {
+            warn!("persisted query allowlist is enabled but no queries are registered");
+        }
+        PersistedQueryStore::new(registered, config.cache_size, config.allowlist)
+    }
+
+    fn get(&self, hash: &str) -> Option<String> {
+        if let Some(query) = self.registered.get(hash) {
+            return Some(query.clone());
+        }
+        if self.allowlist_only {
+            return None;
+        }
+        let query = self
+            .runtime
+            .lock()
+            .unwrap()
+            .cache_get(&hash.to_owned())
+            .cloned();
+        match query {
+            Some(_) => metrics::cache_hit(RUNTIME_CACHE),
+            None => metrics::cache_miss(RUNTIME_CACHE),
+        }
+        query
+    }
+
+    fn insert(&self, hash: String, query: String) {
+        self.runtime.lock().unwrap().cache_set(hash, query);
+    }
+
+    fn is_registered_query(&self, query: &str) -> bool {
+        self.registered.contains_key(&hash_query(query))
+    }
+
+    /// Resolves the query to execute, registering it when the client sends both the hash and the query
+    pub fn resolve(&self, payload: GraphQLPayload) -> Result<GraphQLPayload, FieldError> {
+        let GraphQLPayload {
+            query,
+            operation_name,
+            variables,
+            extensions,
+        } = payload;
+        let persisted = extensions.and_then(|e| e.persisted_query);
+        let query = match (persisted, query) {
+            (Some(persisted), query) => {
+                if persisted.version != SUPPORTED_VERSION {
+                    return Err(apq_error(
+                        "Unsupported persisted query version",
+                        "PERSISTED_QUERY_VERSION_NOT_SUPPORTED",
+                    ));
+                }
+                match query {
+                    Some(query) => {
+                        if hash_query(&query) != persisted.sha256_hash {
+                            return Err(apq_error(
+                                "provided sha does not match query",
+                                "INVALID_PERSISTED_QUERY_HASH",
+                            ));
+                        }
+                        if self.allowlist_only {
+                            if !self.is_registered_query(&query) {
+                                return Err(not_allowed());
+                            }
+                        } else {
+                            self.insert(persisted.sha256_hash, query.clone());
+                        }
+                        query
+                    }
+                    None => match self.get(&persisted.sha256_hash) {
+                        Some(query) => query,
+                        None if self.allowlist_only => return Err(not_allowed()),
+                        None => {
+                            return Err(apq_error(
+                                "PersistedQueryNotFound",
+                                "PERSISTED_QUERY_NOT_FOUND",
+                            ))
+                        }
+                    },
+                }
+            }
+            (None, Some(query)) => {
+                if self.allowlist_only && !self.is_registered_query(&query) {
+                    return Err(not_allowed());
+                }
+                query
+            }
+            (None, None) => return Err(apq_error("Must provide a query string", "BAD_REQUEST")),
+        };
+        Ok(GraphQLPayload {
+            query: Some(query),
+            operation_name,
+            variables,
+            extensions: None,
+        })
+    }
+}
+
+pub fn hash_query(query: &str) -> String {
+    format!("{:x}", Sha256::digest(query.as_bytes()))
+}
+
+/// Loads a manifest file, either a map of hash to query or a list of queries
+fn load_manifest(path: &str) -> HashMap<String, String> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Manifest {
+        Map(HashMap<String, String>),
+        List(Vec<String>),
+    }
+
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read persisted query manifest {}: {}", path, e));
+    let manifest: Manifest = serde_json::from_str(&contents)
+        .unwrap_or_else(|e| panic!("Invalid persisted query manifest {}: {}", path, e));
+    let queries: HashMap<String, String> = match manifest {
+        Manifest::Map(map) => map
+            .into_iter()
+            .map(|(hash, query)| {
+                if hash_query(&query) != hash {
+                    panic!(
+                        "Persisted query manifest hash {} does not match its query",
+                        hash
+                    );
+                }
+                (hash, query)
+            })
+            .collect(),
+        Manifest::List(list) => list.into_iter().map(|q| (hash_query(&q), q)).collect(),
+    };
+    info!("loaded {} persisted queries from {}", queries.len(), path);
+    queries
+}
+
+fn apq_error(message: &str, code: &str) -> FieldError {
+    FieldError::new(message, graphql_value!({ "code": code }))
+}
+
+fn not_allowed() -> FieldError {
+    apq_error(
+        "PersistedQueryNotAllowed: only registered queries may be executed",
+        "PERSISTED_QUERY_NOT_ALLOWED",
+    )
+}
@@ -0,0 +1,251 @@
+use graphql_parser::query::{Definition, Document, OperationDefinition, Selection, SelectionSet};
+use juniper::http::GraphQLRequest;
+use juniper::InputValue;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+use super::persisted::PersistedQuery;
+use crate::federation;
+
+/// The body of a graphql request, which unlike juniper's `GraphQLRequest`
+/// may omit the query when it carries a persisted query hash instead
+#[derive(Deserialize)]
+pub struct GraphQLPayload {
+    pub(super) query: Option<String>,
+    #[serde(rename = "operationName")]
+    pub(super) operation_name: Option<String>,
+    pub(super) variables: Option<InputValue>,
+    pub(super) extensions: Option<Extensions>,
+}
+
+#[derive(Deserialize)]
+pub struct Extensions {
+    #[serde(rename = "persistedQuery")]
+    pub(super) persisted_query: Option<PersistedQuery>,
+}
+
+/// A POST body is either a single operation or an array of operations executed together
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum GraphQLBatchPayload {
+    Single(GraphQLPayload),
+    Batch(Vec<GraphQLPayload>),
+}
+
+/// The query string of a GET request, `variables` and `extensions` are json encoded
+#[derive(Deserialize)]
+pub struct GraphQLGetParams {
+    query: Option<String>,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<String>,
+    extensions: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperationType {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+impl OperationType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OperationType::Query => "query",
+            OperationType::Mutation => "mutation",
+            OperationType::Subscription => "subscription",
+        }
+    }
+}
+
+impl GraphQLGetParams {
+    pub fn into_payload(self) -> Result<GraphQLPayload, serde_json::Error> {
+        let variables = match self.variables {
+            Some(v) if !v.is_empty() => Some(serde_json::from_str(&v)?),
+            _ => None,
+        };
+        let extensions = match self.extensions {
+            Some(e) if !e.is_empty() => Some(serde_json::from_str(&e)?),
+            _ => None,
+        };
+        Ok(GraphQLPayload {
+            query: self.query,
+            operation_name: self.operation_name,
+            variables,
+            extensions,
+        })
+    }
+}
+
+impl GraphQLPayload {
+    pub(super) fn document(&self) -> Option<Document> {
+        graphql_parser::parse_query(self.query.as_ref()?).ok()
+    }
+
+    /// The operation of the document that will be executed, the one named `operationName`
+    /// or else the first one
+    pub(super) fn operation<'a>(&self, document: &'a Document) -> Option<&'a OperationDefinition> {
+        operations(document).find(|operation| {
+            let name = match operation {
+                OperationDefinition::SelectionSet(_) => None,
+                OperationDefinition::Query(q) => q.name.as_ref(),
+                OperationDefinition::Mutation(m) => m.name.as_ref(),
+                OperationDefinition::Subscription(s) => s.name.as_ref(),
+            };
+            match &self.operation_name {
+                Some(wanted) => name == Some(wanted),
+                None => true,
+            }
+        })
+    }
+
+    /// Returns the type of the operation that will be executed, or None if the
+    /// query can't be parsed (execution will report the actual error)
+    pub fn operation_type(&self) -> Option<OperationType> {
+        let document = self.document()?;
+        let operation_type = match self.operation(&document)? {
+            OperationDefinition::SelectionSet(_) | OperationDefinition::Query(_) => {
+                OperationType::Query
+            }
+            OperationDefinition::Mutation(_) => OperationType::Mutation,
+            OperationDefinition::Subscription(_) => OperationType::Subscription,
+        };
+        Some(operation_type)
+    }
+
+    /// Whether any operation in the query selects `__schema` or `__type`
+    pub fn is_introspection(&self) -> bool {
+        let document = match self.document() {
+            Some(document) => document,
+            None => return false,
+        };
+        let fragments: HashMap<&str, &SelectionSet> = document
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                Definition::Fragment(f) => Some((f.name.as_str(), &f.selection_set)),
+                Definition::Operation(_) => None,
+            })
+            .collect();
+        let is_introspection = operations(&document).any(|operation| {
+            let selection_set = match operation {
+                OperationDefinition::SelectionSet(s) => s,
+                OperationDefinition::Query(q) => &q.selection_set,
+                OperationDefinition::Mutation(m) => &m.selection_set,
+                OperationDefinition::Subscription(s) => &s.selection_set,
+            };
+            selects_introspection(selection_set, &fragments, &mut HashSet::new())
+        });
+        is_introspection
+    }
+
+    pub fn into_request(mut self) -> GraphQLRequest {
+        // only queries of the gateway declare `_Any` variables, the others aren't parsed again
+        let declares_any = matches!(&self.query, Some(query) if query.contains("_Any"));
+        if let (true, Some(mut variables)) = (declares_any, self.variables.take()) {
+            if let Some(document) = self.document() {
+                if let Some(operation) = self.operation(&document) {
+                    federation::encode_representations(operation, &mut variables);
+                }
+            }
+            self.variables = Some(variables);
+        }
+        GraphQLRequest::new(
+            self.query.unwrap_or_default(),
+            self.operation_name,
+            self.variables,
+        )
+    }
+}
+
+fn operations(document: &Document) -> impl Iterator<Item = &OperationDefinition> {
+    document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation(operation) => Some(operation),
+            Definition::Fragment(_) => None,
+        })
+}
+
+/// Fragments spread more than once are only visited the first time, which also stops
+/// fragments that spread each other
+fn selects_introspection<'a>(
+    selection_set: &'a SelectionSet,
+    fragments: &HashMap<&str, &'a SelectionSet>,
+    visited: &mut HashSet<&'a str>,
+) -> bool {
+    for selection in &selection_set.items {
+        let selects = match selection {
+            Selection::Field(field) => field.name == "__schema" || field.name == "__type",
+            Selection::InlineFragment(inline) => {
+                selects_introspection(&inline.selection_set, fragments, visited)
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.fragment_name.as_str();
+                match fragments.get(name) {
+                    Some(fragment) if visited.insert(name) => {
+                        selects_introspection(fragment, fragments, visited)
+                    }
+                    _ => false,
+                }
+            }
+        };
+        if selects {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(query: &str) -> GraphQLPayload {
+        serde_json::from_value(serde_json::json!({ "query": query })).unwrap()
+    }
+
+    #[test]
+    fn finds_introspection_in_fields() {
+        assert!(payload("{ __schema { types { name } } }").is_introspection());
+        assert!(payload("{ __type(name: \"Pet\") { name } }").is_introspection());
+        assert!(!payload("{ pets { items { name } } }").is_introspection());
+    }
+
+    #[test]
+    fn finds_introspection_in_inline_fragments() {
+        assert!(payload("{ ... { __schema { types { name } } } }").is_introspection());
+        assert!(
+            payload("{ ... on Query { ... { __type(name: \"Pet\") { name } } } }")
+                .is_introspection()
+        );
+    }
+
+    #[test]
+    fn finds_introspection_in_named_fragments() {
+        let query = "
+            query { ...A }
+            fragment A on Query { ...B }
+            fragment B on Query { __schema { types { name } } }
+        ";
+        assert!(payload(query).is_introspection());
+    }
+
+    #[test]
+    fn stops_at_fragments_that_spread_each_other() {
+        let query = "
+            query { ...A }
+            fragment A on Query { ...B }
+            fragment B on Query { ...A }
+        ";
+        assert!(!payload(query).is_introspection());
+        let query = "
+            query { ...A }
+            fragment A on Query { ...B }
+            fragment B on Query { ...A __schema { types { name } } }
+        ";
+        assert!(payload(query).is_introspection());
+    }
+}
@@ -1,21 +1,184 @@
-use actix_web::{HttpResponse, Responder};
+use crate::config::{HealthConfig, MongoConfig};
+use crate::db::mongo;
 
-pub async fn get_health() -> impl Responder {
-    HttpResponse::Ok().body("OK")
+use actix_web::{web, HttpResponse, Responder};
+use futures::channel::oneshot;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Set by the build script
+const GIT_SHA: &str = env!("GIT_SHA");
+
+/// Probes waiting for the checker, the next ones report the dependencies down
+const QUEUED_PROBES: usize = 64;
+
+type Probe = oneshot::Sender<Result<(), String>>;
+
+/// How long the health checks wait for the dependencies and when the service started
+pub struct HealthChecks {
+    timeout: Duration,
+    started: Instant,
+    /// The probes of the checker thread
+    probes: Mutex<SyncSender<Probe>>,
+}
+
+impl HealthChecks {
+    /// Starts the thread that pings MongoDB for the probes, a single one so a database that
+    /// hangs holds up that thread and not one per probe
+    pub fn start(config: &HealthConfig, mongo_config: &MongoConfig) -> Self {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let database = mongo::connect_for_health(mongo_config, timeout);
+        let (probes, receiver) = sync_channel(QUEUED_PROBES);
+        thread::Builder::new()
+            .name("health-checks".to_owned())
+            .spawn(move || run_checks(receiver, || mongo::ping(&database)))
+            .expect("unable to start the health checks");
+        HealthChecks {
+            timeout,
+            started: Instant::now(),
+            probes: Mutex::new(probes),
+        }
+    }
+}
+
+/// Answers the probes that arrived while a ping ran with the result of the next one
+fn run_checks<E: ToString>(receiver: Receiver<Probe>, ping: impl Fn() -> Result<(), E>) {
+    while let Ok(probe) = receiver.recv() {
+        let mut waiting = vec![probe];
+        waiting.extend(receiver.try_iter());
+        let result = ping().map_err(|e| e.to_string());
+        for probe in waiting {
+            let _ = probe.send(result.clone());
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Serialize)]
+struct ComponentHealth {
+    status: Status,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    status: Status,
+    version: &'static str,
+    git_sha: &'static str,
+    uptime_seconds: u64,
+    components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+/// Reports the status and latency of every dependency. It's a 200 as long as the server
+/// answers so a liveness probe doesn't restart it for a database that's down, `readiness`
+/// takes it out of the load balancer instead.
+pub async fn get_health(checks: web::Data<Arc<HealthChecks>>) -> impl Responder {
+    let components = check_components(&checks).await;
+    HttpResponse::Ok().json(HealthReport {
+        status: overall_status(&components),
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: GIT_SHA,
+        uptime_seconds: checks.started.elapsed().as_secs(),
+        components,
+    })
 }
 
 pub async fn pong() -> impl Responder {
     HttpResponse::Ok().body("pong")
 }
 
-pub async fn readiness() -> impl Responder {
-    let process = std::process::Command::new("sh")
-        .arg("-c")
-        .arg("echo hello")
-        .output();
+/// Whether the service can serve traffic, a 503 takes it out of the load balancer
+pub async fn readiness(checks: web::Data<Arc<HealthChecks>>) -> impl Responder {
+    let components = check_components(&checks).await;
+    match overall_status(&components) {
+        Status::Up => HttpResponse::Ok().body("OK"),
+        Status::Down => {
+            let down: Vec<&str> = components
+                .iter()
+                .filter(|(_, component)| component.status == Status::Down)
+                .map(|(name, _)| *name)
+                .collect();
+            HttpResponse::ServiceUnavailable().body(format!("unavailable: {}", down.join(", ")))
+        }
+    }
+}
+
+async fn check_components(checks: &HealthChecks) -> BTreeMap<&'static str, ComponentHealth> {
+    let mut components = BTreeMap::new();
+    components.insert("mongo", check(checks).await);
+    components
+}
+
+fn overall_status(components: &BTreeMap<&'static str, ComponentHealth>) -> Status {
+    if components.values().all(|c| c.status == Status::Up) {
+        Status::Up
+    } else {
+        Status::Down
+    }
+}
+
+/// Waits for the checker thread to ping the dependencies, and gives up after the timeout
+async fn check(checks: &HealthChecks) -> ComponentHealth {
+    let started = Instant::now();
+    let (sender, receiver) = oneshot::channel();
+    let sent = checks.probes.lock().unwrap().try_send(sender);
+    let result = match sent {
+        Ok(()) => match actix_rt::time::timeout(checks.timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("the check panicked".to_owned()),
+            Err(_) => Err(format!("timed out after {}ms", checks.timeout.as_millis())),
+        },
+        Err(TrySendError::Full(_)) => Err("too many checks are waiting".to_owned()),
+        Err(TrySendError::Disconnected(_)) => Err("the checks stopped".to_owned()),
+    };
+    ComponentHealth {
+        status: if result.is_ok() {
+            Status::Up
+        } else {
+            Status::Down
+        },
+        latency_ms: started.elapsed().as_millis() as u64,
+        error: result.err(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    match process {
-        Ok(_) => HttpResponse::Accepted(),
-        Err(_) => HttpResponse::InternalServerError(),
+    #[test]
+    fn probes_waiting_for_a_ping_share_the_next_one() {
+        let (probes, receiver) = sync_channel(QUEUED_PROBES);
+        let answers: Vec<_> = (0..3)
+            .map(|_| {
+                let (sender, answer) = oneshot::channel();
+                probes.send(sender).unwrap();
+                answer
+            })
+            .collect();
+        drop(probes);
+        let pings = AtomicUsize::new(0);
+        run_checks(receiver, || {
+            pings.fetch_add(1, Ordering::SeqCst);
+            Err("connection refused")
+        });
+        assert_eq!(pings.load(Ordering::SeqCst), 1);
+        for answer in answers {
+            let result = futures::executor::block_on(answer).unwrap();
+            assert_eq!(result, Err("connection refused".to_owned()));
+        }
     }
 }
@@ -0,0 +1,146 @@
+use crate::db::Clients;
+use crate::import::{self, Collection, ImportError, ImportFormat};
+use crate::routes::common::{traced_block, ApiError};
+
+use actix_web::error::PayloadError;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{HttpRequest, HttpResponse};
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Largest file accepted by the import routes
+const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// Query params of the import routes
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    /// `csv` or `ndjson`, defaults to the `Content-Type` header and then csv
+    format: Option<String>,
+    /// Only validates the rows
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl ImportParams {
+    fn format(&self, req: &HttpRequest) -> Result<ImportFormat, ApiError> {
+        match self.format.as_deref() {
+            Some(format) => format.parse().map_err(ApiError::BadRequest),
+            None => {
+                let content_type = req
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.split(';').next())
+                    .map(str::trim);
+                match content_type {
+                    Some("application/x-ndjson") => Ok(ImportFormat::Ndjson),
+                    _ => Ok(ImportFormat::Csv),
+                }
+            }
+        }
+    }
+}
+
+impl From<ImportError> for ApiError {
+    fn from(e: ImportError) -> ApiError {
+        match e {
+            ImportError::InvalidFile(_) => ApiError::BadRequest(e.to_string()),
+            ImportError::Service(e) => ApiError::from(e),
+        }
+    }
+}
+
+pub async fn import_pets(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    params: web::Query<ImportParams>,
+    payload: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    run_import(req, clients, params, payload, Collection::Pets).await
+}
+
+pub async fn import_owners(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    params: web::Query<ImportParams>,
+    payload: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    run_import(req, clients, params, payload, Collection::Owners).await
+}
+
+async fn run_import(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    params: web::Query<ImportParams>,
+    payload: web::Payload,
+    collection: Collection,
+) -> Result<HttpResponse, ApiError> {
+    let format = params.format(&req)?;
+    let dry_run = params.dry_run;
+    let body = read_body(payload).await?;
+    let clients = clients.get_ref().clone();
+    let report = traced_block(&req, move || {
+        import::import(&clients, collection, format, &body, dry_run).map_err(ApiError::from)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Reads the whole body of any stream of chunks, `web::Payload` in the routes
+async fn read_body<S>(mut payload: S) -> Result<BytesMut, ApiError>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        if body.len() + chunk.len() > MAX_UPLOAD_SIZE {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "Imports are limited to {} bytes",
+                MAX_UPLOAD_SIZE
+            )));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream;
+
+    fn chunks(sizes: &[usize]) -> impl Stream<Item = Result<Bytes, PayloadError>> + Unpin {
+        let chunks: Vec<_> = sizes
+            .iter()
+            .map(|size| Ok(Bytes::from(vec![b'a'; *size])))
+            .collect();
+        stream::iter(chunks)
+    }
+
+    #[test]
+    fn reads_a_body_up_to_the_limit() {
+        let half = MAX_UPLOAD_SIZE / 2;
+        let body = block_on(read_body(chunks(&[half, MAX_UPLOAD_SIZE - half]))).unwrap();
+        assert_eq!(body.len(), MAX_UPLOAD_SIZE);
+    }
+
+    #[test]
+    fn rejects_a_body_over_the_limit() {
+        let result = block_on(read_body(chunks(&[MAX_UPLOAD_SIZE, 1])));
+        assert!(matches!(result, Err(ApiError::PayloadTooLarge(_))));
+    }
+
+    #[test]
+    fn a_broken_payload_is_a_bad_request() {
+        let broken = stream::iter(vec![
+            Ok(Bytes::from("a")),
+            Err(PayloadError::Incomplete(None)),
+        ]);
+        let result = block_on(read_body(broken));
+        assert!(matches!(result, Err(ApiError::BadRequest(_))));
+    }
+}
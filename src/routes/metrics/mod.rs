@@ -0,0 +1,84 @@
+use crate::metrics;
+use crate::telemetry::{self, SpanContext};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse};
+use std::future::Future;
+use std::time::Instant;
+
+pub async fn get_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+/// Middleware that traces requests and records their count and duration by route and status
+///
+/// The context of the request's span is kept in its extensions for the handlers and sent
+/// back in `traceparent`.
+pub fn track_requests<S, B>(
+    req: ServiceRequest,
+    service: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let parent = req
+        .headers()
+        .get("traceparent")
+        .and_then(|value| value.to_str().ok())
+        .and_then(SpanContext::from_traceparent);
+    let mut span = telemetry::request_span(method.clone(), parent);
+    span.set_attribute("http.method", method.as_str());
+    span.set_attribute("http.target", req.path());
+    if let Some(context) = span.context() {
+        req.extensions_mut().insert(context);
+    }
+    let response = service.call(req);
+    async move {
+        let mut response = response.await?;
+        let status = response.status();
+        let route = route(response.request(), status);
+        metrics::observe_request(&method, &route, status.as_u16(), started.elapsed());
+        span.set_name(format!("{} {}", method, route));
+        span.set_attribute("http.route", route.as_str());
+        span.set_attribute("http.status_code", status.as_u16());
+        if status.is_server_error() {
+            span.set_error(status.to_string());
+        }
+        if let Some(context) = span.context() {
+            if let Ok(value) = HeaderValue::from_str(&context.traceparent()) {
+                let name = HeaderName::from_static("traceparent");
+                response.headers_mut().insert(name, value);
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// The path with the params of the route replaced by their names, e.g. `/pets/{id}`, and
+/// paths that don't match a route grouped together so they can't add labels
+fn route(req: &HttpRequest, status: StatusCode) -> String {
+    let path = req.path();
+    // the resource map doesn't match the root route of a scope, e.g. `/pets`, but only
+    // paths without a route end up as a 404
+    if status == StatusCode::NOT_FOUND && !req.resource_map().has_resource(path) {
+        return "unmatched".to_owned();
+    }
+    let mut params = req.match_info().iter().peekable();
+    path.split('/')
+        .map(|segment| match params.peek() {
+            Some((name, value)) if *value == segment => {
+                let name = format!("{{{}}}", name);
+                params.next();
+                name
+            }
+            _ => segment.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
@@ -1,27 +1,309 @@
+mod access_log;
+mod common;
+mod export;
 mod graphql;
 mod health;
+mod import;
+mod metrics;
+mod openapi;
+mod owners;
 mod pets;
+mod request_id;
 
+use actix_web::http::Method;
 use actix_web::{web, HttpResponse};
-use graphql::{graphiql, graphql};
+use graphql::{graphiql, graphql, graphql_get};
 use health::{get_health, pong, readiness};
+use metrics::get_metrics;
+use openapi::{openapi_json, swagger_ui};
+
+pub use access_log::log_requests;
+pub use common::{json_config, query_config};
+pub use graphql::{GraphQLOptions, PersistedQueryStore};
+pub use health::HealthChecks;
+pub use metrics::track_requests;
+pub use request_id::assign_request_id;
+
+/// An operation of the REST api, handled by `app_routes` and documented by
+/// `openapi::document`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RestOperation {
+    ListPets,
+    CreatePet,
+    ExportPets,
+    ImportPets,
+    GetPet,
+    UpdatePet,
+    DeletePet,
+    ListOwners,
+    CreateOwner,
+    ExportOwners,
+    ImportOwners,
+    GetOwner,
+    UpdateOwner,
+    DeleteOwner,
+    ListOwnerPets,
+}
+
+/// The REST routes, the fixed paths come before the `{id}` that would match them
+const REST_ROUTES: &[(Method, &str, RestOperation)] = &[
+    (Method::GET, "/pets", RestOperation::ListPets),
+    (Method::POST, "/pets", RestOperation::CreatePet),
+    (Method::GET, "/pets/export", RestOperation::ExportPets),
+    (Method::POST, "/pets/import", RestOperation::ImportPets),
+    (Method::GET, "/pets/{id}", RestOperation::GetPet),
+    (Method::PATCH, "/pets/{id}", RestOperation::UpdatePet),
+    (Method::DELETE, "/pets/{id}", RestOperation::DeletePet),
+    (Method::GET, "/owners", RestOperation::ListOwners),
+    (Method::POST, "/owners", RestOperation::CreateOwner),
+    (Method::GET, "/owners/export", RestOperation::ExportOwners),
+    (Method::POST, "/owners/import", RestOperation::ImportOwners),
+    (Method::GET, "/owners/{id}", RestOperation::GetOwner),
+    (Method::PATCH, "/owners/{id}", RestOperation::UpdateOwner),
+    (Method::DELETE, "/owners/{id}", RestOperation::DeleteOwner),
+    (
+        Method::GET,
+        "/owners/{id}/pets",
+        RestOperation::ListOwnerPets,
+    ),
+];
 
 pub fn app_routes(config: &mut web::ServiceConfig) {
+    for (method, path, operation) in REST_ROUTES {
+        let route = web::method(method.clone());
+        let route = match operation {
+            RestOperation::ListPets => route.to(pets::all_pets),
+            RestOperation::CreatePet => route.to(pets::create_pet),
+            RestOperation::ExportPets => route.to(export::export_pets),
+            RestOperation::ImportPets => route.to(import::import_pets),
+            RestOperation::GetPet => route.to(pets::get_pet),
+            RestOperation::UpdatePet => route.to(pets::update_pet),
+            RestOperation::DeletePet => route.to(pets::delete_pet),
+            RestOperation::ListOwners => route.to(owners::all_owners),
+            RestOperation::CreateOwner => route.to(owners::create_owner),
+            RestOperation::ExportOwners => route.to(export::export_owners),
+            RestOperation::ImportOwners => route.to(import::import_owners),
+            RestOperation::GetOwner => route.to(owners::get_owner),
+            RestOperation::UpdateOwner => route.to(owners::update_owner),
+            RestOperation::DeleteOwner => route.to(owners::delete_owner),
+            RestOperation::ListOwnerPets => route.to(owners::owner_pets),
+        };
+        config.route(path, route);
+    }
     config
-        .service(
-            web::scope("pets")
-                // GET
-                .route("", web::get().to(crate::routes::pets::all_pets))
-                // POST
-                .route("", web::post().to(crate::routes::pets::all_pets)),
-        )
         .service(
             web::scope("/")
                 .route("ping", web::get().to(pong))
                 .route("~/ready", web::get().to(readiness))
                 .route("health", web::get().to(get_health))
+                .route("metrics", web::get().to(get_metrics))
                 .route("graphql", web::post().to(graphql))
-                .route("graphiql", web::get().to(graphiql)),
+                .route("graphql", web::get().to(graphql_get))
+                .route("graphiql", web::get().to(graphiql))
+                .route("openapi.json", web::get().to(openapi_json))
+                .route("swagger", web::get().to(swagger_ui)),
         )
         .route("", web::get().to(|| HttpResponse::NotFound()));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::QueryCache;
+    use crate::config::{CacheConfig, IndexSync, MongoConfig, SlowQueriesConfig};
+    use crate::db::{self, Clients};
+    use crate::models;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use serde_json::{json, Value};
+    use std::env;
+    use std::sync::Arc;
+
+    fn connect(url: &str, db_name: &str, indexes: IndexSync) -> Arc<Clients> {
+        let config = MongoConfig {
+            url: url.to_owned(),
+            db_name: db_name.to_owned(),
+            indexes,
+        };
+        let (database, mongo) = db::mongo::connect(&config, &SlowQueriesConfig::default());
+        db::indexes::sync_on_startup(&database, &models::indexes(), config.indexes);
+        Arc::new(Clients {
+            database,
+            mongo,
+            cache: Arc::new(QueryCache::from_config(&CacheConfig::default())),
+            request_id: None,
+        })
+    }
+
+    /// A database of its own for every test on the MongoDB of `MONGO_URL`, with the indexes of
+    /// the models so usernames are unique
+    fn clients(test: &str) -> Arc<Clients> {
+        let url = env::var("MONGO_URL").unwrap_or_else(|_| "mongodb://localhost:27017".to_owned());
+        let db_name = format!("test_{}_{}", test, std::process::id());
+        let clients = connect(&url, &db_name, IndexSync::Off);
+        clients.database.drop(None).unwrap();
+        connect(&url, &db_name, IndexSync::Create)
+    }
+
+    /// Clients of a MongoDB that isn't there, the driver only connects on the first command
+    fn clients_without_database() -> Arc<Clients> {
+        connect("mongodb://localhost:1", "test", IndexSync::Off)
+    }
+
+    macro_rules! app {
+        ($clients:expr) => {
+            test::init_service(
+                App::new()
+                    .data($clients.clone())
+                    .app_data(json_config())
+                    .app_data(query_config())
+                    .configure(app_routes),
+            )
+            .await
+        };
+    }
+
+    fn location<B>(response: &ServiceResponse<B>) -> String {
+        let location = response.headers().get(header::LOCATION).unwrap();
+        location.to_str().unwrap().to_owned()
+    }
+
+    fn owner(username: &str) -> Value {
+        json!({ "username": username, "first_name": "John", "last_name": "Smith", "gender": "Male" })
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a MongoDB at MONGO_URL"]
+    async fn creates_reads_and_deletes_a_pet() {
+        let clients = clients("pet_crud");
+        let mut app = app!(clients);
+
+        let req = TestRequest::post()
+            .uri("/owners")
+            .set_json(&owner("jsmith"));
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let owner_location = location(&response);
+        // the plain hex of the owner's ObjectId, as `normalize_id` reads it
+        let owner_id = owner_location.trim_start_matches("/owners/").to_owned();
+        assert_eq!(owner_id.len(), 24);
+
+        let pet = json!({ "name": "Fido", "pet_type": "Dog", "age": 3, "gender": "Male", "owner": owner_id });
+        let req = TestRequest::post().uri("/pets").set_json(&pet);
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let pet_location = location(&response);
+        assert!(pet_location.starts_with("/pets/"));
+
+        let req = TestRequest::get().uri(&format!("/owners/{}/pets", owner_id));
+        let pets: Value = test::read_response_json(&mut app, req.to_request()).await;
+        assert_eq!(pets["total_count"], 1);
+        assert_eq!(pets["items"][0]["name"], "Fido");
+
+        let req = TestRequest::get().uri(&pet_location);
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let req = TestRequest::delete().uri(&pet_location);
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        for method in &[Method::GET, Method::DELETE] {
+            let req = TestRequest::default()
+                .method(method.clone())
+                .uri(&pet_location);
+            let response = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+            assert_eq!(body["error"]["code"], "NOT_FOUND");
+        }
+        let _ = clients.database.drop(None);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a MongoDB at MONGO_URL"]
+    async fn rejects_a_pet_of_an_unknown_owner() {
+        let clients = clients("unknown_owner");
+        let mut app = app!(clients);
+
+        let pet = json!({ "name": "Fido", "pet_type": "Dog", "gender": "Male", "owner": "5f5d4c3b2a19080706050403" });
+        let req = TestRequest::post().uri("/pets").set_json(&pet);
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::get().uri("/pets");
+        let pets: Value = test::read_response_json(&mut app, req.to_request()).await;
+        assert_eq!(pets["total_count"], 0);
+        let _ = clients.database.drop(None);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a MongoDB at MONGO_URL"]
+    async fn rejects_a_duplicate_username() {
+        let clients = clients("duplicate_username");
+        let mut app = app!(clients);
+
+        let req = TestRequest::post()
+            .uri("/owners")
+            .set_json(&owner("jsmith"));
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let req = TestRequest::post()
+            .uri("/owners")
+            .set_json(&owner("jsmith"));
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(body["error"]["code"], "CONFLICT");
+        let _ = clients.database.drop(None);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_an_invalid_body_before_reaching_the_database() {
+        // never connects, the body is rejected first
+        let clients = clients_without_database();
+        let mut app = app!(clients);
+
+        let pet = json!({ "name": "Fido", "pet_type": "Dragon", "gender": "Male" });
+        let req = TestRequest::post().uri("/pets").set_json(&pet);
+        let response = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(body["error"]["code"], "BAD_REQUEST");
+    }
+
+    #[actix_rt::test]
+    async fn the_request_id_reaches_the_graphql_errors() {
+        let config = crate::config::Config::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(Arc::new(crate::schema::create_schema()))
+                .data(clients_without_database())
+                .data(Arc::new(PersistedQueryStore::from_config(
+                    &config.persisted_queries,
+                )))
+                .data(Arc::new(GraphQLOptions::from_config(&config)))
+                .app_data(json_config())
+                .wrap_fn(assign_request_id)
+                .configure(app_routes),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/graphql")
+            .header("X-Request-Id", "req-graphql")
+            .set_json(&json!({ "query": "{ allPets(" }));
+        let response = test::call_service(&mut app, req.to_request()).await;
+        let header = response.headers().get("x-request-id").unwrap();
+        assert_eq!(header.to_str().unwrap(), "req-graphql");
+        let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let errors = body["errors"].as_array().unwrap();
+        assert!(!errors.is_empty());
+        for error in errors {
+            assert_eq!(error["extensions"]["requestId"], "req-graphql");
+        }
+    }
+}
@@ -0,0 +1,534 @@
+use crate::import::ImportReport;
+use crate::models::{
+    NewOwner, NewPet, Owner, OwnerConnection, Pet, PetConnection, PetTypes, UpdateOwner, UpdatePet,
+};
+use crate::routes::common::MAX_LIMIT;
+use crate::routes::{RestOperation, REST_ROUTES};
+
+use actix_web::HttpResponse;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+const SWAGGER_UI_VERSION: &str = "3.25.0";
+
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(document())
+}
+
+pub async fn swagger_ui() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(swagger_ui_source("/openapi.json"))
+}
+
+/// Builds the OpenAPI 3 document of the REST routes of `REST_ROUTES`, the schemas are
+/// generated from the model types
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+
+    let pets = Resource::new::<Pet>(&mut gen, "pets", "pet");
+    let owners = Resource::new::<Owner>(&mut gen, "owners", "owner");
+    let pet_filters = vec![
+        query_parameter(
+            "pet_type",
+            &schema_ref::<PetTypes>(&mut gen),
+            "Only pets of the type",
+        ),
+        query_parameter(
+            "owner",
+            &json!({ "type": "string" }),
+            "Only pets of the owner with the id",
+        ),
+    ];
+    let import_report = schema_ref::<ImportReport>(&mut gen);
+    for (method, path, rest_operation) in REST_ROUTES {
+        let operation = match rest_operation {
+            RestOperation::ListPets => {
+                pets.list(&schema_ref::<PetConnection>(&mut gen), pet_filters.clone())
+            }
+            RestOperation::CreatePet => pets.create(&schema_ref::<NewPet>(&mut gen)),
+            RestOperation::ExportPets => {
+                let mut parameters = pet_filters.clone();
+                parameters.push(query_parameter(
+                    "include_owner",
+                    &json!({ "type": "boolean" }),
+                    "Adds the fields of the owner to every pet",
+                ));
+                pets.export(parameters)
+            }
+            RestOperation::ImportPets => pets.import(&import_report),
+            RestOperation::GetPet => pets.get(),
+            RestOperation::UpdatePet => pets.update(&schema_ref::<UpdatePet>(&mut gen)),
+            RestOperation::DeletePet => pets.delete(),
+            RestOperation::ListOwners => {
+                owners.list(&schema_ref::<OwnerConnection>(&mut gen), vec![])
+            }
+            RestOperation::CreateOwner => owners.create(&schema_ref::<NewOwner>(&mut gen)),
+            RestOperation::ExportOwners => owners.export(vec![]),
+            RestOperation::ImportOwners => owners.import(&import_report),
+            RestOperation::GetOwner => owners.get(),
+            RestOperation::UpdateOwner => owners.update(&schema_ref::<UpdateOwner>(&mut gen)),
+            RestOperation::DeleteOwner => owners.delete(),
+            RestOperation::ListOwnerPets => operation(
+                "owners",
+                "listOwnerPets",
+                "List the pets of an owner",
+                pagination_parameters()
+                    .into_iter()
+                    .chain(vec![if_none_match_parameter()])
+                    .collect(),
+                json!({
+                    "200": page_response(
+                        "The pets of the owner",
+                        &schema_ref::<PetConnection>(&mut gen),
+                    ),
+                    "304": not_modified_response(),
+                    "400": error_response("Invalid pagination params"),
+                    "404": error_response("The owner doesn't exist"),
+                }),
+            ),
+        };
+        let item = paths.entry(path.to_string()).or_insert_with(|| json!({}));
+        if path.contains("{id}") {
+            item["parameters"] = json!([id_parameter()]);
+        }
+        item[method.as_str().to_lowercase()] = operation;
+    }
+
+    let mut schemas = gen.take_definitions();
+    for visitor in gen.visitors_mut() {
+        for schema in schemas.values_mut() {
+            visitor.visit_schema(schema);
+        }
+    }
+    let mut schemas = serde_json::to_value(schemas).unwrap();
+    schemas["Error"] = json!({
+        "type": "object",
+        "properties": {
+            "error": {
+                "type": "object",
+                "properties": {
+                    "code": { "type": "string" },
+                    "message": { "type": "string" },
+                },
+                "required": ["code", "message"],
+            },
+        },
+        "required": ["error"],
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "description": env!("CARGO_PKG_DESCRIPTION").trim(),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": { "schemas": schemas },
+    })
+}
+
+/// The operations of a collection with the same shape of routes
+struct Resource {
+    tag: &'static str,
+    name: &'static str,
+    item: Value,
+}
+
+impl Resource {
+    fn new<T: JsonSchema>(
+        gen: &mut SchemaGenerator,
+        tag: &'static str,
+        name: &'static str,
+    ) -> Self {
+        Resource {
+            tag,
+            name,
+            item: schema_ref::<T>(gen),
+        }
+    }
+
+    fn list(&self, connection: &Value, filters: Vec<Value>) -> Value {
+        let mut response = page_response(&format!("A page of {}", self.tag), connection);
+        // an `Accept` of csv or ndjson returns the export instead
+        response["content"]["text/csv"] = json!({ "schema": { "type": "string" } });
+        response["content"]["application/x-ndjson"] = json!({ "schema": { "type": "string" } });
+        operation(
+            self.tag,
+            &format!("list{}", capitalize(self.tag)),
+            &format!("List {}", self.tag),
+            filters
+                .into_iter()
+                .chain(pagination_parameters())
+                .chain(vec![if_none_match_parameter()])
+                .collect(),
+            json!({
+                "200": response,
+                "304": not_modified_response(),
+                "400": error_response("Invalid pagination params"),
+            }),
+        )
+    }
+
+    fn export(&self, mut parameters: Vec<Value>) -> Value {
+        parameters.push(query_parameter(
+            "format",
+            &json!({ "type": "string", "enum": ["csv", "ndjson"] }),
+            "Defaults to the Accept header and then csv",
+        ));
+        operation(
+            self.tag,
+            &format!("export{}", capitalize(self.tag)),
+            &format!("Export every {} as csv or ndjson", self.name),
+            parameters,
+            json!({
+                "200": {
+                    "description": format!("Every {}, streamed as an attachment", self.name),
+                    "content": {
+                        "text/csv": { "schema": { "type": "string" } },
+                        "application/x-ndjson": { "schema": { "type": "string" } },
+                    },
+                },
+                "400": error_response("Invalid format or filters"),
+            }),
+        )
+    }
+
+    fn create(&self, body: &Value) -> Value {
+        let mut operation = operation(
+            self.tag,
+            &format!("create{}", capitalize(self.name)),
+            &format!("Create a {}", self.name),
+            vec![],
+            json!({
+                "201": resource_response(&format!("The created {}", self.name), &self.item),
+                "400": error_response("Invalid body"),
+                "409": error_response("Conflicts with an existing document"),
+            }),
+        );
+        operation["requestBody"] = request_body(body);
+        operation
+    }
+
+    fn import(&self, report: &Value) -> Value {
+        let parameters = vec![
+            query_parameter(
+                "format",
+                &json!({ "type": "string", "enum": ["csv", "ndjson"] }),
+                "Defaults to the Content-Type header and then csv",
+            ),
+            query_parameter(
+                "dry_run",
+                &json!({ "type": "boolean" }),
+                "Only validates the rows",
+            ),
+        ];
+        let mut operation = operation(
+            self.tag,
+            &format!("import{}", capitalize(self.tag)),
+            &format!("Import {} from csv or ndjson", self.tag),
+            parameters,
+            json!({
+                "200": json_response("The number of inserted rows and the row errors", report),
+                "400": error_response("Invalid format or file"),
+                "413": error_response("The file is too large"),
+            }),
+        );
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": {
+                "text/csv": { "schema": { "type": "string" } },
+                "application/x-ndjson": { "schema": { "type": "string" } },
+            },
+        });
+        operation
+    }
+
+    fn get(&self) -> Value {
+        operation(
+            self.tag,
+            &format!("get{}", capitalize(self.name)),
+            &format!("Get a {} by id", self.name),
+            vec![
+                if_none_match_parameter(),
+                header_parameter(
+                    "If-Modified-Since",
+                    "Only returns the document if it was modified after the date, ignored \
+                     with If-None-Match",
+                ),
+            ],
+            json!({
+                "200": resource_response(&format!("The {}", self.name), &self.item),
+                "304": not_modified_response(),
+                "404": error_response(&format!("The {} doesn't exist", self.name)),
+            }),
+        )
+    }
+
+    fn update(&self, body: &Value) -> Value {
+        let mut operation = operation(
+            self.tag,
+            &format!("update{}", capitalize(self.name)),
+            &format!("Update the given fields of a {}", self.name),
+            vec![if_match_parameter()],
+            json!({
+                "200": resource_response(&format!("The updated {}", self.name), &self.item),
+                "400": error_response("Invalid body"),
+                "404": error_response(&format!("The {} doesn't exist", self.name)),
+                "409": error_response("Conflicts with an existing document"),
+                "412": error_response("The document has changed since it was fetched"),
+            }),
+        );
+        operation["requestBody"] = request_body(body);
+        operation
+    }
+
+    fn delete(&self) -> Value {
+        operation(
+            self.tag,
+            &format!("delete{}", capitalize(self.name)),
+            &format!("Delete a {}", self.name),
+            vec![if_match_parameter()],
+            json!({
+                "204": { "description": format!("The {} was deleted", self.name) },
+                "404": error_response(&format!("The {} doesn't exist", self.name)),
+                "412": error_response("The document has changed since it was fetched"),
+            }),
+        )
+    }
+}
+
+fn operation(
+    tag: &str,
+    operation_id: &str,
+    summary: &str,
+    parameters: Vec<Value>,
+    responses: Value,
+) -> Value {
+    let mut operation = json!({
+        "tags": [tag],
+        "operationId": operation_id,
+        "summary": summary,
+        "responses": responses,
+    });
+    if !parameters.is_empty() {
+        operation["parameters"] = Value::Array(parameters);
+    }
+    operation
+}
+
+fn schema_ref<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    let schema: Schema = gen.subschema_for::<T>();
+    serde_json::to_value(schema).unwrap()
+}
+
+fn id_parameter() -> Value {
+    json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": "The hex of an ObjectId or a string id",
+        "schema": { "type": "string" },
+    })
+}
+
+fn pagination_parameters() -> Vec<Value> {
+    let integer = json!({ "type": "integer" });
+    let string = json!({ "type": "string" });
+    vec![
+        query_parameter(
+            "limit",
+            &json!({ "type": "integer", "minimum": 1, "maximum": MAX_LIMIT }),
+            "Number of items to return, larger limits are lowered to the maximum",
+        ),
+        query_parameter("after", &string, "Cursor to return the items after"),
+        query_parameter("before", &string, "Cursor to return the items before"),
+        query_parameter("skip", &integer, "Number of items to skip"),
+    ]
+}
+
+fn query_parameter(name: &str, schema: &Value, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": schema,
+    })
+}
+
+fn header_parameter(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "header",
+        "required": false,
+        "description": description,
+        "schema": { "type": "string" },
+    })
+}
+
+fn if_none_match_parameter() -> Value {
+    header_parameter(
+        "If-None-Match",
+        "ETags of a cached copy, a 304 is returned when one of them is current",
+    )
+}
+
+fn if_match_parameter() -> Value {
+    header_parameter(
+        "If-Match",
+        "Only changes the document if its current ETag is one of these",
+    )
+}
+
+fn request_body(schema: &Value) -> Value {
+    json!({
+        "required": true,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+fn json_response(description: &str, schema: &Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+/// A response of a single document with its validators
+fn resource_response(description: &str, schema: &Value) -> Value {
+    let mut response = json_response(description, schema);
+    response["headers"] = json!({
+        "ETag": etag_header(),
+        "Last-Modified": {
+            "description": "When the document was last modified",
+            "schema": { "type": "string" },
+        },
+    });
+    response
+}
+
+fn page_response(description: &str, schema: &Value) -> Value {
+    let mut response = json_response(description, schema);
+    response["headers"] = json!({
+        "ETag": etag_header(),
+        "Link": {
+            "description": "Links to the next and previous pages",
+            "schema": { "type": "string" },
+        },
+        "X-Total-Count": {
+            "description": "Number of items in all pages",
+            "schema": { "type": "integer" },
+        },
+    });
+    response
+}
+
+fn etag_header() -> Value {
+    json!({
+        "description": "Hash of the body, for If-None-Match and If-Match",
+        "schema": { "type": "string" },
+    })
+}
+
+fn not_modified_response() -> Value {
+    json!({ "description": "The cached copy is still current" })
+}
+
+fn error_response(description: &str) -> Value {
+    json_response(
+        description,
+        &json!({ "$ref": "#/components/schemas/Error" }),
+    )
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn swagger_ui_source(spec_url: &str) -> String {
+    format!(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>{title}</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = function() {{
+      SwaggerUIBundle({{ url: "{url}", dom_id: "#swagger-ui" }});
+    }};
+  </script>
+</body>
+</html>
+"##,
+        title = env!("CARGO_PKG_NAME"),
+        version = SWAGGER_UI_VERSION,
+        url = spec_url,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn every_rest_route_has_an_operation() {
+        let routes: BTreeSet<_> = REST_ROUTES
+            .iter()
+            .map(|(method, path, _)| (method.as_str().to_lowercase(), path.to_string()))
+            .collect();
+        assert_eq!(routes.len(), REST_ROUTES.len(), "a route is declared twice");
+        let document = document();
+        let documented: BTreeSet<_> = document["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .filter(|key| *key != "parameters")
+                    .map(move |method| (method.clone(), path.clone()))
+            })
+            .collect();
+        assert_eq!(documented, routes);
+    }
+
+    #[test]
+    fn operation_ids_are_unique() {
+        let document = document();
+        let ids: Vec<&str> = document["paths"]
+            .as_object()
+            .unwrap()
+            .values()
+            .flat_map(|item| item.as_object().unwrap().iter())
+            .filter(|(key, _)| *key != "parameters")
+            .map(|(_, operation)| operation["operationId"].as_str().unwrap())
+            .collect();
+        let unique: BTreeSet<_> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len());
+        assert_eq!(ids.len(), REST_ROUTES.len());
+    }
+
+    #[test]
+    fn paths_with_an_id_take_it_as_a_parameter() {
+        let document = document();
+        let parameters = &document["paths"]["/owners/{id}/pets"]["parameters"];
+        assert_eq!(parameters[0]["name"], "id");
+        assert_eq!(parameters[0]["in"], "path");
+        assert!(document["paths"]["/owners"].get("parameters").is_none());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::cache::{collection_tag, document_tag};
+use crate::db::Clients;
+use crate::models::common::parse_id;
+use crate::models::{NewOwner, Owner, OwnerConnection, Pet, PetConnection, UpdateOwner};
+use crate::routes::common::{
+    conditional_response, delete_if_unchanged, find_if_match, if_match, page_response,
+    traced_block, update_if_unchanged, ApiError, Pagination,
+};
+use crate::routes::export::{self, ExportFormat};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use bson::doc;
+use mongodb_base_service::{BaseService, ServiceError, ID};
+use mongodb_cursor_pagination::FindResult;
+use std::sync::Arc;
+
+pub async fn all_owners(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    pagination: web::Query<Pagination>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    // csv and ndjson have every owner instead of a page
+    if let Some(format) = ExportFormat::from_accept(&req) {
+        return export::stream_owners(clients, format).await;
+    }
+    let pagination = pagination.into_inner();
+    let key = format!("{:?}", pagination);
+    let owners = traced_block(&req, move || {
+        let tags = vec![collection_tag("owners")];
+        clients
+            .cache
+            .get_or_insert_with("list_owners", &key, tags, || {
+                let service = clients.mongo.get_mongo_service("owners").unwrap();
+                let result: FindResult<Owner> = service.find(
+                    None,
+                    None,
+                    pagination.limit,
+                    pagination.after,
+                    pagination.before,
+                    pagination.skip,
+                )?;
+                Ok(OwnerConnection::from(result))
+            })
+    })
+    .await?;
+    Ok(page_response(
+        &req,
+        &owners.page_info,
+        owners.total_count,
+        &owners,
+    ))
+}
+
+pub async fn get_owner(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let id = parse_id(&id);
+    let owner = traced_block(&req, move || {
+        let key = id.to_string();
+        let tags = vec![document_tag("owners", &id)];
+        clients
+            .cache
+            .get_or_insert_with("get_owner", &key, tags, || find_owner(&clients, id))
+    })
+    .await?;
+    let last_modified = owner.node.date_modified();
+    Ok(conditional_response(
+        &req,
+        HttpResponse::Ok(),
+        &owner,
+        last_modified,
+    ))
+}
+
+pub async fn create_owner(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    new_owner: web::Json<NewOwner>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let owner = traced_block(&req, move || {
+        let new_owner = new_owner.into_inner();
+        check_username(&clients, &new_owner.username, None)?;
+        let service = clients.mongo.get_mongo_service("owners").unwrap();
+        let inserted_id: ID = service.insert_one(new_owner, None)?;
+        clients.cache.invalidate_collection("owners");
+        find_owner(&clients, inserted_id)
+    })
+    .await?;
+    let mut response = HttpResponse::Created();
+    response.header(header::LOCATION, format!("/owners/{}", owner.id));
+    let last_modified = owner.node.date_modified();
+    Ok(conditional_response(&req, response, &owner, last_modified))
+}
+
+pub async fn update_owner(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    id: web::Path<String>,
+    update_owner: web::Json<UpdateOwner>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let id = parse_id(&id);
+    let if_match = if_match(&req)?;
+    let owner = traced_block(&req, move || {
+        let service = clients.mongo.get_mongo_service("owners").unwrap();
+        let current = match &if_match {
+            Some(if_match) => Some(find_if_match::<Owner>(
+                service.data_source(),
+                &id,
+                if_match,
+                "Unable to find owner",
+            )?),
+            None => None,
+        };
+        let update_owner = update_owner.into_inner();
+        if let Some(username) = &update_owner.username {
+            check_username(&clients, username, Some(&id))?;
+        }
+        let result: Result<Owner, ApiError> = match &current {
+            Some(current) => update_if_unchanged(service.data_source(), current, &update_owner)
+                .and_then(|_| find_owner(&clients, id.clone())),
+            None => service
+                .update_one(id.clone(), update_owner, None)
+                .map_err(ApiError::from),
+        };
+        clients.cache.invalidate_document("owners", &id);
+        result
+    })
+    .await?;
+    let last_modified = owner.node.date_modified();
+    Ok(conditional_response(
+        &req,
+        HttpResponse::Ok(),
+        &owner,
+        last_modified,
+    ))
+}
+
+pub async fn delete_owner(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let id = parse_id(&id);
+    let if_match = if_match(&req)?;
+    traced_block(&req, move || {
+        let service = clients.mongo.get_mongo_service("owners").unwrap();
+        let result = match &if_match {
+            Some(if_match) => {
+                find_if_match::<Owner>(service.data_source(), &id, if_match, "Unable to find owner")
+                    .and_then(|current| delete_if_unchanged(service.data_source(), &current))
+            }
+            None => match service.delete_one_by_query(doc! { "_id": id.to_bson() }) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(ApiError::NotFound("Unable to find owner".to_owned())),
+                Err(e) => Err(e.into()),
+            },
+        };
+        clients.cache.invalidate_document("owners", &id);
+        result
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn owner_pets(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    id: web::Path<String>,
+    pagination: web::Query<Pagination>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let id = parse_id(&id);
+    let pagination = pagination.into_inner();
+    let key = format!("{},{:?}", id, pagination);
+    let pets = traced_block(&req, move || {
+        // the page depends on the owner existing and on the pets
+        let tags = vec![document_tag("owners", &id), collection_tag("pets")];
+        clients
+            .cache
+            .get_or_insert_with("list_owner_pets", &key, tags, || {
+                let owner = find_owner(&clients, id)?;
+                let service = clients.mongo.get_mongo_service("pets").unwrap();
+                let result: FindResult<Pet> = service.find(
+                    Some(doc! { "owner": owner.id.to_bson() }),
+                    None,
+                    pagination.limit,
+                    pagination.after,
+                    pagination.before,
+                    pagination.skip,
+                )?;
+                Ok(PetConnection::from(result))
+            })
+    })
+    .await?;
+    Ok(page_response(
+        &req,
+        &pets.page_info,
+        pets.total_count,
+        &pets,
+    ))
+}
+
+fn find_owner(clients: &Clients, id: ID) -> Result<Owner, ApiError> {
+    let service = clients.mongo.get_mongo_service("owners").unwrap();
+    let result: Result<Option<Owner>, ServiceError> = service.find_one_by_id(id);
+    match result? {
+        Some(owner) => Ok(owner),
+        None => Err(ApiError::NotFound("Unable to find owner".to_owned())),
+    }
+}
+
+/// Usernames are unique, `id` is the owner being updated which may keep its own username
+fn check_username(clients: &Clients, username: &str, id: Option<&ID>) -> Result<(), ApiError> {
+    let service = clients.mongo.get_mongo_service("owners").unwrap();
+    let existing = service
+        .data_source()
+        .find_one(Some(doc! { "username": username }), None)
+        .map_err(ServiceError::from)?;
+    match existing {
+        Some(existing) if id.map(|id| id.to_bson()).as_ref() != existing.get("_id") => Err(
+            ApiError::Conflict(format!("The username {} is already taken", username)),
+        ),
+        _ => Ok(()),
+    }
+}
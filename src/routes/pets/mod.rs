@@ -1,27 +1,223 @@
+use crate::cache::{collection_tag, document_tag};
 use crate::db::Clients;
-use crate::models::Pet;
+use crate::models::common::{normalize_id, parse_id};
+use crate::models::{NewPet, Owner, Pet, PetConnection, PetTypes, UpdatePet};
+use crate::routes::common::{
+    conditional_response, delete_if_unchanged, find_if_match, if_match, page_response,
+    traced_block, update_if_unchanged, ApiError, Pagination,
+};
+use crate::routes::export::{self, ExportFormat, ExportParams};
 
-use actix_web::{web, Responder};
-use cached::TimedCache;
-use mongodb_base_service::BaseService;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use bson::{doc, Document};
+use mongodb_base_service::{BaseService, ServiceError, ID};
 use mongodb_cursor_pagination::FindResult;
+use serde::Deserialize;
 use std::sync::Arc;
 
-pub async fn all_pets(clients: web::Data<Arc<Clients>>) -> impl Responder {
-    cached_key! {
-        ALL_PETS: TimedCache<String, Vec<Pet>> =
-            TimedCache::with_lifespan_and_capacity(10, 10000);
-        Key = { format!("hey") };
-        fn build(
-            clients: &Clients
-        ) -> Vec<Pet> = {
-            let service = clients.mongo.get_mongo_service("pets").unwrap();
-            let result: FindResult<Pet> = service
-                .find(None, None, None, None, None, None)
-                .expect("Received data");
-            result.items
+/// Query params that filter the pets list and export
+#[derive(Debug, Deserialize)]
+pub struct PetFilters {
+    pet_type: Option<PetTypes>,
+    owner: Option<String>,
+}
+
+impl PetFilters {
+    pub fn to_document(&self) -> Option<Document> {
+        let mut filter = Document::new();
+        if let Some(pet_type) = self.pet_type {
+            filter.insert("pet_type", format!("{:?}", pet_type));
+        }
+        if let Some(owner) = &self.owner {
+            filter.insert("owner", parse_id(owner).to_bson());
         }
+        if filter.is_empty() {
+            None
+        } else {
+            Some(filter)
+        }
+    }
+}
+
+pub async fn all_pets(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    filters: web::Query<PetFilters>,
+    pagination: web::Query<Pagination>,
+    export: web::Query<ExportParams>,
+) -> Result<HttpResponse, ApiError> {
+    // csv and ndjson have every pet matching the filters instead of a page
+    if let Some(format) = ExportFormat::from_accept(&req) {
+        let clients = clients.get_ref().clone();
+        let filters = filters.into_inner();
+        return export::stream_pets(clients, filters, format, export.include_owner).await;
+    }
+    let clients = clients.get_ref().clone();
+    let filters = filters.into_inner();
+    let pagination = pagination.into_inner();
+    let key = format!("{:?},{:?}", filters, pagination);
+    let pets = traced_block(&req, move || {
+        let tags = vec![collection_tag("pets")];
+        clients
+            .cache
+            .get_or_insert_with("list_pets", &key, tags, || {
+                let service = clients.mongo.get_mongo_service("pets").unwrap();
+                let result: FindResult<Pet> = service.find(
+                    filters.to_document(),
+                    None,
+                    pagination.limit,
+                    pagination.after,
+                    pagination.before,
+                    pagination.skip,
+                )?;
+                Ok(PetConnection::from(result))
+            })
+    })
+    .await?;
+    Ok(page_response(
+        &req,
+        &pets.page_info,
+        pets.total_count,
+        &pets,
+    ))
+}
+
+pub async fn get_pet(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let id = parse_id(&id);
+    let pet = traced_block(&req, move || {
+        let key = id.to_string();
+        let tags = vec![document_tag("pets", &id)];
+        clients
+            .cache
+            .get_or_insert_with("get_pet", &key, tags, || find_pet(&clients, id))
+    })
+    .await?;
+    let last_modified = pet.node.date_modified();
+    Ok(conditional_response(
+        &req,
+        HttpResponse::Ok(),
+        &pet,
+        last_modified,
+    ))
+}
+
+pub async fn create_pet(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    new_pet: web::Json<NewPet>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let pet = traced_block(&req, move || {
+        let mut new_pet = new_pet.into_inner();
+        new_pet.owner = new_pet.owner.map(normalize_id);
+        check_owner(&clients, &new_pet.owner)?;
+        let service = clients.mongo.get_mongo_service("pets").unwrap();
+        let inserted_id: ID = service.insert_one(new_pet, None)?;
+        clients.cache.invalidate_collection("pets");
+        find_pet(&clients, inserted_id)
+    })
+    .await?;
+    let mut response = HttpResponse::Created();
+    response.header(header::LOCATION, format!("/pets/{}", pet.id));
+    let last_modified = pet.node.date_modified();
+    Ok(conditional_response(&req, response, &pet, last_modified))
+}
+
+pub async fn update_pet(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    id: web::Path<String>,
+    update_pet: web::Json<UpdatePet>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let id = parse_id(&id);
+    let if_match = if_match(&req)?;
+    let pet = traced_block(&req, move || {
+        let service = clients.mongo.get_mongo_service("pets").unwrap();
+        let current = match &if_match {
+            Some(if_match) => Some(find_if_match::<Pet>(
+                service.data_source(),
+                &id,
+                if_match,
+                "Unable to find pet",
+            )?),
+            None => None,
+        };
+        let mut update_pet = update_pet.into_inner();
+        update_pet.owner = update_pet.owner.map(normalize_id);
+        check_owner(&clients, &update_pet.owner)?;
+        let result: Result<Pet, ApiError> = match &current {
+            Some(current) => update_if_unchanged(service.data_source(), current, &update_pet)
+                .and_then(|_| find_pet(&clients, id.clone())),
+            None => service
+                .update_one(id.clone(), update_pet, None)
+                .map_err(ApiError::from),
+        };
+        clients.cache.invalidate_document("pets", &id);
+        result
+    })
+    .await?;
+    let last_modified = pet.node.date_modified();
+    Ok(conditional_response(
+        &req,
+        HttpResponse::Ok(),
+        &pet,
+        last_modified,
+    ))
+}
+
+pub async fn delete_pet(
+    req: HttpRequest,
+    clients: web::Data<Arc<Clients>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let clients = clients.get_ref().clone();
+    let id = parse_id(&id);
+    let if_match = if_match(&req)?;
+    traced_block(&req, move || {
+        let service = clients.mongo.get_mongo_service("pets").unwrap();
+        let result = match &if_match {
+            Some(if_match) => {
+                find_if_match::<Pet>(service.data_source(), &id, if_match, "Unable to find pet")
+                    .and_then(|current| delete_if_unchanged(service.data_source(), &current))
+            }
+            None => match service.delete_one_by_query(doc! { "_id": id.to_bson() }) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(ApiError::NotFound("Unable to find pet".to_owned())),
+                Err(e) => Err(e.into()),
+            },
+        };
+        clients.cache.invalidate_document("pets", &id);
+        result
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn find_pet(clients: &Clients, id: ID) -> Result<Pet, ApiError> {
+    let service = clients.mongo.get_mongo_service("pets").unwrap();
+    let result: Result<Option<Pet>, ServiceError> = service.find_one_by_id(id);
+    match result? {
+        Some(pet) => Ok(pet),
+        None => Err(ApiError::NotFound("Unable to find pet".to_owned())),
+    }
+}
+
+/// Pets can only be given to owners that exist
+fn check_owner(clients: &Clients, owner: &Option<ID>) -> Result<(), ApiError> {
+    let owner_id = match owner {
+        Some(owner_id) => owner_id.clone(),
+        None => return Ok(()),
+    };
+    let service = clients.mongo.get_mongo_service("owners").unwrap();
+    let result: Result<Option<Owner>, ServiceError> = service.find_one_by_id(owner_id);
+    match result? {
+        Some(_) => Ok(()),
+        None => Err(ApiError::BadRequest("Unable to find owner".to_owned())),
     }
-    let pets = build(clients.get_ref());
-    web::Json(pets)
 }
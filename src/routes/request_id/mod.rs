@@ -0,0 +1,124 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use std::future::Future;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Ids sent by clients that are longer or have other characters are replaced, so they can
+/// be logged as is
+const MAX_LENGTH: usize = 128;
+
+/// The id of a request, kept in its extensions
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+/// Middleware that gives every request the id in its `X-Request-ID` header, or a new one,
+/// and sends it back in `X-Request-ID`
+pub fn assign_request_id<S, B>(
+    req: ServiceRequest,
+    service: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    // only valid ids are kept, so it's always a valid header value
+    let value = HeaderValue::from_str(&id).unwrap();
+    req.extensions_mut().insert(RequestId(id));
+    let response = service.call(req);
+    async move {
+        let mut response = response.await?;
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        Ok(response)
+    }
+}
+
+/// The id the middleware gave the request
+pub fn request_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone())
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    #[test]
+    fn accepts_short_ids_of_safe_characters() {
+        assert!(is_valid("f81d4fae-7dec-11d0-a765-00a0c91e6bf6"));
+        assert!(is_valid("trace_1.span:2"));
+        assert!(is_valid(&"a".repeat(MAX_LENGTH)));
+    }
+
+    #[test]
+    fn rejects_ids_that_cant_be_logged_as_is() {
+        assert!(!is_valid(""));
+        assert!(!is_valid(&"a".repeat(MAX_LENGTH + 1)));
+        for id in &["two words", "line\nbreak", "quote\"", "{json}", "é", "a/b"] {
+            assert!(!is_valid(id), "{:?}", id);
+        }
+    }
+
+    async fn respond_with_id(req: HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().body(request_id(&req).unwrap_or_default())
+    }
+
+    /// The id the handler saw and the one of the response header
+    async fn ids(req: TestRequest) -> (String, String) {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(assign_request_id)
+                .route("/", web::get().to(respond_with_id)),
+        )
+        .await;
+        let response = test::call_service(&mut app, req.to_request()).await;
+        let header = response.headers().get(REQUEST_ID_HEADER).unwrap();
+        let header = header.to_str().unwrap().to_owned();
+        let body = test::read_body(response).await;
+        (String::from_utf8(body.to_vec()).unwrap(), header)
+    }
+
+    #[actix_rt::test]
+    async fn keeps_a_valid_incoming_id() {
+        let (seen, sent) = ids(TestRequest::with_header("X-Request-Id", "client-42")).await;
+        assert_eq!(seen, "client-42");
+        assert_eq!(sent, "client-42");
+    }
+
+    #[actix_rt::test]
+    async fn replaces_a_missing_or_invalid_id() {
+        let invalid = ["not valid".to_owned(), "a".repeat(MAX_LENGTH + 1)];
+        let headers = invalid.iter().map(Some).chain(Some(None));
+        for header in headers {
+            let req = match header {
+                Some(id) => TestRequest::with_header("X-Request-Id", id.as_str()),
+                None => TestRequest::default(),
+            };
+            let (seen, sent) = ids(req).await;
+            assert_eq!(seen, sent);
+            assert!(Uuid::parse_str(&sent).is_ok(), "{}", sent);
+        }
+        let (first, _) = ids(TestRequest::default()).await;
+        let (second, _) = ids(TestRequest::default()).await;
+        assert_ne!(first, second);
+    }
+}
@@ -1,11 +1,18 @@
 use bson::doc;
-use cached::TimedCache;
-use juniper::{FieldError, RootNode};
+use juniper::{graphql_value, FieldError, RootNode};
 use mongodb_base_service::{BaseService, DeleteResponseGQL, ServiceError, ID};
 use mongodb_cursor_pagination::FindResult;
 
+use crate::cache::{collection_tag, document_tag};
 use crate::db::Clients;
+use crate::federation::{self, Entity, Representation, Service};
 use crate::models::*;
+use crate::telemetry;
+
+/// Errors with a code are shown to clients even when internal errors are masked
+fn not_found(message: &str) -> FieldError {
+    FieldError::new(message, graphql_value!({ "code": "NOT_FOUND" }))
+}
 
 pub struct Query;
 
@@ -19,41 +26,38 @@ impl Query {
         before: Option<String>,
         skip: Option<i32>,
     ) -> Result<PetConnection, FieldError> {
-        cached_key_result! {
-            ALL_PETS: TimedCache<String, PetConnection> =
-                TimedCache::with_lifespan_and_capacity(10, 10000);
-            Key = { format!("{:?},{:?},{:?},{:?}", limit, after, before, skip) };
-            fn build(
-                ctx: &Clients,
-                limit: Option<i32>,
-                after: Option<String>,
-                before: Option<String>,
-                skip: Option<i32>
-            ) -> Result<PetConnection, FieldError> = {
+        let _span = telemetry::resolver("Query", "allPets");
+        let key = format!("{:?},{:?},{:?},{:?}", limit, after, before, skip);
+        ctx.cache
+            .get_or_insert_with("all_pets", &key, vec![collection_tag("pets")], || {
                 let service = &ctx.mongo.get_mongo_service("pets").unwrap();
-                let result: Result<FindResult<Pet>, ServiceError> = service.find(None, None, limit, after, before, skip);
+                let result: Result<FindResult<Pet>, ServiceError> =
+                    service.find(None, None, limit, after, before, skip);
                 match result {
                     Ok(all_items) => {
                         let connection: PetConnection = all_items.into();
                         Ok(connection)
-                    },
-                    Err(e) => Err(FieldError::from(e))
+                    }
+                    Err(e) => Err(FieldError::from(e)),
                 }
-            }
-        }
-        build(ctx, limit, after, before, skip).map_err(|e| e.into())
+            })
     }
 
     fn pet_by_id(ctx: &Clients, id: ID) -> Result<Pet, FieldError> {
-        let service = &ctx.mongo.get_mongo_service("pets").unwrap();
-        let result: Result<Option<Pet>, ServiceError> = service.find_one_by_id(id);
-        match result {
-            Ok(item) => match item {
-                Some(item) => Ok(item),
-                None => Err("Unable to find item".into()),
-            },
-            Err(e) => Err(FieldError::from(e)),
-        }
+        let _span = telemetry::resolver("Query", "petById");
+        let key = id.to_string();
+        let tags = vec![document_tag("pets", &id)];
+        ctx.cache.get_or_insert_with("pet_by_id", &key, tags, || {
+            let service = &ctx.mongo.get_mongo_service("pets").unwrap();
+            let result: Result<Option<Pet>, ServiceError> = service.find_one_by_id(id);
+            match result {
+                Ok(item) => match item {
+                    Some(item) => Ok(item),
+                    None => Err(not_found("Unable to find item")),
+                },
+                Err(e) => Err(FieldError::from(e)),
+            }
+        })
     }
 
     fn pets_by_type(
@@ -64,20 +68,28 @@ impl Query {
         before: Option<String>,
         skip: Option<i32>,
     ) -> Result<PetConnection, FieldError> {
-        let service = &ctx.mongo.get_mongo_service("pets").unwrap();
-        let filter = match pet_type {
-            Some(pt) => Some(doc! { "pet_type": format!("{:?}", pt) }),
-            None => None,
-        };
-        let result: Result<FindResult<Pet>, ServiceError> =
-            service.find(filter, None, limit, after, before, skip);
-        match result {
-            Ok(all_items) => {
-                let connection: PetConnection = all_items.into();
-                Ok(connection)
-            }
-            Err(e) => Err(FieldError::from(e)),
-        }
+        let _span = telemetry::resolver("Query", "petsByType");
+        let key = format!(
+            "{:?},{:?},{:?},{:?},{:?}",
+            pet_type, limit, after, before, skip
+        );
+        ctx.cache
+            .get_or_insert_with("pets_by_type", &key, vec![collection_tag("pets")], || {
+                let service = &ctx.mongo.get_mongo_service("pets").unwrap();
+                let filter = match pet_type {
+                    Some(pt) => Some(doc! { "pet_type": format!("{:?}", pt) }),
+                    None => None,
+                };
+                let result: Result<FindResult<Pet>, ServiceError> =
+                    service.find(filter, None, limit, after, before, skip);
+                match result {
+                    Ok(all_items) => {
+                        let connection: PetConnection = all_items.into();
+                        Ok(connection)
+                    }
+                    Err(e) => Err(FieldError::from(e)),
+                }
+            })
     }
 
     fn all_owners(
@@ -87,29 +99,70 @@ impl Query {
         before: Option<String>,
         skip: Option<i32>,
     ) -> Result<OwnerConnection, FieldError> {
-        let service = &ctx.mongo.get_mongo_service("owners").unwrap();
-        let result: Result<FindResult<Owner>, ServiceError> =
-            service.find(None, None, limit, after, before, skip);
-        match result {
-            Ok(all_items) => {
-                let connection: OwnerConnection = all_items.into();
-                Ok(connection)
-            }
-            Err(e) => Err(FieldError::from(e)),
-        }
+        let _span = telemetry::resolver("Query", "allOwners");
+        let key = format!("{:?},{:?},{:?},{:?}", limit, after, before, skip);
+        ctx.cache
+            .get_or_insert_with("all_owners", &key, vec![collection_tag("owners")], || {
+                let service = &ctx.mongo.get_mongo_service("owners").unwrap();
+                let result: Result<FindResult<Owner>, ServiceError> =
+                    service.find(None, None, limit, after, before, skip);
+                match result {
+                    Ok(all_items) => {
+                        let connection: OwnerConnection = all_items.into();
+                        Ok(connection)
+                    }
+                    Err(e) => Err(FieldError::from(e)),
+                }
+            })
     }
 
     fn owner_by_id(ctx: &Clients, id: ID) -> Result<Owner, FieldError> {
-        let service = &ctx.mongo.get_mongo_service("owners").unwrap();
-        let result: Result<Option<Owner>, ServiceError> = service.find_one_by_id(id);
-        match result {
-            Ok(item) => match item {
-                Some(item) => Ok(item),
-                None => Err("Unable to find item".into()),
-            },
-            Err(e) => Err(FieldError::from(e)),
+        let _span = telemetry::resolver("Query", "ownerById");
+        let key = id.to_string();
+        let tags = vec![document_tag("owners", &id)];
+        ctx.cache.get_or_insert_with("owner_by_id", &key, tags, || {
+            let service = &ctx.mongo.get_mongo_service("owners").unwrap();
+            let result: Result<Option<Owner>, ServiceError> = service.find_one_by_id(id);
+            match result {
+                Ok(item) => match item {
+                    Some(item) => Ok(item),
+                    None => Err(not_found("Unable to find item")),
+                },
+                Err(e) => Err(FieldError::from(e)),
+            }
+        })
+    }
+
+    /// used by the federation gateway to compose the supergraph
+    #[graphql(name = "_service")]
+    fn service() -> Service {
+        let _span = telemetry::resolver("Query", "_service");
+        Service {
+            sdl: federation::cached_service_sdl(),
         }
     }
+
+    /// used by the federation gateway to resolve references to pets and owners
+    #[graphql(name = "_entities")]
+    fn entities(executor: &Executor, representations: Vec<Representation>) -> Vec<Option<Entity>> {
+        let _span = telemetry::resolver("Query", "_entities");
+        // a representation that can't be resolved is null with an error, the others are
+        // still resolved
+        representations
+            .iter()
+            .enumerate()
+            .map(|(i, representation)| {
+                match federation::resolve_entity(executor.context(), representation) {
+                    Ok(entity) => entity,
+                    Err(e) => {
+                        let message = format!("representations[{}]: {}", i, e.message());
+                        executor.push_error(FieldError::new(message, e.extensions().clone()));
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
 }
 
 pub struct Mutation;
@@ -117,9 +170,11 @@ pub struct Mutation;
 #[juniper::object(Context = Clients)]
 impl Mutation {
     fn create_pet(ctx: &Clients, new_pet: NewPet, user_id: Option<ID>) -> Result<Pet, FieldError> {
+        let _span = telemetry::resolver("Mutation", "createPet");
         let service = &ctx.mongo.get_mongo_service("pets").unwrap();
         // don't insert if there's one with the same name and type
         let inserted_id: ID = service.insert_one(new_pet, user_id)?;
+        ctx.cache.invalidate_collection("pets");
         match service.find_one_by_id(inserted_id)? {
             Some(item) => Ok(item),
             None => Err("Unable to find inserted item".into()),
@@ -132,15 +187,19 @@ impl Mutation {
         update_pet: UpdatePet,
         user_id: Option<ID>,
     ) -> Result<Pet, FieldError> {
+        let _span = telemetry::resolver("Mutation", "updatePet");
         let service = &ctx.mongo.get_mongo_service("pets").unwrap();
-        service
-            .update_one(id, update_pet, user_id)
-            .map_err(|e| e.into())
+        let result = service.update_one(id.clone(), update_pet, user_id);
+        ctx.cache.invalidate_document("pets", &id);
+        result.map_err(|e| e.into())
     }
 
     fn delete_pet(ctx: &Clients, id: ID) -> Result<DeleteResponseGQL, FieldError> {
+        let _span = telemetry::resolver("Mutation", "deletePet");
         let service = &ctx.mongo.get_mongo_service("pets").unwrap();
-        match service.delete_one_by_id(id) {
+        let result = service.delete_one_by_id(id.clone());
+        ctx.cache.invalidate_document("pets", &id);
+        match result {
             Ok(result) => Ok(result.into()),
             Err(e) => Err(e.into()),
         }
@@ -151,9 +210,11 @@ impl Mutation {
         new_owner: NewOwner,
         user_id: Option<ID>,
     ) -> Result<Owner, FieldError> {
+        let _span = telemetry::resolver("Mutation", "createOwner");
         let service = &ctx.mongo.get_mongo_service("owners").unwrap();
         // don't insert if there's one with the same name and type
         let inserted_id: ID = service.insert_one(new_owner, user_id)?;
+        ctx.cache.invalidate_collection("owners");
         match service.find_one_by_id(inserted_id)? {
             Some(item) => Ok(item),
             None => Err("Unable to find inserted item".into()),
@@ -166,15 +227,19 @@ impl Mutation {
         update_owner: UpdateOwner,
         user_id: Option<ID>,
     ) -> Result<Owner, FieldError> {
+        let _span = telemetry::resolver("Mutation", "updateOwner");
         let service = &ctx.mongo.get_mongo_service("owners").unwrap();
-        service
-            .update_one(id, update_owner, user_id)
-            .map_err(|e| e.into())
+        let result = service.update_one(id.clone(), update_owner, user_id);
+        ctx.cache.invalidate_document("owners", &id);
+        result.map_err(|e| e.into())
     }
 
     fn delete_owner(ctx: &Clients, id: ID) -> Result<DeleteResponseGQL, FieldError> {
+        let _span = telemetry::resolver("Mutation", "deleteOwner");
         let service = &ctx.mongo.get_mongo_service("owners").unwrap();
-        match service.delete_one_by_id(id) {
+        let result = service.delete_one_by_id(id.clone());
+        ctx.cache.invalidate_document("owners", &id);
+        match result {
             Ok(result) => Ok(result.into()),
             Err(e) => Err(e.into()),
         }
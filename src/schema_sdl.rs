@@ -0,0 +1,68 @@
+// only the schema is used here, the database connection and cache are never opened
+#[allow(dead_code)]
+mod cache;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod db;
+#[allow(dead_code)]
+mod environment;
+mod federation;
+#[allow(dead_code)]
+mod logging;
+#[allow(dead_code)]
+mod metrics;
+mod models;
+mod schema;
+mod sdl;
+#[allow(dead_code)]
+mod telemetry;
+
+use std::{env, fs, process};
+
+use crate::schema::create_schema;
+use crate::sdl::diff::{diff, Severity};
+
+const USAGE: &str = "usage: schema [--compare <schema.graphql>]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let schema = create_schema();
+    let sdl = sdl::print_schema(&schema);
+
+    match args
+        .iter()
+        .map(|a| a.as_str())
+        .collect::<Vec<&str>>()
+        .as_slice()
+    {
+        [] => print!("{}", sdl),
+        ["--compare", path] => {
+            let committed = fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("Unable to read {}: {}", path, e);
+                process::exit(2);
+            });
+            let old = graphql_parser::parse_schema(&committed).unwrap_or_else(|e| {
+                eprintln!("Unable to parse {}: {}", path, e);
+                process::exit(2);
+            });
+            let new = sdl::schema_document(&schema);
+            let changes = diff(&old, &new);
+            if changes.is_empty() {
+                println!("No changes to the schema");
+                return;
+            }
+            for change in &changes {
+                println!("{}", change);
+            }
+            if changes.iter().any(|c| c.severity == Severity::Breaking) {
+                eprintln!("Breaking changes found, compared to {}", path);
+                process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
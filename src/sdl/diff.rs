@@ -0,0 +1,615 @@
+use graphql_parser::schema::{
+    Definition, Directive, Document, Field, InputValue, Type, TypeDefinition, Value,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use super::type_name;
+
+/// Directives a federation gateway plans its queries to the service with, removing them
+/// breaks the gateway's plans
+const FEDERATION_DIRECTIVES: &[&str] = &["key", "extends", "external", "requires", "provides"];
+
+/// How a schema change affects existing clients
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Existing operations will fail
+    Breaking,
+    /// Existing operations keep working but clients may not handle the new values
+    Dangerous,
+    Safe,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Breaking => write!(f, "BREAKING"),
+            Severity::Dangerous => write!(f, "DANGEROUS"),
+            Severity::Safe => write!(f, "SAFE"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Change {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.severity, self.message)
+    }
+}
+
+struct Changes(Vec<Change>);
+
+impl Changes {
+    fn push(&mut self, severity: Severity, message: String) {
+        self.0.push(Change { severity, message });
+    }
+}
+
+/// Compares two schemas and lists the changes, most severe first
+pub fn diff(old: &Document, new: &Document) -> Vec<Change> {
+    let old_types = types_by_name(old);
+    let new_types = types_by_name(new);
+    let mut changes = Changes(Vec::new());
+
+    for (name, old_type) in &old_types {
+        match new_types.get(name) {
+            None => changes.push(Severity::Breaking, format!("Type {} was removed", name)),
+            Some(new_type) => diff_type(name, old_type, new_type, &mut changes),
+        }
+    }
+    for name in new_types.keys() {
+        if !old_types.contains_key(name) {
+            changes.push(Severity::Safe, format!("Type {} was added", name));
+        }
+    }
+
+    let mut changes = changes.0;
+    changes.sort_by_key(|c| c.severity);
+    changes
+}
+
+fn types_by_name(document: &Document) -> BTreeMap<&str, &TypeDefinition> {
+    document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::TypeDefinition(t) => Some((type_name(t), t)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn kind(definition: &TypeDefinition) -> &'static str {
+    match definition {
+        TypeDefinition::Scalar(_) => "scalar",
+        TypeDefinition::Object(_) => "object",
+        TypeDefinition::Interface(_) => "interface",
+        TypeDefinition::Union(_) => "union",
+        TypeDefinition::Enum(_) => "enum",
+        TypeDefinition::InputObject(_) => "input object",
+    }
+}
+
+fn type_directives(definition: &TypeDefinition) -> &[Directive] {
+    match definition {
+        TypeDefinition::Scalar(t) => &t.directives,
+        TypeDefinition::Object(t) => &t.directives,
+        TypeDefinition::Interface(t) => &t.directives,
+        TypeDefinition::Union(t) => &t.directives,
+        TypeDefinition::Enum(t) => &t.directives,
+        TypeDefinition::InputObject(t) => &t.directives,
+    }
+}
+
+fn diff_type(name: &str, old: &TypeDefinition, new: &TypeDefinition, changes: &mut Changes) {
+    diff_directives(name, type_directives(old), type_directives(new), changes);
+    match (old, new) {
+        (TypeDefinition::Object(o), TypeDefinition::Object(n)) => {
+            for interface in &o.implements_interfaces {
+                if !n.implements_interfaces.contains(interface) {
+                    changes.push(
+                        Severity::Breaking,
+                        format!("{} no longer implements interface {}", name, interface),
+                    );
+                }
+            }
+            for interface in &n.implements_interfaces {
+                if !o.implements_interfaces.contains(interface) {
+                    changes.push(
+                        Severity::Dangerous,
+                        format!("{} now implements interface {}", name, interface),
+                    );
+                }
+            }
+            diff_fields(name, &o.fields, &n.fields, changes);
+        }
+        (TypeDefinition::Interface(o), TypeDefinition::Interface(n)) => {
+            diff_fields(name, &o.fields, &n.fields, changes);
+        }
+        (TypeDefinition::Union(o), TypeDefinition::Union(n)) => {
+            for member in &o.types {
+                if !n.types.contains(member) {
+                    changes.push(
+                        Severity::Breaking,
+                        format!("{} was removed from union {}", member, name),
+                    );
+                }
+            }
+            for member in &n.types {
+                if !o.types.contains(member) {
+                    changes.push(
+                        Severity::Dangerous,
+                        format!("{} was added to union {}", member, name),
+                    );
+                }
+            }
+        }
+        (TypeDefinition::Enum(o), TypeDefinition::Enum(n)) => {
+            for value in &o.values {
+                match n.values.iter().find(|v| v.name == value.name) {
+                    None => changes.push(
+                        Severity::Breaking,
+                        format!("{} was removed from enum {}", value.name, name),
+                    ),
+                    Some(new_value) => diff_directives(
+                        &format!("{}.{}", name, value.name),
+                        &value.directives,
+                        &new_value.directives,
+                        changes,
+                    ),
+                }
+            }
+            for value in &n.values {
+                if !o.values.iter().any(|v| v.name == value.name) {
+                    changes.push(
+                        Severity::Dangerous,
+                        format!("{} was added to enum {}", value.name, name),
+                    );
+                }
+            }
+        }
+        (TypeDefinition::InputObject(o), TypeDefinition::InputObject(n)) => {
+            diff_input_fields(name, &o.fields, &n.fields, changes);
+        }
+        (TypeDefinition::Scalar(_), TypeDefinition::Scalar(_)) => {}
+        _ => changes.push(
+            Severity::Breaking,
+            format!("{} changed from {} to {}", name, kind(old), kind(new)),
+        ),
+    }
+}
+
+fn diff_fields(type_name: &str, old: &[Field], new: &[Field], changes: &mut Changes) {
+    let new_fields: HashMap<&str, &Field> = new.iter().map(|f| (f.name.as_str(), f)).collect();
+    for old_field in old {
+        let path = format!("{}.{}", type_name, old_field.name);
+        let new_field = match new_fields.get(old_field.name.as_str()) {
+            Some(f) => f,
+            None => {
+                changes.push(Severity::Breaking, format!("Field {} was removed", path));
+                continue;
+            }
+        };
+        if !is_safe_output_change(&old_field.field_type, &new_field.field_type) {
+            changes.push(
+                Severity::Breaking,
+                format!(
+                    "Field {} changed type from {} to {}",
+                    path, old_field.field_type, new_field.field_type
+                ),
+            );
+        } else if old_field.field_type != new_field.field_type {
+            changes.push(
+                Severity::Safe,
+                format!(
+                    "Field {} changed type from {} to {}",
+                    path, old_field.field_type, new_field.field_type
+                ),
+            );
+        }
+        diff_directives(&path, &old_field.directives, &new_field.directives, changes);
+        diff_arguments(&path, &old_field.arguments, &new_field.arguments, changes);
+    }
+    for new_field in new {
+        if !old.iter().any(|f| f.name == new_field.name) {
+            changes.push(
+                Severity::Safe,
+                format!("Field {}.{} was added", type_name, new_field.name),
+            );
+        }
+    }
+}
+
+fn diff_arguments(path: &str, old: &[InputValue], new: &[InputValue], changes: &mut Changes) {
+    for old_arg in old {
+        match new.iter().find(|a| a.name == old_arg.name) {
+            None => changes.push(
+                Severity::Breaking,
+                format!("Argument {} was removed from {}", old_arg.name, path),
+            ),
+            Some(new_arg) => {
+                diff_directives(
+                    &format!("{}({}:)", path, old_arg.name),
+                    &old_arg.directives,
+                    &new_arg.directives,
+                    changes,
+                );
+                let changed_type = format!(
+                    "Argument {} on {} changed type from {} to {}",
+                    old_arg.name, path, old_arg.value_type, new_arg.value_type
+                );
+                if !is_safe_input_change(&old_arg.value_type, &new_arg.value_type) {
+                    changes.push(Severity::Breaking, changed_type);
+                    continue;
+                }
+                if old_arg.value_type != new_arg.value_type {
+                    changes.push(Severity::Safe, changed_type);
+                }
+                if old_arg.default_value != new_arg.default_value {
+                    changes.push(
+                        Severity::Dangerous,
+                        format!(
+                            "Argument {} on {} changed default value from {} to {}",
+                            old_arg.name,
+                            path,
+                            format_default(&old_arg.default_value),
+                            format_default(&new_arg.default_value)
+                        ),
+                    );
+                }
+            }
+        }
+    }
+    for new_arg in new {
+        if old.iter().any(|a| a.name == new_arg.name) {
+            continue;
+        }
+        if is_required(new_arg) {
+            changes.push(
+                Severity::Breaking,
+                format!("Required argument {} was added to {}", new_arg.name, path),
+            );
+        } else {
+            changes.push(
+                Severity::Dangerous,
+                format!("Optional argument {} was added to {}", new_arg.name, path),
+            );
+        }
+    }
+}
+
+fn diff_input_fields(
+    type_name: &str,
+    old: &[InputValue],
+    new: &[InputValue],
+    changes: &mut Changes,
+) {
+    for old_field in old {
+        let path = format!("{}.{}", type_name, old_field.name);
+        match new.iter().find(|f| f.name == old_field.name) {
+            None => changes.push(
+                Severity::Breaking,
+                format!("Input field {} was removed", path),
+            ),
+            Some(new_field) => {
+                diff_directives(&path, &old_field.directives, &new_field.directives, changes);
+                let changed_type = format!(
+                    "Input field {} changed type from {} to {}",
+                    path, old_field.value_type, new_field.value_type
+                );
+                if !is_safe_input_change(&old_field.value_type, &new_field.value_type) {
+                    changes.push(Severity::Breaking, changed_type);
+                } else if old_field.value_type != new_field.value_type {
+                    changes.push(Severity::Safe, changed_type);
+                }
+            }
+        }
+    }
+    for new_field in new {
+        if old.iter().any(|f| f.name == new_field.name) {
+            continue;
+        }
+        let path = format!("{}.{}", type_name, new_field.name);
+        if is_required(new_field) {
+            changes.push(
+                Severity::Breaking,
+                format!("Required input field {} was added", path),
+            );
+        } else {
+            changes.push(
+                Severity::Dangerous,
+                format!("Optional input field {} was added", path),
+            );
+        }
+    }
+}
+
+/// Directives are compared with their arguments, so a directive whose arguments changed, e.g.
+/// the fields of a `@key`, is reported as removed and added
+fn diff_directives(path: &str, old: &[Directive], new: &[Directive], changes: &mut Changes) {
+    let old_formatted: Vec<String> = old.iter().map(format_directive).collect();
+    let new_formatted: Vec<String> = new.iter().map(format_directive).collect();
+    for (directive, formatted) in old.iter().zip(&old_formatted) {
+        if !new_formatted.contains(formatted) {
+            let severity = if FEDERATION_DIRECTIVES.contains(&directive.name.as_str()) {
+                Severity::Breaking
+            } else {
+                Severity::Dangerous
+            };
+            changes.push(
+                severity,
+                format!("Directive {} was removed from {}", formatted, path),
+            );
+        }
+    }
+    for formatted in &new_formatted {
+        if !old_formatted.contains(formatted) {
+            changes.push(
+                Severity::Dangerous,
+                format!("Directive {} was added to {}", formatted, path),
+            );
+        }
+    }
+}
+
+fn format_directive(directive: &Directive) -> String {
+    if directive.arguments.is_empty() {
+        return format!("@{}", directive.name);
+    }
+    let arguments: Vec<String> = directive
+        .arguments
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect();
+    format!("@{}({})", directive.name, arguments.join(", "))
+}
+
+fn is_required(input: &InputValue) -> bool {
+    match input.value_type {
+        Type::NonNullType(_) => input.default_value.is_none(),
+        _ => false,
+    }
+}
+
+fn format_default(value: &Option<Value>) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => "none".to_owned(),
+    }
+}
+
+/// Output types can safely become stricter (non-null) but not change shape
+fn is_safe_output_change(old: &Type, new: &Type) -> bool {
+    match (old, new) {
+        (Type::NamedType(o), Type::NamedType(n)) => o == n,
+        (Type::ListType(o), Type::ListType(n)) => is_safe_output_change(o, n),
+        (Type::NonNullType(o), Type::NonNullType(n)) => is_safe_output_change(o, n),
+        (Type::NonNullType(_), _) => false,
+        (_, Type::NonNullType(n)) => is_safe_output_change(old, n),
+        _ => false,
+    }
+}
+
+/// Input types can safely become looser (nullable) but not change shape
+fn is_safe_input_change(old: &Type, new: &Type) -> bool {
+    match (old, new) {
+        (Type::NamedType(o), Type::NamedType(n)) => o == n,
+        (Type::ListType(o), Type::ListType(n)) => is_safe_input_change(o, n),
+        (Type::NonNullType(o), Type::NonNullType(n)) => is_safe_input_change(o, n),
+        (Type::NonNullType(o), _) => is_safe_input_change(o, new),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(old: &str, new: &str) -> Vec<(Severity, String)> {
+        let old = graphql_parser::parse_schema(old).unwrap();
+        let new = graphql_parser::parse_schema(new).unwrap();
+        diff(&old, &new)
+            .into_iter()
+            .map(|change| (change.severity, change.message))
+            .collect()
+    }
+
+    #[test]
+    fn classifies_changes() {
+        use Severity::*;
+        let cases: &[(&str, &str, Severity, &str)] = &[
+            (
+                "type A { a: Int }",
+                "type B { a: Int }",
+                Breaking,
+                "Type A was removed",
+            ),
+            (
+                "type A { a: Int }",
+                "type A { a: Int b: Int }",
+                Safe,
+                "Field A.b was added",
+            ),
+            (
+                "type A { a: Int b: Int }",
+                "type A { a: Int }",
+                Breaking,
+                "Field A.b was removed",
+            ),
+            (
+                "type A { a: Int }",
+                "type A { a: Int! }",
+                Safe,
+                "Field A.a changed type from Int to Int!",
+            ),
+            (
+                "type A { a: Int! }",
+                "type A { a: Int }",
+                Breaking,
+                "Field A.a changed type from Int! to Int",
+            ),
+            (
+                "type A { a(x: Int): Int }",
+                "type A { a(x: Int, y: Int!): Int }",
+                Breaking,
+                "Required argument y was added to A.a",
+            ),
+            (
+                "type A { a(x: Int): Int }",
+                "type A { a(x: Int, y: Int): Int }",
+                Dangerous,
+                "Optional argument y was added to A.a",
+            ),
+            (
+                "type A { a(x: Int = 1): Int }",
+                "type A { a(x: Int = 2): Int }",
+                Dangerous,
+                "Argument x on A.a changed default value from 1 to 2",
+            ),
+            (
+                "type A { a(x: Int!): Int }",
+                "type A { a(x: Int): Int }",
+                // an input that becomes nullable accepts what it did
+                Safe,
+                "Argument x on A.a changed type from Int! to Int",
+            ),
+            (
+                "type A { a(x: Int! = 1): Int }",
+                "type A { a(x: Int = 2): Int }",
+                Dangerous,
+                "Argument x on A.a changed default value from 1 to 2",
+            ),
+            (
+                "type A { a(x: Int): Int }",
+                "type A { a(x: Int!): Int }",
+                Breaking,
+                "Argument x on A.a changed type from Int to Int!",
+            ),
+            (
+                "type A { a(x: Int): Int }",
+                "type A { a(x: Int): Int }",
+                Safe,
+                "",
+            ),
+            (
+                "input I { a: [Int!]! }",
+                "input I { a: [Int] }",
+                Safe,
+                "Input field I.a changed type from [Int!]! to [Int]",
+            ),
+            (
+                "enum E { X Y }",
+                "enum E { X }",
+                Breaking,
+                "Y was removed from enum E",
+            ),
+            (
+                "enum E { X }",
+                "enum E { X Y }",
+                Dangerous,
+                "Y was added to enum E",
+            ),
+            (
+                "union U = A | B",
+                "union U = A",
+                Breaking,
+                "B was removed from union U",
+            ),
+            (
+                "union U = A",
+                "union U = A | B",
+                Dangerous,
+                "B was added to union U",
+            ),
+            (
+                "input I { a: Int }",
+                "input I { a: Int b: Int! }",
+                Breaking,
+                "Required input field I.b was added",
+            ),
+            (
+                "input I { a: Int }",
+                "input I { a: Int b: Int }",
+                Dangerous,
+                "Optional input field I.b was added",
+            ),
+            (
+                "type A { a: Int }",
+                "input A { a: Int }",
+                Breaking,
+                "A changed from object to input object",
+            ),
+        ];
+        for (old, new, severity, message) in cases {
+            let found = changes(old, new);
+            if message.is_empty() {
+                assert!(found.is_empty(), "{} -> {}: {:?}", old, new, found);
+            } else {
+                let expected = (*severity, message.to_string());
+                assert!(found.contains(&expected), "{} -> {}: {:?}", old, new, found);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_directive_changes() {
+        use Severity::*;
+        let cases: &[(&str, &str, Severity, &str)] = &[
+            (
+                r#"type Pet @key(fields: "id") { id: ID! }"#,
+                "type Pet { id: ID! }",
+                Breaking,
+                r#"Directive @key(fields: "id") was removed from Pet"#,
+            ),
+            (
+                r#"type Pet @key(fields: "id") { id: ID! }"#,
+                r#"type Pet @key(fields: "name") { id: ID! }"#,
+                Dangerous,
+                r#"Directive @key(fields: "name") was added to Pet"#,
+            ),
+            (
+                "type A { a: Int }",
+                r#"type A { a: Int @deprecated(reason: "no") }"#,
+                Dangerous,
+                r#"Directive @deprecated(reason: "no") was added to A.a"#,
+            ),
+            (
+                "type A { a: Int @external }",
+                "type A { a: Int }",
+                Breaking,
+                "Directive @external was removed from A.a",
+            ),
+            (
+                "enum E { X @deprecated }",
+                "enum E { X }",
+                Dangerous,
+                "Directive @deprecated was removed from E.X",
+            ),
+            (
+                "input I { a: Int @deprecated }",
+                "input I { a: Int }",
+                Dangerous,
+                "Directive @deprecated was removed from I.a",
+            ),
+        ];
+        for (old, new, severity, message) in cases {
+            let found = changes(old, new);
+            let expected = (*severity, message.to_string());
+            assert!(found.contains(&expected), "{} -> {}: {:?}", old, new, found);
+        }
+        let unchanged = r#"type Pet @key(fields: "id") { id: ID! }"#;
+        assert!(changes(unchanged, unchanged).is_empty());
+    }
+
+    #[test]
+    fn lists_the_most_severe_changes_first() {
+        let found = changes("type A { a: Int b: Int }", "type A { a: Int c: Int }");
+        let severities: Vec<Severity> = found.iter().map(|(severity, _)| *severity).collect();
+        assert_eq!(severities, vec![Severity::Breaking, Severity::Safe]);
+    }
+}
@@ -0,0 +1,243 @@
+pub mod diff;
+
+use graphql_parser::schema::{
+    Definition, Directive, Document, EnumType, EnumValue, Field, InputObjectType, InputValue,
+    InterfaceType, ObjectType, ScalarType, SchemaDefinition, Type, TypeDefinition, UnionType,
+    Value,
+};
+use graphql_parser::Pos;
+use juniper::meta::{self, DeprecationStatus, MetaType};
+use juniper::{DefaultScalarValue, ScalarValue};
+
+use crate::federation;
+use crate::schema::Schema;
+
+const BUILT_IN_SCALARS: [&str; 5] = ["String", "Int", "Float", "Boolean", "ID"];
+
+/// Prints the schema definition language (SDL) of the schema
+pub fn print_schema(schema: &Schema) -> String {
+    schema_document(schema).to_string()
+}
+
+/// Builds the SDL document of the schema, types are sorted by name so the output is stable
+pub fn schema_document(schema: &Schema) -> Document {
+    let types = &schema.schema;
+    let query = types.concrete_query_type().name().map(|n| n.to_owned());
+    let mutation = types
+        .concrete_mutation_type()
+        .and_then(|t| t.name())
+        .map(|n| n.to_owned());
+
+    let mut definitions = Vec::new();
+    if query.as_deref() != Some("Query") || mutation.as_deref().unwrap_or("Mutation") != "Mutation"
+    {
+        definitions.push(Definition::SchemaDefinition(SchemaDefinition {
+            position: Pos::default(),
+            directives: vec![],
+            query,
+            mutation,
+            subscription: None,
+        }));
+    }
+
+    let mut type_definitions: Vec<TypeDefinition> = types
+        .concrete_type_list()
+        .into_iter()
+        .filter(|t| match t.name() {
+            Some(name) => !name.starts_with("__") && !BUILT_IN_SCALARS.contains(&name),
+            None => false,
+        })
+        .filter_map(type_definition)
+        .collect();
+    type_definitions.sort_by(|a, b| type_name(a).cmp(type_name(b)));
+    definitions.extend(type_definitions.into_iter().map(Definition::TypeDefinition));
+
+    Document { definitions }
+}
+
+pub fn type_name(definition: &TypeDefinition) -> &str {
+    match definition {
+        TypeDefinition::Scalar(t) => &t.name,
+        TypeDefinition::Object(t) => &t.name,
+        TypeDefinition::Interface(t) => &t.name,
+        TypeDefinition::Union(t) => &t.name,
+        TypeDefinition::Enum(t) => &t.name,
+        TypeDefinition::InputObject(t) => &t.name,
+    }
+}
+
+fn type_definition(meta_type: &MetaType) -> Option<TypeDefinition> {
+    let definition = match meta_type {
+        MetaType::Scalar(s) => TypeDefinition::Scalar(ScalarType {
+            description: s.description.clone(),
+            ..ScalarType::new(s.name.to_string())
+        }),
+        MetaType::Object(o) => TypeDefinition::Object(ObjectType {
+            description: o.description.clone(),
+            implements_interfaces: o.interface_names.clone(),
+            // the same `@key` as the SDL of the gateway, so a diff sees the federation directives
+            directives: federation::key_directives(&o.name),
+            fields: o.fields.iter().filter_map(field).collect(),
+            ..ObjectType::new(o.name.to_string())
+        }),
+        MetaType::Interface(i) => TypeDefinition::Interface(InterfaceType {
+            description: i.description.clone(),
+            fields: i.fields.iter().filter_map(field).collect(),
+            ..InterfaceType::new(i.name.to_string())
+        }),
+        MetaType::Union(u) => TypeDefinition::Union(UnionType {
+            description: u.description.clone(),
+            types: u.of_type_names.clone(),
+            ..UnionType::new(u.name.to_string())
+        }),
+        MetaType::Enum(e) => TypeDefinition::Enum(EnumType {
+            description: e.description.clone(),
+            values: e
+                .values
+                .iter()
+                .map(|v| EnumValue {
+                    description: v.description.clone(),
+                    directives: deprecated(&v.deprecation_status),
+                    ..EnumValue::new(v.name.clone())
+                })
+                .collect(),
+            ..EnumType::new(e.name.to_string())
+        }),
+        MetaType::InputObject(i) => TypeDefinition::InputObject(InputObjectType {
+            description: i.description.clone(),
+            fields: i.input_fields.iter().map(input_value).collect(),
+            ..InputObjectType::new(i.name.to_string())
+        }),
+        MetaType::List(_) | MetaType::Nullable(_) | MetaType::Placeholder(_) => return None,
+    };
+    Some(definition)
+}
+
+fn field(f: &meta::Field<DefaultScalarValue>) -> Option<Field> {
+    // introspection fields are implicit in every schema
+    if f.name.starts_with("__") {
+        return None;
+    }
+    Some(Field {
+        position: Pos::default(),
+        description: f.description.clone(),
+        name: f.name.clone(),
+        arguments: f
+            .arguments
+            .as_ref()
+            .map(|args| args.iter().map(input_value).collect())
+            .unwrap_or_default(),
+        field_type: sdl_type(&f.field_type),
+        directives: deprecated(&f.deprecation_status),
+    })
+}
+
+fn input_value(argument: &meta::Argument<DefaultScalarValue>) -> InputValue {
+    InputValue {
+        position: Pos::default(),
+        description: argument.description.clone(),
+        name: argument.name.clone(),
+        value_type: sdl_type(&argument.arg_type),
+        default_value: argument.default_value.as_ref().map(sdl_value),
+        directives: vec![],
+    }
+}
+
+fn sdl_type(t: &juniper::Type) -> Type {
+    match t {
+        juniper::Type::Named(name) => Type::NamedType(name.to_string()),
+        juniper::Type::NonNullNamed(name) => {
+            Type::NonNullType(Box::new(Type::NamedType(name.to_string())))
+        }
+        juniper::Type::List(inner) => Type::ListType(Box::new(sdl_type(inner))),
+        juniper::Type::NonNullList(inner) => {
+            Type::NonNullType(Box::new(Type::ListType(Box::new(sdl_type(inner)))))
+        }
+    }
+}
+
+fn sdl_value(value: &juniper::InputValue) -> Value {
+    match value {
+        juniper::InputValue::Null => Value::Null,
+        juniper::InputValue::Scalar(s) => {
+            if let Some(i) = s.as_int() {
+                Value::Int(i.into())
+            } else if let Some(f) = s.as_float() {
+                Value::Float(f)
+            } else if let Some(b) = s.as_boolean() {
+                Value::Boolean(b)
+            } else {
+                Value::String(s.as_string().unwrap_or_default())
+            }
+        }
+        juniper::InputValue::Enum(e) => Value::Enum(e.clone()),
+        juniper::InputValue::Variable(v) => Value::Variable(v.clone()),
+        juniper::InputValue::List(items) => {
+            Value::List(items.iter().map(|i| sdl_value(&i.item)).collect())
+        }
+        juniper::InputValue::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| (k.item.clone(), sdl_value(&v.item)))
+                .collect(),
+        ),
+    }
+}
+
+fn deprecated(status: &DeprecationStatus) -> Vec<Directive> {
+    match status {
+        DeprecationStatus::Current => vec![],
+        DeprecationStatus::Deprecated(reason) => vec![Directive {
+            position: Pos::default(),
+            name: "deprecated".to_owned(),
+            arguments: reason
+                .iter()
+                .map(|r| ("reason".to_owned(), Value::String(r.clone())))
+                .collect(),
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::create_schema;
+
+    fn object<'a>(document: &'a Document, name: &str) -> &'a ObjectType {
+        document
+            .definitions
+            .iter()
+            .find_map(|definition| match definition {
+                Definition::TypeDefinition(TypeDefinition::Object(o)) if o.name == name => Some(o),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn prints_the_keys_of_the_entities() {
+        let sdl = print_schema(&create_schema());
+        assert!(sdl.contains("type Pet @key(fields: \"id\") {"), "{}", sdl);
+        assert!(sdl.contains("type Owner @key(fields: \"id\") {"), "{}", sdl);
+        assert!(sdl.contains("type PageInfo {"), "{}", sdl);
+    }
+
+    #[test]
+    fn has_the_directives_of_the_service_sdl() {
+        let schema = create_schema();
+        let printed = schema_document(&schema);
+        let service = graphql_parser::parse_schema(&federation::service_sdl(&schema)).unwrap();
+        for definition in &service.definitions {
+            if let Definition::TypeDefinition(TypeDefinition::Object(o)) = definition {
+                let directives: Vec<String> =
+                    o.directives.iter().map(ToString::to_string).collect();
+                let printed: Vec<String> = object(&printed, &o.name)
+                    .directives
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+                assert_eq!(printed, directives, "{}", o.name);
+            }
+        }
+    }
+}
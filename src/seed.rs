@@ -1,86 +1,230 @@
-#[macro_use]
-extern crate cached;
-
+// the response cache is only used by the graphql routes
+#[allow(dead_code)]
+mod cache;
+// only the database and cache settings are used here
+#[allow(dead_code)]
+mod config;
 mod db;
+#[allow(dead_code)]
+mod environment;
+mod federation;
+mod fixtures;
+mod logging;
+// only the database, cache and resolver metrics are recorded here and never served
+#[allow(dead_code)]
+mod metrics;
 mod models;
 mod schema;
+// printing and diffing are only used by the schema binary
+#[allow(dead_code)]
+mod sdl;
+// spans are only exported by the server, the seed runs outside of any request
+#[allow(dead_code)]
+mod telemetry;
 
-use bson::doc;
-use dotenv::dotenv;
-use mongodb_base_service::{BaseService, ID};
+use mongodb_base_service::BaseService;
+use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
 
+use crate::cache::QueryCache;
+use crate::config::Config;
 use crate::db::Clients;
+use crate::environment::Environment;
+use crate::fixtures::generate::{self, GenerateOptions};
+use crate::fixtures::Fixtures;
 use crate::schema::{create_schema, Schema};
 
+const USAGE: &str = "usage: seed [--upsert] [--env <environment>] [<file or directory>...]
+       seed --generate [--owners <count>] [--pets <count>] [--seed <value>] [--batch-size <count>] [--env <environment>]";
+
+/// Loaded when no file is given
+const DEFAULT_FIXTURES: &str = "fixtures";
+
+const DEFAULT_OWNERS: usize = 1_000;
+const DEFAULT_PETS: usize = 3_000;
+const DEFAULT_BATCH_SIZE: usize = 1_000;
+
+struct Args {
+    paths: Vec<PathBuf>,
+    /// Keeps the existing data and only inserts or updates the fixtures
+    upsert: bool,
+    /// The environment whose fixtures are loaded, the configured one by default
+    environment: Option<Environment>,
+    /// Generates random data instead of loading fixtures
+    generate: Option<GenerateOptions>,
+}
+
+fn parse_count(name: &str, value: Option<String>) -> Result<usize, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("{} needs a number", name))
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut paths = Vec::new();
+    let mut upsert = false;
+    let mut environment = None;
+    let mut generate = false;
+    let mut owners = None;
+    let mut pets = None;
+    let mut seed = None;
+    let mut batch_size = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--upsert" => upsert = true,
+            "--env" => {
+                let value = args.next().ok_or("--env needs an environment")?;
+                let parsed = Environment::parse(&value).ok_or_else(|| {
+                    format!(
+                        "Unknown environment {}, expected development, staging or production",
+                        value
+                    )
+                })?;
+                environment = Some(parsed);
+            }
+            "--generate" => generate = true,
+            "--owners" => owners = Some(parse_count("--owners", args.next())?),
+            "--pets" => pets = Some(parse_count("--pets", args.next())?),
+            "--seed" => {
+                let value = args.next().and_then(|value| value.parse().ok());
+                seed = Some(value.ok_or("--seed needs a number")?);
+            }
+            "--batch-size" => match parse_count("--batch-size", args.next())? {
+                0 => return Err("--batch-size must be at least 1".to_owned()),
+                size => batch_size = Some(size),
+            },
+            _ if arg.starts_with("--") => return Err(USAGE.to_owned()),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let generate = if generate {
+        if upsert {
+            return Err("--generate replaces the data and can't be used with --upsert".to_owned());
+        }
+        if !paths.is_empty() {
+            return Err("--generate doesn't load fixture files".to_owned());
+        }
+        Some(GenerateOptions {
+            owners: owners.unwrap_or(DEFAULT_OWNERS),
+            pets: pets.unwrap_or(DEFAULT_PETS),
+            // printed so the run can be repeated
+            seed: seed.unwrap_or_else(rand::random),
+            batch_size: batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+        })
+    } else if owners.is_some() || pets.is_some() || seed.is_some() || batch_size.is_some() {
+        return Err("--owners, --pets, --seed and --batch-size need --generate".to_owned());
+    } else {
+        None
+    };
+    if paths.is_empty() {
+        paths.push(PathBuf::from(DEFAULT_FIXTURES));
+    }
+    Ok(Args {
+        paths,
+        upsert,
+        environment,
+        generate,
+    })
+}
+
 fn main() {
-    std::env::set_var("RUST_LOG", "info,actix_web=warn");
-    env_logger::init();
-    dotenv().ok();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    logging::init(&config.log, config.log_format);
+
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let environment = args.environment.unwrap_or(config.environment);
+    let fixtures = if args.generate.is_some() {
+        Fixtures::default()
+    } else {
+        let fixtures = Fixtures::load(&args.paths, environment).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        if fixtures.is_empty() {
+            eprintln!("No fixtures for {}", environment);
+        }
+        fixtures
+    };
 
+    let (database, mongo) = db::mongo::connect(&config.mongo, &config.slow_queries);
     let db_clients = Arc::new(Clients {
-        mongo: db::mongo::connect(),
+        database,
+        mongo,
+        cache: Arc::new(QueryCache::from_config(&config.cache)),
+        request_id: None,
     });
 
-    // drop the existing data
-    let owners_service = db_clients.mongo.get_mongo_service("owners").unwrap();
-    let _ = owners_service.data_source().drop(None);
-
-    let pets_service = db_clients.mongo.get_mongo_service("pets").unwrap();
-    let _ = pets_service.data_source().drop(None);
-
-    // seed data
-    // create owners first
-    let owners = vec![
-        {
-            doc! { "username": "jsmith", "first_name": "John", "last_name": "Smith", "gender": "Male" }
-        },
-        {
-            doc! { "username": "janejohnson", "first_name": "Jane", "last_name": "Johnson", "gender": "Female" }
-        },
-        {
-            doc! { "username": "bgoldman", "first_name": "Bob", "last_name": "Goldman", "gender": "Male" }
-        },
-        {
-            doc! { "username": "emartinez", "first_name": "Eileen", "last_name": "Martinez", "gender": "Female" }
-        },
-        {
-            doc! { "username": "helenp78", "first_name": "Helen", "last_name": "Phillips", "gender": "Female" }
-        },
-    ];
-
-    // add the owners
-    let ids: Vec<ID> = owners_service.insert_many(owners, None).unwrap();
-    println!("{:?}", ids);
-    let pets = vec![
-        {
-            doc! { "name": "Fido", "pet_type": "Dog", "age": 10, "gender": "Male", "owner": &ids[0].to_bson() }
-        },
-        {
-            doc! { "name": "Cleo", "pet_type": "Cat", "age": 12, "gender": "Female", "owner": &ids[1].to_bson() }
-        },
-        {
-            doc! { "name": "Oreo", "pet_type": "Cat", "age": 2, "gender": "Female", "owner": &ids[2].to_bson() }
-        },
-        {
-            doc! { "name": "Milo", "pet_type": "Dog", "age": 10, "gender": "Male", "owner": &ids[3].to_bson() }
-        },
-        {
-            doc! { "name": "Squirt", "pet_type": "Fish", "age": 2, "gender": "Female", "owner": &ids[4].to_bson() }
-        },
-        {
-            doc! { "name": "Lurch", "pet_type": "Hamster", "age": 1, "gender": "Male", "owner": &ids[0].to_bson() }
-        },
-        {
-            doc! { "name": "Fonz", "pet_type": "Turtle", "age": 10, "gender": "Male", "owner": &ids[1].to_bson() }
-        },
-        {
-            doc! { "name": "Lucy", "pet_type": "Turtle", "age": 10, "gender": "Female", "owner": &ids[1].to_bson() }
-        },
-    ];
-
-    let _pets_results: Vec<ID> = pets_service.insert_many(pets, None).unwrap();
-    println!("Data inserted");
+    let result = if args.upsert {
+        fixtures::upsert(&db_clients, fixtures)
+    } else {
+        let marked = db::environment::marked(&db_clients.database).unwrap_or_else(|e| {
+            eprintln!("Unable to read the environment of the database: {}", e);
+            process::exit(1);
+        });
+        let production = [Some(config.environment), Some(environment), marked]
+            .iter()
+            .flatten()
+            .any(|environment| environment.is_production());
+        if production {
+            eprintln!(
+                "Refusing to drop the data of a production database, \
+                 --upsert only inserts and updates the fixtures"
+            );
+            process::exit(1);
+        }
+        // the owners of the database are about to be dropped
+        if let Err(e) = fixtures.check_owners() {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+
+        // drop the existing data
+        let owners_service = db_clients.mongo.get_mongo_service("owners").unwrap();
+        let _ = owners_service.data_source().drop(None);
+
+        let pets_service = db_clients.mongo.get_mongo_service("pets").unwrap();
+        let _ = pets_service.data_source().drop(None);
+        // dropping the collections dropped their indexes
+        db::indexes::sync_on_startup(
+            &db_clients.database,
+            &models::indexes(),
+            config.mongo.indexes,
+        );
+
+        match &args.generate {
+            Some(options) => {
+                println!(
+                    "Generating {} owners and {} pets with --seed {}",
+                    options.owners, options.pets, options.seed
+                );
+                generate::generate(&db_clients, options, |progress| {
+                    eprintln!(
+                        "{}: {}/{} ({}%)",
+                        progress.collection,
+                        progress.inserted,
+                        progress.total,
+                        progress.inserted * 100 / progress.total
+                    )
+                })
+            }
+            None => fixtures::insert(&db_clients, fixtures),
+        }
+    };
+    match result {
+        Ok(report) => println!("{}", report),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
 
     // putting this here to prevent dead code check issues
     let _schema: Arc<Schema> = std::sync::Arc::new(create_schema());
@@ -0,0 +1,756 @@
+// Traces of requests, GraphQL operations, resolvers and MongoDB commands, exported with
+// OTLP/HTTP or to a file
+//
+// The span of a request is kept in its extensions, and the work it does on the blocking
+// pool enters it so the spans started on that thread are its children.
+//
+// Spans are queued for the exporter thread, and dropped when the queue is full so a slow or
+// unreachable collector can't hold up requests or grow the queue without limit.
+//
+// Only the OTLP/HTTP json encoding is written here rather than using the opentelemetry
+// crates, whose OTLP exporter needs gRPC and its protobuf build, when collectors accept json
+// over HTTP sent with the actix client the service already has.
+//
+// GraphQL requests with the debug token also record a debug trace of the resolvers and
+// commands of their thread, which is returned in the extensions of the response.
+
+use crate::config::{TracingConfig, TracingExporter};
+use crate::db::mongo::command_collection;
+use crate::metrics;
+
+use actix_web::client::{Client, SendRequestError};
+use bson::Document;
+use lazy_static::lazy_static;
+use log::warn;
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use prometheus::HistogramTimer;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Spans are exported when this many are waiting or `BATCH_DELAY` after the first one
+const MAX_BATCH: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+/// Spans waiting to be exported, the next ones are dropped until the exporter catches up
+const MAX_QUEUE: usize = 4 * MAX_BATCH;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Only locked by the first span each thread ends, which keeps a clone in `SENDER`
+    static ref EXPORTER: Mutex<Option<SyncSender<SpanData>>> = Mutex::new(None);
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<SpanContext>> = const { RefCell::new(None) };
+    static RESOLVER: RefCell<Option<String>> = const { RefCell::new(None) };
+    static DEBUG_TRACE: RefCell<Option<DebugTrace>> = const { RefCell::new(None) };
+    static SENDER: RefCell<Option<SyncSender<SpanData>>> = const { RefCell::new(None) };
+}
+
+/// What's propagated in a W3C `traceparent` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+impl SpanContext {
+    /// Parses `<version>-<trace id>-<parent id>-<flags>`, later versions may add fields
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() < 4 {
+            return None;
+        }
+        let version = parts[0];
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.len() != 4) {
+            return None;
+        }
+        let mut trace_id = [0; 16];
+        let mut span_id = [0; 8];
+        let mut flags = [0; 1];
+        decode_hex(parts[1], &mut trace_id)?;
+        decode_hex(parts[2], &mut span_id)?;
+        decode_hex(parts[3], &mut flags)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 1 == 1,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+
+    fn child(&self) -> Self {
+        SpanContext {
+            span_id: new_span_id(),
+            ..*self
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+struct SpanData {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+/// Exported when dropped, does nothing when tracing is disabled and only propagates its
+/// context when its trace isn't sampled
+pub struct Span {
+    context: Option<SpanContext>,
+    data: Option<SpanData>,
+}
+
+impl Span {
+    fn new(name: String, kind: SpanKind, parent: Option<SpanContext>, root: bool) -> Self {
+        let context = match parent {
+            _ if !ENABLED.load(Ordering::Relaxed) => None,
+            Some(parent) => Some(parent.child()),
+            // resolvers and commands outside of a request aren't traced
+            None if !root => None,
+            None => Some(SpanContext {
+                trace_id: *Uuid::new_v4().as_bytes(),
+                span_id: new_span_id(),
+                sampled: true,
+            }),
+        };
+        let data = context.filter(|c| c.sampled).map(|context| SpanData {
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+            name,
+            kind,
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        });
+        Span { context, data }
+    }
+
+    pub fn context(&self) -> Option<SpanContext> {
+        self.context
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        if let Some(data) = &mut self.data {
+            data.name = name;
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn set_error(&mut self, message: String) {
+        if let Some(data) = &mut self.data {
+            data.error = Some(message);
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end = SystemTime::now();
+            export(data);
+        }
+    }
+}
+
+/// Queues a span for the exporter, or drops it when the queue is full
+fn export(span: SpanData) {
+    SENDER.with(|sender| {
+        let mut sender = sender.borrow_mut();
+        if sender.is_none() {
+            *sender = EXPORTER.lock().unwrap().clone();
+        }
+        if let Some(sender) = sender.as_ref() {
+            if let Err(TrySendError::Full(_)) = sender.try_send(span) {
+                metrics::count_dropped_span();
+            }
+        }
+    });
+}
+
+/// Starts the span of a request, a child of the caller's span when it sent a `traceparent`
+pub fn request_span(name: String, parent: Option<SpanContext>) -> Span {
+    Span::new(name, SpanKind::Server, parent, true)
+}
+
+/// Starts a child of the current span of the thread
+fn child_span(name: String, kind: SpanKind) -> Span {
+    Span::new(name, kind, current(), false)
+}
+
+fn current() -> Option<SpanContext> {
+    CURRENT.with(|current| *current.borrow())
+}
+
+/// Makes `context` the current span of the thread until dropped
+pub struct Entered {
+    previous: Option<SpanContext>,
+}
+
+pub fn enter(context: Option<SpanContext>) -> Entered {
+    let previous = CURRENT.with(|current| current.replace(context));
+    Entered { previous }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous);
+    }
+}
+
+/// A child of the current span that is the current span until dropped
+pub struct Scope {
+    // dropped in order, the previous span is current again before this one ends
+    _entered: Entered,
+    span: Span,
+}
+
+impl Scope {
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.span.set_attribute(key, value);
+    }
+}
+
+pub fn span(name: String) -> Scope {
+    let span = child_span(name, SpanKind::Internal);
+    Scope {
+        _entered: enter(span.context().or_else(current)),
+        span,
+    }
+}
+
+/// Times and traces a resolver, and makes it the current resolver of the thread, until
+/// dropped
+pub struct Resolver {
+    _timer: HistogramTimer,
+    _scope: Scope,
+    previous: Option<String>,
+    /// The index of its timing in the debug trace of the thread
+    debug_timing: Option<usize>,
+}
+
+pub fn resolver(type_name: &str, field: &str) -> Resolver {
+    let name = format!("{}.{}", type_name, field);
+    let mut scope = span(name.clone());
+    scope.set_attribute("graphql.type", type_name);
+    scope.set_attribute("graphql.field", field);
+    Resolver {
+        _timer: metrics::time_resolver(type_name, field),
+        _scope: scope,
+        previous: RESOLVER.with(|current| current.replace(Some(name))),
+        debug_timing: with_debug_trace(|trace| {
+            trace.resolvers.push(ResolverTiming {
+                parent_type: type_name.to_owned(),
+                field_name: field.to_owned(),
+                start_offset: trace.started.elapsed(),
+                duration: Duration::default(),
+            });
+            trace.resolvers.len() - 1
+        }),
+    }
+}
+
+impl Drop for Resolver {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        RESOLVER.with(|current| *current.borrow_mut() = previous);
+        if let Some(index) = self.debug_timing {
+            with_debug_trace(|trace| {
+                let timing = &mut trace.resolvers[index];
+                timing.duration = trace.started.elapsed() - timing.start_offset;
+            });
+        }
+    }
+}
+
+/// The resolver running on the thread, e.g. `Query.petsByType`
+pub fn current_resolver() -> Option<String> {
+    RESOLVER.with(|current| current.borrow().clone())
+}
+
+/// Traces the commands the MongoDB client sends on a thread that is in a span, which
+/// covers every call of the services
+///
+/// The command events are sent on the thread that runs the command, so the span is started
+/// there and kept by request id until the command succeeds or fails.
+#[derive(Default)]
+pub struct MongoCommandSpans {
+    spans: Mutex<HashMap<i32, Span>>,
+}
+
+impl CommandEventHandler for MongoCommandSpans {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        let collection = command_collection(&event);
+        let name = format!("{} {}", event.command_name, collection)
+            .trim_end()
+            .to_owned();
+        let mut span = child_span(name, SpanKind::Client);
+        if span.context().is_none() {
+            return;
+        }
+        span.set_attribute("db.system", "mongodb");
+        span.set_attribute("db.name", event.db.as_str());
+        span.set_attribute("db.operation", event.command_name.as_str());
+        span.set_attribute("db.mongodb.collection", collection);
+        self.spans.lock().unwrap().insert(event.request_id, span);
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.spans.lock().unwrap().remove(&event.request_id);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        let span = self.spans.lock().unwrap().remove(&event.request_id);
+        if let Some(mut span) = span {
+            span.set_error(event.failure.to_string());
+        }
+    }
+}
+
+/// What the resolvers of a request with the debug token did, from the start of its execution
+pub struct DebugTrace {
+    pub start_time: SystemTime,
+    pub started: Instant,
+    pub duration: Duration,
+    pub resolvers: Vec<ResolverTiming>,
+    pub commands: Vec<CommandTiming>,
+    /// The index of the commands that haven't finished by request id
+    running: HashMap<i32, usize>,
+}
+
+pub struct ResolverTiming {
+    pub parent_type: String,
+    pub field_name: String,
+    /// From the start of the trace
+    pub start_offset: Duration,
+    pub duration: Duration,
+}
+
+pub struct CommandTiming {
+    pub command_name: String,
+    pub collection: String,
+    pub command: Document,
+    /// The resolver that sent it, e.g. `Query.petsByType`
+    pub resolver: Option<String>,
+    /// From the start of the trace
+    pub start_offset: Duration,
+    pub duration: Duration,
+    pub failure: Option<String>,
+}
+
+fn with_debug_trace<T>(f: impl FnOnce(&mut DebugTrace) -> T) -> Option<T> {
+    DEBUG_TRACE.with(|trace| trace.borrow_mut().as_mut().map(f))
+}
+
+/// Records the debug trace of the thread until it's finished or dropped
+pub struct DebugRecording {
+    _private: (),
+}
+
+pub fn record_debug_trace() -> DebugRecording {
+    let trace = DebugTrace {
+        start_time: SystemTime::now(),
+        started: Instant::now(),
+        duration: Duration::default(),
+        resolvers: Vec::new(),
+        commands: Vec::new(),
+        running: HashMap::new(),
+    };
+    DEBUG_TRACE.with(|current| *current.borrow_mut() = Some(trace));
+    DebugRecording { _private: () }
+}
+
+impl DebugRecording {
+    pub fn finish(self) -> DebugTrace {
+        let mut trace = DEBUG_TRACE
+            .with(|current| current.borrow_mut().take())
+            .expect("debug trace recorded");
+        trace.duration = trace.started.elapsed();
+        trace
+    }
+}
+
+impl Drop for DebugRecording {
+    fn drop(&mut self) {
+        DEBUG_TRACE.with(|current| current.borrow_mut().take());
+    }
+}
+
+/// Adds the MongoDB commands sent on a thread that records a debug trace to the trace
+///
+/// The driver runs the commands on the thread that sends them, so they finish on it too.
+pub struct MongoDebugTrace;
+
+impl MongoDebugTrace {
+    fn finished(&self, request_id: i32, duration: Duration, failure: Option<String>) {
+        with_debug_trace(|trace| {
+            if let Some(index) = trace.running.remove(&request_id) {
+                let command = &mut trace.commands[index];
+                command.duration = duration;
+                command.failure = failure;
+            }
+        });
+    }
+}
+
+impl CommandEventHandler for MongoDebugTrace {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        let resolver = current_resolver();
+        with_debug_trace(|trace| {
+            trace.running.insert(event.request_id, trace.commands.len());
+            trace.commands.push(CommandTiming {
+                collection: command_collection(&event).to_owned(),
+                command_name: event.command_name,
+                command: event.command,
+                resolver,
+                start_offset: trace.started.elapsed(),
+                duration: Duration::default(),
+                failure: None,
+            });
+        });
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.finished(event.request_id, event.duration, None);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        let failure = Some(event.failure.to_string());
+        self.finished(event.request_id, event.duration, failure);
+    }
+}
+
+/// Starts exporting spans on a thread of its own, tracing stays disabled without an
+/// exporter
+pub fn init(config: &TracingConfig) -> io::Result<()> {
+    let (sender, receiver) = mpsc::sync_channel(MAX_QUEUE);
+    let service_name = config.service_name.clone();
+    let exporter = thread::Builder::new().name("span-exporter".to_owned());
+    match config.exporter {
+        TracingExporter::None => return Ok(()),
+        TracingExporter::Otlp => {
+            let url = format!("{}/v1/traces", config.otlp_endpoint.trim_end_matches('/'));
+            exporter.spawn(move || export_otlp(receiver, &url, &service_name))?;
+        }
+        TracingExporter::File => {
+            // opened here so a file that can't be written stops the service from starting
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.file)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.file, e)))?;
+            exporter.spawn(move || export_file(receiver, file, &service_name))?;
+        }
+    }
+    *EXPORTER.lock().unwrap() = Some(sender);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+fn export_batches(receiver: mpsc::Receiver<SpanData>, mut export: impl FnMut(&[SpanData])) {
+    while let Ok(span) = receiver.recv() {
+        let mut batch = vec![span];
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < MAX_BATCH {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(wait) {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        export(&batch);
+    }
+}
+
+/// Posts the spans to `<endpoint>/v1/traces` with the actix client, which needs a system
+/// of its own on the exporter thread
+fn export_otlp(receiver: mpsc::Receiver<SpanData>, url: &str, service_name: &str) {
+    let mut system = actix_rt::System::new("span-exporter");
+    let client = system.block_on(async { Client::new() });
+    export_batches(receiver, |spans| {
+        let body = export_request(service_name, spans);
+        let request = client.post(url);
+        // the request's timeout needs the timer of the system
+        let result = system.block_on(async move {
+            let response = request.send_json(&body).await?;
+            Ok::<_, SendRequestError>(response.status())
+        });
+        match result {
+            Ok(status) if status.is_success() => {}
+            Ok(status) => warn!("Unable to export {} spans: {}", spans.len(), status),
+            Err(e) => warn!("Unable to export {} spans: {}", spans.len(), e),
+        }
+    });
+}
+
+/// Appends every batch to the file as a line of OTLP json
+fn export_file(receiver: mpsc::Receiver<SpanData>, mut file: File, service_name: &str) {
+    export_batches(receiver, |spans| {
+        let line = export_request(service_name, spans).to_string();
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("Unable to export {} spans: {}", spans.len(), e);
+        }
+    });
+}
+
+/// An OTLP `ExportTraceServiceRequest` in the json encoding, where ids are hex
+fn export_request(service_name: &str, spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &json!(service_name))]
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION")
+                },
+                "spans": spans.iter().map(otlp_span).collect::<Vec<Value>>()
+            }]
+        }]
+    })
+}
+
+fn otlp_span(span: &SpanData) -> Value {
+    let mut value = json!({
+        "traceId": encode_hex(&span.context.trace_id),
+        "spanId": encode_hex(&span.context.span_id),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<Value>>(),
+        "status": match &span.error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 0 }),
+        }
+    });
+    if let Some(parent_span_id) = &span.parent_span_id {
+        value["parentSpanId"] = json!(encode_hex(parent_span_id));
+    }
+    value
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        // 64 bit integers are strings in the json encoding
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::String(value) => json!({ "stringValue": value }),
+        value => json!({ "stringValue": value.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    nanos.to_string()
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut span_id = [0; 8];
+    span_id.copy_from_slice(&Uuid::new_v4().as_bytes()[..8]);
+    span_id
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes lowercase hex of exactly the length of `bytes`
+fn decode_hex(hex: &str, bytes: &mut [u8]) -> Option<()> {
+    let lowercase_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+    if hex.len() != bytes.len() * 2 || !hex.bytes().all(lowercase_hex) {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn traceparent(version: &str, trace_id: &str, span_id: &str, flags: &str) -> String {
+        format!("{}-{}-{}-{}", version, trace_id, span_id, flags)
+    }
+
+    #[test]
+    fn traceparent_round_trips() {
+        let value = traceparent("00", TRACE_ID, SPAN_ID, "01");
+        let context = SpanContext::from_traceparent(&value).unwrap();
+        assert_eq!(context.traceparent(), value);
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.span_id, context.span_id);
+        assert!(child.sampled);
+    }
+
+    #[test]
+    fn the_sampled_flag_is_the_lowest_bit() {
+        let parse =
+            |flags| SpanContext::from_traceparent(&traceparent("00", TRACE_ID, SPAN_ID, flags));
+        assert!(parse("01").unwrap().sampled);
+        assert!(parse("03").unwrap().sampled);
+        let unsampled = parse("00").unwrap();
+        assert!(!unsampled.sampled);
+        assert!(unsampled.traceparent().ends_with("-00"));
+        assert!(!parse("02").unwrap().sampled);
+    }
+
+    #[test]
+    fn later_versions_may_add_fields() {
+        let value = format!("{}-extra", traceparent("01", TRACE_ID, SPAN_ID, "01"));
+        let context = SpanContext::from_traceparent(&value).unwrap();
+        // the version this service knows is sent on
+        assert_eq!(
+            context.traceparent(),
+            traceparent("00", TRACE_ID, SPAN_ID, "01")
+        );
+        let value = format!("{}-extra", traceparent("00", TRACE_ID, SPAN_ID, "01"));
+        assert_eq!(SpanContext::from_traceparent(&value), None);
+    }
+
+    #[test]
+    fn invalid_traceparents_are_rejected() {
+        let zero_trace_id = "0".repeat(32);
+        let zero_span_id = "0".repeat(16);
+        let invalid = [
+            traceparent("ff", TRACE_ID, SPAN_ID, "01"),
+            traceparent("0", TRACE_ID, SPAN_ID, "01"),
+            traceparent("00", &zero_trace_id, SPAN_ID, "01"),
+            traceparent("00", TRACE_ID, &zero_span_id, "01"),
+            traceparent("00", &TRACE_ID[1..], SPAN_ID, "01"),
+            traceparent("00", TRACE_ID, &format!("{}0", SPAN_ID), "01"),
+            traceparent("00", TRACE_ID, SPAN_ID, "1"),
+            traceparent("00", &TRACE_ID.to_uppercase(), SPAN_ID, "01"),
+            traceparent("00", TRACE_ID, "00f067aa0ba902bz", "01"),
+            traceparent("00", TRACE_ID, SPAN_ID, "0x"),
+            format!("00-{}-{}", TRACE_ID, SPAN_ID),
+            String::new(),
+        ];
+        for value in &invalid {
+            assert_eq!(SpanContext::from_traceparent(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn hex_is_decoded_to_exactly_the_length_of_the_bytes() {
+        let mut bytes = [0; 2];
+        assert_eq!(decode_hex("0aff", &mut bytes), Some(()));
+        assert_eq!(bytes, [0x0a, 0xff]);
+        assert_eq!(decode_hex("0aff00", &mut bytes), None);
+        assert_eq!(decode_hex("0af", &mut bytes), None);
+        assert_eq!(decode_hex("+aff", &mut bytes), None);
+        assert_eq!(encode_hex(&[0x0a, 0xff]), "0aff");
+    }
+
+    #[test]
+    fn spans_are_encoded_as_otlp_json() {
+        let context =
+            SpanContext::from_traceparent(&traceparent("00", TRACE_ID, SPAN_ID, "01")).unwrap();
+        let start = UNIX_EPOCH + Duration::from_nanos(1_600_000_000_000_000_001);
+        let span = SpanData {
+            context,
+            parent_span_id: Some([1, 2, 3, 4, 5, 6, 7, 8]),
+            name: "find pets".to_owned(),
+            kind: SpanKind::Client,
+            start,
+            end: start + Duration::from_millis(2),
+            attributes: vec![
+                ("db.system", json!("mongodb")),
+                ("http.status_code", json!(200)),
+                ("sampled", json!(true)),
+                ("ratio", json!(0.5)),
+            ],
+            error: Some("timed out".to_owned()),
+        };
+        assert_eq!(
+            export_request("pets-api", &[span]),
+            json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [
+                            { "key": "service.name", "value": { "stringValue": "pets-api" } }
+                        ]
+                    },
+                    "scopeSpans": [{
+                        "scope": {
+                            "name": env!("CARGO_PKG_NAME"),
+                            "version": env!("CARGO_PKG_VERSION")
+                        },
+                        "spans": [{
+                            "traceId": TRACE_ID,
+                            "spanId": SPAN_ID,
+                            "parentSpanId": "0102030405060708",
+                            "name": "find pets",
+                            "kind": 3,
+                            "startTimeUnixNano": "1600000000000000001",
+                            "endTimeUnixNano": "1600000000002000001",
+                            "attributes": [
+                                { "key": "db.system", "value": { "stringValue": "mongodb" } },
+                                { "key": "http.status_code", "value": { "intValue": "200" } },
+                                { "key": "sampled", "value": { "boolValue": true } },
+                                { "key": "ratio", "value": { "doubleValue": 0.5 } }
+                            ],
+                            "status": { "code": 2, "message": "timed out" }
+                        }]
+                    }]
+                }]
+            })
+        );
+    }
+}